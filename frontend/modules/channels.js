@@ -175,6 +175,7 @@ export async function openEditChannelModal(channelId) {
     setIf('#editChName', 'value', ch.name);
     $('#editChIsVoice').checked = ch.is_voice;
     $('#editChIsPrivate').checked = ch.is_private;
+    $('#editChSharePlayControl').value = ch.shareplay_control || 'everyone';
     $('#editChannelModal').setAttribute('data-channel-id', channelId);
 
     await loadEditChannelMembers(channelId);
@@ -190,13 +191,14 @@ export async function saveEditChannel() {
     const name = $('#editChName').value.trim();
     const is_voice = $('#editChIsVoice').checked;
     const is_private = $('#editChIsPrivate').checked;
+    const shareplay_control = $('#editChSharePlayControl').value;
 
     if (!name) return alert('Name required');
 
     try {
         await apiFetch(`/api/channels/${id}`, {
             method: 'PATCH',
-            body: JSON.stringify({ name, is_voice, is_private })
+            body: JSON.stringify({ name, is_voice, is_private, shareplay_control })
         });
         await loadChannels();
         closeEditChannelModal();
@@ -1,7 +1,7 @@
 import { store } from './store.js';
 import { apiFetch } from './api.js';
 import { connectWs } from './socket.js';
-import { $, el, absFileUrl, buildFileUrl, setIf, truncateId, presenceClass, localizeDate, replaceEmojisAndLinkify, isEmojiOnly, formatFileSize } from './utils.js';
+import { $, el, absFileUrl, buildFileUrl, urlExpiring, setIf, truncateId, presenceClass, localizeDate, replaceEmojisAndLinkify, isEmojiOnly, formatFileSize } from './utils.js';
 import { prefetchUsers } from './users.js';
 import { buildEmojiUrl } from './emojis.js';
 
@@ -61,11 +61,30 @@ export async function loadOlder() {
     await fetchMessagesPage(chan, before);
 }
 
+// Signed file URLs expire after file_url_ttl_secs; ask the server for a new one.
+// Updates the message so later renders use it too. Returns null if it can't.
+async function resignFileUrl(message) {
+    if (!message.file_id) return null;
+    try {
+        const data = await apiFetch(`/api/files/${encodeURIComponent(message.file_id)}/url`);
+        message.file_url = data.url;
+        return absFileUrl(data.url);
+    } catch (e) {
+        console.warn('Failed to re-sign file URL', e);
+        return null;
+    }
+}
+
 function renderAttachment(message) {
     const box = el('div', { class: 'attachment' }, []);
-    const url = message.file_url
+    let url = message.file_url
         ? absFileUrl(message.file_url)
         : buildFileUrl(message.file_id, 'file');
+    const resign = async () => {
+        const fresh = await resignFileUrl(message);
+        if (fresh) url = fresh;
+        return !!fresh;
+    };
 
     // If we have metadata, use it
     const filename = message.filename || 'attachment';
@@ -94,7 +113,15 @@ function renderAttachment(message) {
             href: url,
             target: '_blank',
             rel: 'noopener noreferrer',
-            class: 'file-card'
+            class: 'file-card',
+            onclick: (e) => {
+                if (!urlExpiring(url)) return;
+                e.preventDefault();
+                resign().then(() => {
+                    card.href = url;
+                    window.open(url, '_blank', 'noopener');
+                });
+            }
         }, [icon, info]);
 
         box.replaceChildren(card);
@@ -185,24 +212,26 @@ function renderAttachment(message) {
     // If that fails, then maybe fallback or show link.
     // This avoids the HEAD request latency for valid images/videos.
 
-    let promise;
-    if (isImage) {
-        promise = tryImage();
-        const placeholder = el('div', { class: 'attachment-placeholder' });
-        box.appendChild(placeholder);
-    }
-    else if (isVideo) promise = tryVideo();
-    else if (isAudio) promise = tryAudio();
-    else {
+    const load = () => {
+        if (isImage) return tryImage();
+        if (isVideo) return tryVideo();
+        if (isAudio) return tryAudio();
         // No clear extension match, do HEAD to find out mime
-        promise = fetch(url, { method: 'HEAD' })
+        return fetch(url, { method: 'HEAD' })
             .then((res) => {
                 if (!res.ok) throw new Error('HEAD failed');
                 return handleByType((res.headers.get('Content-Type') || '').toLowerCase());
             });
+    };
+    if (isImage) {
+        const placeholder = el('div', { class: 'attachment-placeholder' });
+        box.appendChild(placeholder);
     }
 
-    promise
+    // Messages loaded a while ago may carry an expired signature, which fails like
+    // any other error (403), so re-sign and try once more before giving up
+    (urlExpiring(url) ? resign().then(load) : load())
+        .catch(() => resign().then(ok => ok ? load() : Promise.reject()))
         .then((node) => {
             clearBox();
             if (node && node.tagName === 'IMG') {
@@ -210,10 +239,18 @@ function renderAttachment(message) {
                 wrapper.onclick = (e) => {
                     e.preventDefault();
                     e.stopPropagation();
-                    openImagePreview(url, filename);
+                    if (urlExpiring(url)) resign().then(() => openImagePreview(url, filename));
+                    else openImagePreview(url, filename);
                 };
                 box.appendChild(wrapper);
             } else {
+                // Seeking or resuming after the signature expired refetches and fails
+                node.addEventListener('error', async () => {
+                    if (!urlExpiring(url, 0) || !(await resign())) return;
+                    const time = node.currentTime;
+                    node.src = url;
+                    node.currentTime = time;
+                });
                 box.appendChild(node);
             }
         })
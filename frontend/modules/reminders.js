@@ -0,0 +1,65 @@
+import { store } from './store.js';
+import { apiFetch } from './api.js';
+import { $, el, playNotificationSound } from './utils.js';
+import { selectChannel } from './channels.js';
+
+// Reminders on screen, by job id, so a reconnect doesn't show one twice
+const shown = new Set();
+
+function container() {
+    let c = $('#reminders');
+    if (!c) {
+        c = el('div', { id: 'reminders' });
+        document.body.appendChild(c);
+    }
+    return c;
+}
+
+async function dismiss(ev, card) {
+    card.remove();
+    shown.delete(ev.job_id);
+    try {
+        await apiFetch(`/api/scheduled/${encodeURIComponent(ev.job_id)}/dismiss`, { method: 'POST' });
+    } catch (e) { console.warn('Dismiss reminder failed', e); }
+}
+
+// Show a fired reminder until it's dismissed; `live` means it just fired rather than
+// being picked up after a reconnect.
+export function showReminder(ev, live = true) {
+    if (!ev?.job_id || shown.has(ev.job_id)) return;
+    shown.add(ev.job_id);
+
+    const author = store.users.get(ev.author_id)?.username || 'Someone';
+    const text = ev.content || 'Sent a file';
+    const card = el('div', { class: 'reminder-card glass' }, [
+        el('div', { class: 'reminder-title' }, 'Reminder'),
+        ev.note ? el('div', { class: 'reminder-note' }, ev.note) : null,
+        el('div', { class: 'reminder-body' }, `${author}: ${text}`),
+    ]);
+    card.appendChild(el('div', { class: 'reminder-actions' }, [
+        el('button', {
+            class: 'button', onclick: () => {
+                selectChannel(ev.channel_id);
+                dismiss(ev, card);
+            }
+        }, 'Open'),
+        el('button', { class: 'button alt', onclick: () => dismiss(ev, card) }, 'Dismiss'),
+    ]));
+    container().appendChild(card);
+
+    if (live) {
+        if (Notification.permission === 'granted') {
+            const n = new Notification(`Reminder: ${ev.note || author}`, { body: text, icon: '/img/favicon.png' });
+            n.onclick = () => { window.focus(); };
+        }
+        playNotificationSound('message');
+    }
+}
+
+// Reminders that fired while we were offline
+export async function loadPendingReminders() {
+    try {
+        const list = await apiFetch('/api/scheduled/reminders');
+        (list || []).forEach(ev => showReminder(ev, false));
+    } catch (e) { console.warn('Failed to load reminders', e); }
+}
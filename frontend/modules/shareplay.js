@@ -1,16 +1,21 @@
 import { store } from './store.js';
 import { $ } from './utils.js';
+import { apiFetch } from './api.js';
 import { sendTyping } from './socket.js'; // Just to have access to ws sending if needed, though we usully access store.ws
 
 export class SharePlay {
     constructor() {
         this.ctx = new (window.AudioContext || window.webkitAudioContext)();
         this.gainNode = this.ctx.createGain();
-        this.gainNode.gain.value = 0.4; // Default to 40%
+        this.volume = 0.4; // Default to 40%
+        this.trackGain = 1; // Loudness normalization of the current track, the same for everyone
+        this.gainNode.gain.value = this.volume;
         this.gainNode.connect(this.ctx.destination);
 
-        // HTML5 Audio element for streaming playback
-        this.audio = new Audio();
+        // HTML5 media element for streaming playback. A <video> plays audio-only
+        // songs just as well, and shows the picture for watch-party items.
+        this.audio = document.createElement('video');
+        this.audio.playsInline = true;
         this.audio.crossOrigin = 'anonymous'; // Required for CORS with Web Audio API
         this.audio.preload = 'metadata';
         this.mediaSource = null; // MediaElementSourceNode - only create once per audio element
@@ -20,6 +25,8 @@ export class SharePlay {
         this.channelId = null; // Current channel for API calls
         this.localStatus = 'paused';
         this.serverState = null;
+        this.urls = {}; // item id -> { song_url, thumbnail_url }, signed by the server
+        this.videoMode = false; // Queue new items as video
 
         // Bind UI
         this.ui = {
@@ -29,22 +36,36 @@ export class SharePlay {
             currentTime: $('#nowPlayingCurrentTime'),
             totalTime: $('#nowPlayingTotalTime'),
             queue: $('#shareplayQueueItems'),
+            history: $('#shareplayHistoryItems'),
+            playlists: $('#shareplayPlaylistSelect'),
+            btnLoadPlaylist: $('#shareplayPlaylistLoadButton'),
+            btnSavePlaylist: $('#shareplayPlaylistSaveButton'),
             input: $('#shareplayQueueAddInput'),
             btnAdd: $('#shareplayQueueAddButton'),
+            btnPlayNext: $('#shareplayQueuePlayNextButton'),
+            btnVideo: $('#shareplayQueueVideoButton'),
+            video: $('#shareplayVideo'),
             btnPrev: $('#btnSharePlayPrevious'),
             btnPause: $('#btnSharePlayPause'),
             btnNext: $('#btnSharePlayNext'),
+            btnVoteSkip: $('#btnSharePlayVoteSkip'),
+            voteCount: $('#sharePlayVoteCount'),
             btnRepeat: $('#btnSharePlayRepeat'),
+            btnShuffle: $('#btnSharePlayShuffle'),
+            btnClearUpcoming: $('#btnSharePlayClearUpcoming'),
             volume: $('#shareplayVolume'),
             cover: $('#nowPlayingCover'),
         };
 
         this.animationFrameId = null;
+        this.ui.video.appendChild(this.audio);
 
         // Set up audio event handlers
         this.audio.addEventListener('ended', () => {
             console.log('[SharePlay] Audio ended naturally');
-            this.sendAction('next');
+            // Not 'next': anyone may report the end, even where SharePlay is limited to DJs
+            const current = this.serverState?.queue[this.serverState.current_index];
+            if (current) this.sendAction('ended', current.id);
         });
 
         this.audio.addEventListener('canplay', () => {
@@ -65,7 +86,13 @@ export class SharePlay {
 
     bindEvents() {
         this.ui.btnAdd.onclick = () => this.addItem();
-        this.ui.input.onkeydown = (e) => { if (e.key === 'Enter') this.addItem(); };
+        this.ui.btnPlayNext.onclick = () => this.addItem('play_next');
+        this.ui.btnVideo.onclick = () => {
+            this.videoMode = !this.videoMode;
+            this.ui.btnVideo.classList.toggle('active', this.videoMode);
+        };
+        // Shift+Enter queues the song after the current one
+        this.ui.input.onkeydown = (e) => { if (e.key === 'Enter') this.addItem(e.shiftKey ? 'play_next' : 'add'); };
 
         this.ui.btnPause.onclick = () => {
             const action = this.serverState?.status === 'playing' ? 'pause' : 'play';
@@ -74,7 +101,14 @@ export class SharePlay {
 
         this.ui.btnNext.onclick = () => this.sendAction('next');
         this.ui.btnPrev.onclick = () => this.sendAction('prev');
+        this.ui.btnVoteSkip.onclick = () => this.sendAction('vote_skip');
         this.ui.btnRepeat.onclick = () => this.sendAction('toggle_repeat');
+        this.ui.btnShuffle.onclick = () => this.sendAction('shuffle');
+        this.ui.btnClearUpcoming.onclick = () => this.sendAction('clear_upcoming');
+        this.ui.btnLoadPlaylist.onclick = () => {
+            if (this.ui.playlists.value) this.sendAction('load_playlist', this.ui.playlists.value);
+        };
+        this.ui.btnSavePlaylist.onclick = () => this.savePlaylist();
 
         // Seek bar click
         this.ui.seek.onclick = (e) => {
@@ -89,15 +123,22 @@ export class SharePlay {
 
         this.ui.volume.oninput = () => {
             const val = parseInt(this.ui.volume.value);
-            this.gainNode.gain.value = val / 200;
+            this.volume = val / 200;
+            this.applyGain();
         };
     }
 
-    addItem() {
+    applyGain() {
+        this.gainNode.gain.value = this.volume * this.trackGain;
+    }
+
+    addItem(action = 'add') {
         const url = this.ui.input.value.trim();
         if (!url) return;
-        console.log(`[SharePlay] User adding item: ${url}`);
-        this.sendAction('add', url);
+        // add_video / play_next_video download the video for everyone to watch together
+        if (this.videoMode) action += '_video';
+        console.log(`[SharePlay] User adding item (${action}): ${url}`);
+        this.sendAction(action, url);
         this.ui.input.value = '';
 
         // Optimistic UI? Or just wait for sync.
@@ -123,7 +164,19 @@ export class SharePlay {
         // console.log("[SharePlay] Syncing state:", state);
         this.serverState = state;
         if (channelId) {
-            this.channelId = channelId; // Store for use in loadTrack
+            if (this.channelId !== channelId) {
+                this.channelId = channelId; // Store for use in loadTrack
+                this.loadPlaylists();
+            }
+        }
+
+        // Song and thumbnail URLs are signed, so <audio>/<img> can load them without a token
+        if (this.channelId) {
+            try {
+                this.urls = await apiFetch(`/api/shareplay/${this.channelId}/urls`);
+            } catch (e) {
+                console.warn('[SharePlay] Failed to get queue URLs', e);
+            }
         }
 
         // Update UI Visibility
@@ -138,6 +191,17 @@ export class SharePlay {
             ? state.queue[currentIndex]
             : null;
 
+        // A track is logged in the history once playback moves on from it
+        if (this.historyTrackId !== (currentItem?.id ?? null)) {
+            this.historyTrackId = currentItem?.id ?? null;
+            this.loadHistory();
+        }
+
+        // gain_db is worked out by the server from the track's measured loudness
+        const gainDb = currentItem?.gain_db;
+        this.trackGain = (gainDb === null || gainDb === undefined) ? 1 : Math.pow(10, gainDb / 20);
+        this.applyGain();
+
         // Sync Playback
         if (currentItem && currentItem.file_path) {
             // Check if we need to load new file
@@ -177,9 +241,13 @@ export class SharePlay {
             // Update Seek Bar (visual only)
             requestAnimationFrame(() => this.updateSeekBar());
 
-            // Update Cover
-            if (currentItem.thumbnail_path) {
-                this.ui.cover.src = store.baseUrl + `/api/shareplay/thumbnail/${currentItem.id}?t=${Date.now()}`;
+            // Update Cover, or show the video in its place
+            this.ui.video.classList.toggle('hidden', !currentItem.video);
+            const coverUrl = this.urls[currentItem.id]?.thumbnail_url;
+            if (currentItem.video) {
+                this.ui.cover.classList.add('hidden');
+            } else if (coverUrl) {
+                this.ui.cover.src = store.baseUrl + coverUrl;
                 this.ui.cover.classList.remove('hidden');
             } else {
                 this.ui.cover.classList.add('hidden');
@@ -190,11 +258,13 @@ export class SharePlay {
             // Still downloading
             this.ui.title.textContent = `Grabbing: ${currentItem.url}...`;
             this.ui.cover.classList.add('hidden');
+            this.ui.video.classList.add('hidden');
             this.stop();
         } else {
             // Nothing playing
             this.ui.title.textContent = "Nothing is playing.";
             this.ui.cover.classList.add('hidden');
+            this.ui.video.classList.add('hidden');
             this.stop();
         }
     }
@@ -207,11 +277,9 @@ export class SharePlay {
         console.log(`[SharePlay] Loading track ID: ${id} for channel: ${channelId}`);
 
         try {
-            const res = await fetch(store.baseUrl + `/api/shareplay/${channelId}/current`, {
+            const { song_id, song_url } = await apiFetch(`/api/shareplay/${channelId}/current`, {
                 cache: 'no-store'
             });
-            if (!res.ok) throw new Error(`Failed to load track info: ${res.status} ${res.statusText}`);
-            const { song_id } = await res.json();
             console.log(`[SharePlay] Got song ID: ${song_id}, setting audio source for streaming...`);
 
             // Check if we already moved on to another track during the fetch
@@ -221,7 +289,7 @@ export class SharePlay {
             }
 
             // Set the audio source - browser handles streaming via HTTP Range requests
-            this.audio.src = store.baseUrl + `/api/shareplay/song/${song_id}`;
+            this.audio.src = store.baseUrl + song_url;
 
             // Connect to Web Audio API for gain control (only once per audio element)
             if (!this.mediaSource) {
@@ -324,6 +392,7 @@ export class SharePlay {
         if (this.ui.currentTime) this.ui.currentTime.textContent = '0:00';
         if (this.ui.totalTime) this.ui.totalTime.textContent = '0:00';
         if (this.ui.queue) this.ui.queue.innerHTML = '';
+        if (this.ui.video) this.ui.video.classList.add('hidden');
     }
 
     getCurrentTime() {
@@ -338,15 +407,18 @@ export class SharePlay {
             if (idx === state.current_index) el.classList.add('playing');
 
             // Thumbnail
-            if (item.thumbnail_path) {
+            const thumbUrl = this.urls[item.id]?.thumbnail_url;
+            if (thumbUrl) {
                 const img = document.createElement('img');
-                img.src = store.baseUrl + `/api/shareplay/thumbnail/${item.id}`;
+                img.src = store.baseUrl + thumbUrl;
                 img.className = 'shareplay-queue-thumb';
                 el.appendChild(img);
             } else {
                 const placeholder = document.createElement('div');
                 placeholder.className = 'shareplay-queue-thumb-placeholder';
-                placeholder.innerHTML = '<i class="bi bi-music-note-beamed"></i>';
+                placeholder.innerHTML = item.video
+                    ? '<i class="bi bi-film"></i>'
+                    : '<i class="bi bi-music-note-beamed"></i>';
                 el.appendChild(placeholder);
             }
 
@@ -359,7 +431,11 @@ export class SharePlay {
                 if (status === 'grabbing') {
                     title.textContent = `Getting: ${item.url}...`;
                 } else if (status === 'downloading') {
-                    title.textContent = `Downloading: ${item.title}...`;
+                    title.textContent = `Downloading: ${item.title}${this.formatProgress(item)}`;
+                } else if (status === 'pending') {
+                    // Playlist entries already have a title; single songs don't until grabbed
+                    const name = item.title === 'Grabbing...' ? item.url : item.title;
+                    title.textContent = `Waiting: ${name}`;
                 } else if (status === 'error') {
                     title.textContent = `Error: ${item.title}`;
                     el.classList.add('error');
@@ -392,10 +468,92 @@ export class SharePlay {
                 this.sendAction('track', idx.toString());
             };
 
+            // Drag to reorder
+            el.draggable = true;
+            el.ondragstart = (e) => {
+                e.dataTransfer.effectAllowed = 'move';
+                e.dataTransfer.setData('text/plain', idx.toString());
+            };
+            el.ondragover = (e) => {
+                e.preventDefault();
+                el.classList.add('drag-over');
+            };
+            el.ondragleave = () => el.classList.remove('drag-over');
+            el.ondrop = (e) => {
+                e.preventDefault();
+                el.classList.remove('drag-over');
+                const from = parseInt(e.dataTransfer.getData('text/plain'));
+                if (!isNaN(from) && from !== idx) {
+                    this.sendAction('move', `${from},${idx}`);
+                }
+            };
+
             this.ui.queue.appendChild(el);
         });
     }
 
+    async loadPlaylists() {
+        if (!this.channelId) return;
+        let playlists = [];
+        try {
+            playlists = await apiFetch(`/api/shareplay/playlists?channel_id=${encodeURIComponent(this.channelId)}`);
+        } catch (e) {
+            console.warn('[SharePlay] Failed to get playlists', e);
+        }
+        this.ui.playlists.innerHTML = '';
+        playlists.forEach(p => {
+            const opt = document.createElement('option');
+            opt.value = p.id;
+            opt.textContent = `${p.name} (${p.entry_count})${p.shared ? ' - shared' : ''}`;
+            this.ui.playlists.appendChild(opt);
+        });
+    }
+
+    async savePlaylist() {
+        if (!this.channelId) return;
+        const name = prompt('Playlist name');
+        if (!name || !name.trim()) return;
+        const shared = confirm('Share this playlist with the channel?');
+        try {
+            await apiFetch('/api/shareplay/playlists', {
+                method: 'POST',
+                body: JSON.stringify({ name: name.trim(), channel_id: this.channelId, shared })
+            });
+            await this.loadPlaylists();
+        } catch (e) { alert('Failed to save playlist: ' + e.message); }
+    }
+
+    async loadHistory() {
+        if (!this.channelId) return;
+        let entries = [];
+        try {
+            entries = await apiFetch(`/api/shareplay/${this.channelId}/history?limit=10`);
+        } catch (e) {
+            console.warn('[SharePlay] Failed to get history', e);
+        }
+        this.ui.history.innerHTML = '';
+        entries.forEach(entry => {
+            const el = document.createElement('div');
+            el.className = 'shareplay-queue-item';
+            el.title = 'Queue again';
+
+            const title = document.createElement('div');
+            title.className = 'title';
+            title.textContent = entry.added_by_username
+                ? `${entry.title} (${entry.added_by_username})`
+                : entry.title;
+
+            const duration = document.createElement('div');
+            duration.className = 'duration';
+            duration.textContent = this.formatTime(entry.duration_seconds);
+
+            el.appendChild(title);
+            el.appendChild(duration);
+            el.onclick = () => this.sendAction('requeue', entry.id);
+            this.ui.history.appendChild(el);
+        });
+    }
+
     renderControls(state) {
         this.ui.btnPause.innerHTML = state.status === 'playing'
             ? '<i class="bi bi-pause-fill"></i>'
@@ -432,6 +590,16 @@ export class SharePlay {
             currentIdx < queueLen - 1
         );
         this.ui.btnNext.disabled = !canGoNext;
+
+        const votes = state.skip_votes || [];
+        this.ui.btnVoteSkip.disabled = state.current_index == null || votes.includes(store.user?.id);
+        this.ui.voteCount.textContent = votes.length ? `${votes.length}/${state.skip_votes_needed}` : '';
+    }
+
+    // The server refused an action, e.g. the channel limits SharePlay to DJs
+    denied(reason) {
+        console.warn(`[SharePlay] Action denied: ${reason}`);
+        alert(reason);
     }
 
     updateSeekBar() {
@@ -476,6 +644,13 @@ export class SharePlay {
         const s = Math.floor(secs % 60);
         return `${m}:${s.toString().padStart(2, '0')}`;
     }
+
+    formatProgress(item) {
+        if (item.download_progress == null) return '...';
+        let text = ` (${Math.floor(item.download_progress)}%`;
+        if (item.download_eta_secs != null) text += `, ${this.formatTime(item.download_eta_secs)} left`;
+        return text + ')';
+    }
 }
 
 export const sharePlay = new SharePlay();
@@ -5,6 +5,7 @@ import { renderMessages, renderMessageItem, isScrolledToBottom, scrollToBottom,
 import { updateCallUI, createPeerConnection, handleSignal } from './voice.js';
 import { renderChannelList, markChannelRead } from './channels.js';
 import { sharePlay } from './shareplay.js';
+import { showReminder, loadPendingReminders } from './reminders.js';
 
 export function connectWs(reconnect = false) {
     const url = toWsUrl(store.baseUrl);
@@ -30,6 +31,7 @@ export function connectWs(reconnect = false) {
             if (store.callChannelId && store.callChannelId !== store.currentChannelId) {
                 ws.send(JSON.stringify({ type: 'join', channel_id: store.callChannelId }));
             }
+            loadPendingReminders();
         };
         ws.onmessage = (ev) => {
             try {
@@ -251,6 +253,12 @@ export function handleWsMessage(ev) {
             }
             break;
         }
+        case 'shareplay_denied': {
+            if (ev.channel_id === store.callChannelId) {
+                sharePlay.denied(ev.reason);
+            }
+            break;
+        }
         case 'user_updated': {
             // Force fetch updated user data (this re-renders messages if in current channel)
             fetchUser(ev.user_id, true);
@@ -262,6 +270,10 @@ export function handleWsMessage(ev) {
             import('./presence.js').then(m => m.refreshMembersModalIfOpen());
             break;
         }
+        case 'reminder': {
+            showReminder(ev);
+            break;
+        }
         default: break;
     }
 }
@@ -72,6 +72,16 @@ export const absFileUrl = (file_url) => {
     }
 };
 
+// Whether a signed URL's `expires` has passed, or will within `slackSecs`
+export const urlExpiring = (url, slackSecs = 60) => {
+    try {
+        const expires = Number(new URL(url, store.baseUrl || location.href).searchParams.get('expires'));
+        return !!expires && expires - slackSecs <= Date.now() / 1000;
+    } catch {
+        return false;
+    }
+};
+
 export const setIf = (sel, prop, val) => { const n = $(sel); if (n) n[prop] = val; };
 export const textIf = (sel, val) => { const n = $(sel); if (n) n.textContent = val; };
 
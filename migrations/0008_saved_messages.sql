@@ -0,0 +1,14 @@
+-- 0008_saved_messages.sql
+
+CREATE TABLE saved_messages (
+    user_id    TEXT NOT NULL,
+    message_id TEXT NOT NULL,
+    note       TEXT,
+    remind_at  TEXT,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (user_id, message_id),
+    FOREIGN KEY (user_id)    REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_saved_user_created ON saved_messages(user_id, created_at);
@@ -0,0 +1,24 @@
+-- 0009_scheduled_jobs.sql
+
+CREATE TABLE scheduled_jobs (
+    id           TEXT PRIMARY KEY,
+    user_id      TEXT NOT NULL,
+    kind         TEXT NOT NULL, -- 'reminder' | 'message'
+    run_at       TEXT NOT NULL,
+    channel_id   TEXT,          -- target channel for 'message'
+    message_id   TEXT,          -- referenced message for 'reminder'
+    content      TEXT,
+    file_id      TEXT,
+    status       TEXT NOT NULL DEFAULT 'pending', -- 'pending' | 'running' | 'done' | 'failed' | 'cancelled'
+    error        TEXT,
+    created_at   TEXT NOT NULL,
+    updated_at   TEXT NOT NULL,
+    completed_at TEXT,
+    FOREIGN KEY (user_id)    REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (file_id)    REFERENCES files(id)
+);
+
+CREATE INDEX idx_scheduled_due ON scheduled_jobs(status, run_at);
+CREATE INDEX idx_scheduled_user ON scheduled_jobs(user_id, status);
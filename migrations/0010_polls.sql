@@ -0,0 +1,34 @@
+-- 0010_polls.sql
+
+CREATE TABLE polls (
+    message_id   TEXT PRIMARY KEY,
+    question     TEXT NOT NULL,
+    multi_choice INTEGER NOT NULL DEFAULT 0,
+    anonymous    INTEGER NOT NULL DEFAULT 0,
+    closes_at    TEXT,
+    created_at   TEXT NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+CREATE TABLE poll_options (
+    id         TEXT PRIMARY KEY,
+    message_id TEXT NOT NULL,
+    position   INTEGER NOT NULL,
+    label      TEXT NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES polls(message_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_poll_options_message ON poll_options(message_id);
+
+CREATE TABLE poll_votes (
+    message_id TEXT NOT NULL,
+    option_id  TEXT NOT NULL,
+    user_id    TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (option_id, user_id),
+    FOREIGN KEY (message_id) REFERENCES polls(message_id) ON DELETE CASCADE,
+    FOREIGN KEY (option_id)  REFERENCES poll_options(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id)    REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_poll_votes_message ON poll_votes(message_id);
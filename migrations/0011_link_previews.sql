@@ -0,0 +1,26 @@
+-- 0011_link_previews.sql
+
+-- Cached OpenGraph/oEmbed metadata, keyed by URL
+CREATE TABLE link_previews (
+    id                TEXT PRIMARY KEY,
+    url               TEXT NOT NULL UNIQUE,
+    status            TEXT NOT NULL, -- 'ok' | 'failed'
+    title             TEXT,
+    description       TEXT,
+    site_name         TEXT,
+    image_stored_name TEXT,          -- under uploads_dir/previews
+    image_width       INTEGER,
+    image_height      INTEGER,
+    fetched_at        TEXT NOT NULL
+);
+
+CREATE TABLE message_embeds (
+    message_id TEXT NOT NULL,
+    preview_id TEXT NOT NULL,
+    position   INTEGER NOT NULL,
+    PRIMARY KEY (message_id, preview_id),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (preview_id) REFERENCES link_previews(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_message_embeds_message ON message_embeds(message_id);
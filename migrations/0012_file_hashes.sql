@@ -0,0 +1,5 @@
+-- 0012_file_hashes.sql
+
+-- SHA-256 of the stored content, computed while the upload is streamed to disk.
+-- NULL for files uploaded before this migration.
+ALTER TABLE files ADD COLUMN sha256 TEXT;
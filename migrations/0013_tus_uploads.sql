@@ -0,0 +1,16 @@
+-- 0013_tus_uploads.sql
+
+-- Unfinished resumable (tus) uploads. The id becomes the files.id once the last
+-- byte arrives, at which point the row is deleted.
+CREATE TABLE tus_uploads (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  original_name TEXT NOT NULL,
+  upload_length INTEGER NOT NULL,
+  upload_offset INTEGER NOT NULL DEFAULT 0,
+  expires_at TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_tus_uploads_expires ON tus_uploads(expires_at);
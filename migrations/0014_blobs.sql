@@ -0,0 +1,14 @@
+-- 0014_blobs.sql
+
+-- Content-addressed storage: one blob per distinct SHA-256, shared by every
+-- files row with that hash. refcount is the number of such rows; the GC deletes
+-- blobs that reach zero.
+CREATE TABLE blobs (
+  sha256 TEXT PRIMARY KEY,
+  stored_name TEXT NOT NULL, -- path relative to uploads_dir
+  size_bytes INTEGER NOT NULL,
+  refcount INTEGER NOT NULL DEFAULT 0,
+  created_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_files_sha256 ON files(sha256);
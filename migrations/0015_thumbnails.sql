@@ -0,0 +1,18 @@
+-- 0015_thumbnails.sql
+
+-- Pixel size and blurhash of image and video attachments (for videos, of the
+-- poster frame). NULL for other files and for files uploaded before this migration.
+ALTER TABLE files ADD COLUMN width INTEGER;
+ALTER TABLE files ADD COLUMN height INTEGER;
+ALTER TABLE files ADD COLUMN blurhash TEXT;
+
+-- Downscaled JPEGs of image attachments and video poster frames. Like blobs they
+-- belong to the content, so identical uploads share them; the blob GC removes them.
+CREATE TABLE thumbnails (
+  sha256 TEXT NOT NULL,
+  size INTEGER NOT NULL, -- bounding box the thumbnail was scaled to fit in
+  width INTEGER NOT NULL,
+  height INTEGER NOT NULL,
+  stored_name TEXT NOT NULL,
+  PRIMARY KEY (sha256, size)
+);
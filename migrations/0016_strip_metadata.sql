@@ -0,0 +1,7 @@
+-- 0016_strip_metadata.sql
+
+-- 1 if EXIF/XMP/text metadata was removed from the image before it was stored.
+ALTER TABLE files ADD COLUMN metadata_stripped INTEGER NOT NULL DEFAULT 0;
+
+-- Per-upload opt-out, from the `keep_metadata` key of tus Upload-Metadata.
+ALTER TABLE tus_uploads ADD COLUMN keep_metadata INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+-- 0017_upload_scanning.sql
+
+-- Virus scan state when [clamd] is configured: 'pending_scan' while the upload is
+-- being scanned, then 'clean', 'infected' (file moved to quarantine) or 'error'
+-- (scanner unreachable and fail_open set). NULL if the file was never scanned.
+ALTER TABLE files ADD COLUMN scan_status TEXT;
+-- Signature name for infected files, error message for failed scans
+ALTER TABLE files ADD COLUMN scan_result TEXT;
+ALTER TABLE files ADD COLUMN scanned_at TEXT;
@@ -0,0 +1,9 @@
+-- 0018_voice_notes.sql
+
+-- Length and waveform (one peak byte per bar, 0-255) of audio attachments.
+-- NULL for other files and for files uploaded before this migration.
+ALTER TABLE files ADD COLUMN duration_ms INTEGER;
+ALTER TABLE files ADD COLUMN waveform BLOB;
+
+-- 1 if the message is a voice note: an audio attachment without text.
+ALTER TABLE messages ADD COLUMN voice_note INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- 0019_shareplay_states.sql
+
+-- SharePlay queue of each channel (a serialized SharePlayState), so a restart doesn't
+-- lose it. The songs themselves stay in temp/; missing ones are downloaded again.
+CREATE TABLE shareplay_states (
+  channel_id TEXT PRIMARY KEY,
+  state TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+  FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE
+);
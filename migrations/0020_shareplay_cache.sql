@@ -0,0 +1,23 @@
+-- 0020_shareplay_cache.sql
+
+-- Downloaded SharePlay songs, shared by every queue item that plays them. The key is
+-- the extractor and video id (e.g. "Youtube:dQw4w9WgXcQ"); files live in temp/cache/.
+CREATE TABLE shareplay_cache (
+  key TEXT PRIMARY KEY,
+  title TEXT NOT NULL,
+  duration_seconds INTEGER NOT NULL,
+  file_path TEXT NOT NULL,
+  thumbnail_path TEXT,
+  size_bytes INTEGER NOT NULL,
+  last_used_at TEXT NOT NULL,
+  created_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_shareplay_cache_last_used ON shareplay_cache(last_used_at);
+
+-- Normalized URLs already resolved to a cache entry, so they skip yt-dlp entirely
+CREATE TABLE shareplay_cache_urls (
+  url TEXT PRIMARY KEY,
+  key TEXT NOT NULL,
+  FOREIGN KEY (key) REFERENCES shareplay_cache(key) ON DELETE CASCADE
+);
@@ -0,0 +1,5 @@
+-- 0021_shareplay_control.sql
+
+-- Who may control a channel's SharePlay: 'everyone' who can read it, only 'voice'
+-- participants, or only 'djs' (see permissions::is_shareplay_dj). Others can still vote to skip.
+ALTER TABLE channels ADD COLUMN shareplay_control TEXT NOT NULL DEFAULT 'everyone';
@@ -0,0 +1,16 @@
+-- 0022_shareplay_history.sql
+
+-- Tracks each channel's SharePlay played, logged once playback moves on from them
+-- (finished, skipped or removed) after they played for a while.
+CREATE TABLE shareplay_history (
+  id TEXT PRIMARY KEY,
+  channel_id TEXT NOT NULL,
+  url TEXT NOT NULL,
+  title TEXT NOT NULL,
+  duration_seconds INTEGER NOT NULL,
+  added_by TEXT, -- nullable so ON DELETE SET NULL can work
+  played_at TEXT NOT NULL,
+  FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
+  FOREIGN KEY (added_by) REFERENCES users(id) ON DELETE SET NULL
+);
+CREATE INDEX idx_shareplay_history_channel ON shareplay_history(channel_id, played_at);
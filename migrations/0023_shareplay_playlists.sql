@@ -0,0 +1,27 @@
+-- 0023_shareplay_playlists.sql
+
+-- SharePlay queues saved by their users. A playlist is private to its owner unless
+-- `shared`, when members of the channel it was saved in can see and load it too.
+CREATE TABLE shareplay_playlists (
+  id TEXT PRIMARY KEY,
+  owner_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  channel_id TEXT, -- where it was saved; nullable so ON DELETE SET NULL can work
+  shared INTEGER NOT NULL DEFAULT 0,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+  FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE SET NULL
+);
+CREATE INDEX idx_shareplay_playlists_owner ON shareplay_playlists(owner_id);
+CREATE INDEX idx_shareplay_playlists_channel ON shareplay_playlists(channel_id);
+
+CREATE TABLE shareplay_playlist_entries (
+  playlist_id TEXT NOT NULL,
+  position INTEGER NOT NULL,
+  url TEXT NOT NULL,
+  title TEXT NOT NULL,
+  duration_seconds INTEGER NOT NULL,
+  PRIMARY KEY (playlist_id, position),
+  FOREIGN KEY (playlist_id) REFERENCES shareplay_playlists(id) ON DELETE CASCADE
+);
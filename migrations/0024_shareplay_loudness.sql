@@ -0,0 +1,6 @@
+-- 0024_shareplay_loudness.sql
+
+-- EBU R128 loudness of cached SharePlay songs, measured once after download so
+-- clients can all apply the same gain. NULL if it couldn't be measured.
+ALTER TABLE shareplay_cache ADD COLUMN loudness_lufs REAL;
+ALTER TABLE shareplay_cache ADD COLUMN true_peak_db REAL;
@@ -0,0 +1,5 @@
+-- 0025_shareplay_cache_private_urls.sql
+
+-- Links to uploaded files are no longer remembered by URL: who may open one depends on
+-- the user and on the link's signature, so it has to go through the access check.
+DELETE FROM shareplay_cache_urls WHERE key LIKE 'stuffchat:%' OR key LIKE 'video:stuffchat:%';
@@ -0,0 +1,6 @@
+-- 0026_reminder_inbox.sql
+
+-- A fired reminder keeps its event until the owner dismisses it, so one that fired
+-- while they were offline is shown when they next connect.
+ALTER TABLE scheduled_jobs ADD COLUMN notification TEXT;
+ALTER TABLE scheduled_jobs ADD COLUMN dismissed_at TEXT;
@@ -0,0 +1,6 @@
+-- 0027_shareplay_video_entries.sql
+
+-- Whether a saved or played song was a watch-party video, so loading or requeueing
+-- it plays it as one again.
+ALTER TABLE shareplay_playlist_entries ADD COLUMN video INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE shareplay_history ADD COLUMN video INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,306 @@
+use crate::db::Db;
+use crate::errors::ApiError;
+use sqlx::Row;
+use std::path::Path;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::io::AsyncReadExt;
+
+/// Number of bars in a waveform. Shorter clips get one bar per block instead.
+const WAVEFORM_LEN: usize = 128;
+/// Peaks are first taken over 10 ms blocks, then merged into the waveform bars.
+const BLOCKS_PER_SECOND: u32 = 100;
+/// ffmpeg decodes to 8 kHz mono; plenty for a waveform and a duration.
+const FFMPEG_SAMPLE_RATE: u32 = 8000;
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(60);
+/// Measuring loudness decodes the whole track at full rate, so it gets longer.
+const LOUDNESS_TIMEOUT: Duration = Duration::from_secs(300);
+/// Gain is held back so a normalized track's true peak stays below this.
+const MAX_TRUE_PEAK_DB: f64 = -1.0;
+
+/// Duration and waveform of an audio attachment.
+pub struct AudioInfo {
+    pub duration_ms: i64,
+    /// Peak level of each bar, scaled so the loudest one is 255.
+    pub waveform: Vec<u8>,
+}
+
+/// Decode an audio file with symphonia, or with ffmpeg for codecs it lacks (Opus,
+/// mostly). Returns `None` for other files and for anything that fails to decode;
+/// the upload itself goes ahead either way.
+pub async fn analyze(path: &Path, mime: Option<&str>) -> Option<AudioInfo> {
+    let mime = mime?;
+    if !mime.starts_with("audio/") && !mime.starts_with("video/") {
+        return None;
+    }
+    let owned_path = path.to_path_buf();
+    let owned_mime = mime.to_string();
+    match tokio::task::spawn_blocking(move || decode(&owned_path, &owned_mime)).await {
+        Ok(Ok(info)) => return Some(info),
+        Ok(Err(e)) => log::debug!("symphonia can't decode {}: {}", path.display(), e),
+        Err(e) => log::warn!("Audio decoding task for {} failed: {}", path.display(), e),
+    }
+    decode_with_ffmpeg(path).await
+}
+
+/// Running peak level over fixed-size blocks of frames.
+struct Peaks {
+    block_frames: u64,
+    blocks: Vec<f32>,
+    current: f32,
+    frames: u64,
+}
+
+impl Peaks {
+    fn new(sample_rate: u32) -> Self {
+        Peaks {
+            block_frames: (sample_rate / BLOCKS_PER_SECOND).max(1) as u64,
+            blocks: Vec::new(),
+            current: 0.0,
+            frames: 0,
+        }
+    }
+
+    /// `amplitude` is the loudest channel of one frame, in 0.0..=1.0.
+    fn push(&mut self, amplitude: f32) {
+        self.current = self.current.max(amplitude);
+        self.frames += 1;
+        if self.frames.is_multiple_of(self.block_frames) {
+            self.blocks.push(self.current);
+            self.current = 0.0;
+        }
+    }
+
+    fn finish(mut self, sample_rate: u32) -> Option<AudioInfo> {
+        if self.frames == 0 {
+            return None;
+        }
+        if !self.frames.is_multiple_of(self.block_frames) {
+            self.blocks.push(self.current);
+        }
+        Some(AudioInfo {
+            duration_ms: (self.frames * 1000 / sample_rate as u64) as i64,
+            waveform: waveform(&self.blocks),
+        })
+    }
+}
+
+/// Merge block peaks into at most `WAVEFORM_LEN` bars, normalized to 0..=255.
+fn waveform(blocks: &[f32]) -> Vec<u8> {
+    let len = blocks.len().min(WAVEFORM_LEN);
+    let bars: Vec<f32> = (0..len)
+        .map(|i| {
+            let (start, end) = (i * blocks.len() / len, (i + 1) * blocks.len() / len);
+            blocks[start..end.max(start + 1)]
+                .iter()
+                .fold(0.0f32, |a, &b| a.max(b))
+        })
+        .collect();
+    let loudest = bars.iter().fold(0.0f32, |a, &b| a.max(b));
+    if loudest <= 0.0 {
+        return vec![0; len];
+    }
+    bars.iter()
+        .map(|b| (b / loudest * 255.0).round().clamp(0.0, 255.0) as u8)
+        .collect()
+}
+
+fn decode(path: &Path, mime: &str) -> Result<AudioInfo, SymphoniaError> {
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.mime_type(mime);
+    let mut format = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())?
+        .format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(SymphoniaError::Unsupported("no audio track"))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or(SymphoniaError::Unsupported("unknown sample rate"))?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut peaks = Peaks::new(sample_rate);
+    let mut buf: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // A corrupt packet here and there isn't worth giving up over
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let spec = *decoded.spec();
+        let buf = match &mut buf {
+            Some(b) if b.capacity() >= decoded.capacity() * spec.channels.count() => b,
+            _ => buf.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(decoded);
+        for frame in buf.samples().chunks(spec.channels.count().max(1)) {
+            peaks.push(frame.iter().fold(0.0f32, |a, s| a.max(s.abs())));
+        }
+    }
+    peaks
+        .finish(sample_rate)
+        .ok_or(SymphoniaError::Unsupported("no audio frames"))
+}
+
+/// `None` if ffmpeg isn't installed or can't read the file.
+async fn decode_with_ffmpeg(path: &Path) -> Option<AudioInfo> {
+    let child = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-vn", "-ac", "1", "-ar", &FFMPEG_SAMPLE_RATE.to_string()])
+        .args(["-f", "s16le", "pipe:1"])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => {
+            log::debug!("ffmpeg unavailable, skipping audio analysis: {}", e);
+            return None;
+        }
+    };
+    let mut stdout = child.stdout.take()?;
+
+    // Read the PCM as it comes rather than buffering the whole decoded file
+    let read = async {
+        let mut peaks = Peaks::new(FFMPEG_SAMPLE_RATE);
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut carry: Option<u8> = None;
+        loop {
+            let n = stdout.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let mut bytes = &buf[..n];
+            if let Some(lo) = carry.take() {
+                peaks.push(sample_amplitude(lo, bytes[0]));
+                bytes = &bytes[1..];
+            }
+            let mut samples = bytes.chunks_exact(2);
+            for s in &mut samples {
+                peaks.push(sample_amplitude(s[0], s[1]));
+            }
+            carry = samples.remainder().first().copied();
+        }
+        let status = child.wait().await?;
+        Ok::<_, std::io::Error>((status, peaks))
+    };
+    match tokio::time::timeout(FFMPEG_TIMEOUT, read).await {
+        Ok(Ok((status, peaks))) if status.success() => peaks.finish(FFMPEG_SAMPLE_RATE),
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => {
+            log::debug!("Reading ffmpeg output for {} failed: {}", path.display(), e);
+            None
+        }
+        Err(_) => {
+            log::warn!("ffmpeg timed out on {}", path.display());
+            None
+        }
+    }
+}
+
+/// EBU R128 loudness of a whole track.
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS
+    pub integrated_lufs: f64,
+    /// True peak, in dBTP
+    pub true_peak_db: f64,
+}
+
+impl Loudness {
+    /// The gain that brings the track to `target_lufs`, less if that would push its
+    /// true peak over -1 dBTP.
+    pub fn gain_db(&self, target_lufs: f64) -> f64 {
+        (target_lufs - self.integrated_lufs).min(MAX_TRUE_PEAK_DB - self.true_peak_db)
+    }
+}
+
+/// Measure a track with ffmpeg's `loudnorm` filter. `None` if ffmpeg isn't installed,
+/// can't read the file, or the track is silent.
+pub async fn measure_loudness(path: &Path) -> Option<Loudness> {
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .args(["-vn", "-af", "loudnorm=print_format=json", "-f", "null", "-"])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+    let output = match tokio::time::timeout(LOUDNESS_TIMEOUT, output).await {
+        Ok(Ok(out)) if out.status.success() => out,
+        Ok(Ok(_)) => return None,
+        Ok(Err(e)) => {
+            log::debug!("ffmpeg unavailable, skipping loudness analysis: {}", e);
+            return None;
+        }
+        Err(_) => {
+            log::warn!("ffmpeg timed out measuring loudness of {}", path.display());
+            return None;
+        }
+    };
+
+    // loudnorm prints its measurements as a flat JSON object after everything else
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let json = &stderr[stderr.rfind('{')?..=stderr.rfind('}')?];
+    let stats: serde_json::Value = serde_json::from_str(json).ok()?;
+    // Strings like "-14.20", or "-inf" for silence
+    let field = |name: &str| {
+        stats[name]
+            .as_str()?
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+    };
+    Some(Loudness {
+        integrated_lufs: field("input_i")?,
+        true_peak_db: field("input_tp")?,
+    })
+}
+
+fn sample_amplitude(lo: u8, hi: u8) -> f32 {
+    (i16::from_le_bytes([lo, hi]) as f32 / i16::MAX as f32).abs().min(1.0)
+}
+
+/// Duration and waveform of another file with the same content, so re-uploads
+/// don't have to decode it again.
+pub async fn known_audio_info(db: &Db, sha256: &str) -> Result<Option<AudioInfo>, ApiError> {
+    let row = sqlx::query(
+        "SELECT duration_ms, waveform FROM files
+         WHERE sha256 = ? AND duration_ms IS NOT NULL LIMIT 1",
+    )
+    .bind(sha256)
+    .fetch_optional(&db.0)
+    .await?;
+    Ok(row.map(|r| AudioInfo {
+        duration_ms: r.get("duration_ms"),
+        waveform: r.get::<Option<Vec<u8>>, _>("waveform").unwrap_or_default(),
+    }))
+}
@@ -0,0 +1,229 @@
+use crate::config::Config;
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::storage::Storage;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Uploads are stored once per distinct content, at `blobs/ab/abcdef...` (the SHA-256
+/// in hex, sharded by its first byte). `files` rows point at a blob through `sha256`,
+/// and `blobs.refcount` counts those rows, so a blob is only deleted once nothing
+/// references it.
+const BLOB_DIR: &str = "blobs";
+
+/// Uploads hold this shared while they put a blob and commit its reference; the GC
+/// takes it exclusively, so a blob that is being re-uploaded can't be deleted between
+/// the upload finding it in storage and its refcount going up.
+static BLOB_LOCK: tokio::sync::RwLock<()> = tokio::sync::RwLock::const_new(());
+
+/// Path of a blob relative to the storage root; this is what goes in `files.stored_name`.
+pub fn blob_stored_name(sha256: &str) -> String {
+    format!("{}/{}/{}", BLOB_DIR, &sha256[..2], sha256)
+}
+
+/// A blob that is in storage but whose reference is not committed yet. Keep it alive
+/// until the transaction that calls `add_ref` has committed.
+pub struct PendingBlob {
+    pub stored_name: String,
+    sha256: String,
+    size: i64,
+    _lock: tokio::sync::RwLockReadGuard<'static, ()>,
+}
+
+/// Make sure the blob for `sha256` is in storage, uploading `temp_path` if this content
+/// is new and dropping it otherwise.
+pub async fn put_blob(
+    storage: &dyn Storage,
+    temp_path: &Path,
+    sha256: &str,
+    size: i64,
+) -> Result<PendingBlob, ApiError> {
+    let stored_name = blob_stored_name(sha256);
+    let lock = BLOB_LOCK.read().await;
+    let exists = storage.exists(&stored_name).await.map_err(|e| {
+        log::error!("Storage check for {} failed: {}", stored_name, e);
+        ApiError::Internal
+    })?;
+    if exists {
+        let _ = tokio::fs::remove_file(temp_path).await;
+    } else {
+        storage.put_file(&stored_name, temp_path).await.map_err(|e| {
+            log::error!("Storing {} failed: {}", stored_name, e);
+            ApiError::Internal
+        })?;
+    }
+    Ok(PendingBlob {
+        stored_name,
+        sha256: sha256.to_string(),
+        size,
+        _lock: lock,
+    })
+}
+
+impl PendingBlob {
+    /// Count one more `files` row pointing at this blob. Call it in the same
+    /// transaction that inserts or updates that row.
+    pub async fn add_ref(&self, conn: &mut sqlx::SqliteConnection) -> Result<(), ApiError> {
+        sqlx::query(
+            "INSERT INTO blobs (sha256, stored_name, size_bytes, refcount, created_at)
+             VALUES (?, ?, ?, 1, ?)
+             ON CONFLICT(sha256) DO UPDATE SET refcount = refcount + 1",
+        )
+        .bind(&self.sha256)
+        .bind(&self.stored_name)
+        .bind(self.size)
+        .bind(chrono::Utc::now())
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// The reference was never committed, e.g. the upload went over quota. A blob
+    /// that is new gets a row with no references, so the GC deletes it.
+    pub async fn abandon(self, db: &Db) -> Result<(), ApiError> {
+        sqlx::query(
+            "INSERT INTO blobs (sha256, stored_name, size_bytes, refcount, created_at)
+             VALUES (?, ?, ?, 0, ?)
+             ON CONFLICT(sha256) DO NOTHING",
+        )
+        .bind(&self.sha256)
+        .bind(&self.stored_name)
+        .bind(self.size)
+        .bind(chrono::Utc::now())
+        .execute(&db.0)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Delete a `files` row that nothing points to anymore (e.g. a replaced avatar) and
+/// drop its reference on the blob. Rows still used by a message, a scheduled message
+/// or an avatar are left alone. Returns whether the row was deleted.
+pub async fn release_file(db: &Db, file_id: &str) -> Result<bool, ApiError> {
+    let mut tx = db.0.begin().await?;
+    let row = sqlx::query(
+        "SELECT sha256 FROM files f
+         WHERE f.id = ?
+           AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.file_id = f.id)
+           AND NOT EXISTS (SELECT 1 FROM scheduled_jobs j WHERE j.file_id = f.id AND j.status IN ('pending', 'running'))
+           AND NOT EXISTS (SELECT 1 FROM users u WHERE u.avatar_file_id = f.id)
+           AND NOT EXISTS (SELECT 1 FROM profile_pictures p WHERE p.file_id = f.id)",
+    )
+    .bind(file_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(false);
+    };
+
+    sqlx::query("DELETE FROM files WHERE id = ?")
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await?;
+    if let Some(sha256) = row.get::<Option<String>, _>("sha256") {
+        sqlx::query("UPDATE blobs SET refcount = refcount - 1 WHERE sha256 = ?")
+            .bind(&sha256)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Delete blobs whose refcount dropped to zero. Returns the number removed.
+pub async fn gc_unreferenced_blobs(storage: &dyn Storage, db: &Db) -> Result<u64, ApiError> {
+    let _lock = BLOB_LOCK.write().await;
+    let rows = sqlx::query("DELETE FROM blobs WHERE refcount <= 0 RETURNING sha256, stored_name")
+        .fetch_all(&db.0)
+        .await?;
+    for r in &rows {
+        let stored: String = r.get("stored_name");
+        if let Err(e) = storage.delete(&stored).await {
+            log::warn!("Failed to delete blob {}: {}", stored, e);
+        }
+        crate::thumbnails::delete_thumbnails(storage, db, r.get("sha256")).await?;
+    }
+    Ok(rows.len() as u64)
+}
+
+pub async fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut f = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// One-off migration (`--dedupe-uploads`): move every file still stored under its own
+/// `{uuid}.{ext}` name in `uploads_dir` into the blob store, hashing it and collapsing
+/// duplicates. Safe to re-run; files already in the blob store are skipped.
+pub async fn dedupe_uploads_dir(
+    cfg: &Config,
+    db: &Db,
+    storage: &dyn Storage,
+) -> Result<(), ApiError> {
+    // Quarantined and still-scanning uploads aren't in uploads_dir proper; leave them be
+    let rows = sqlx::query(
+        "SELECT id, stored_name FROM files
+         WHERE stored_name NOT LIKE 'blobs/%' AND (scan_status IS NULL OR scan_status IN ('clean', 'error'))",
+    )
+        .fetch_all(&db.0)
+        .await?;
+    log::info!("Dedupe: {} files to migrate", rows.len());
+
+    let (mut migrated, mut duplicates, mut missing) = (0, 0, 0);
+    for r in rows {
+        let id: String = r.get("id");
+        let stored: String = r.get("stored_name");
+        let old_path: PathBuf = Path::new(&cfg.uploads_dir).join(&stored);
+
+        let sha256 = match hash_file(&old_path).await {
+            Ok(h) => h,
+            Err(e) => {
+                log::warn!("Dedupe: skipping file {} ({}): {}", id, stored, e);
+                missing += 1;
+                continue;
+            }
+        };
+        let size = tokio::fs::metadata(&old_path)
+            .await
+            .map(|m| m.len() as i64)
+            .map_err(|_| ApiError::Internal)?;
+        if storage
+            .exists(&blob_stored_name(&sha256))
+            .await
+            .unwrap_or(false)
+        {
+            duplicates += 1;
+        }
+
+        let blob = put_blob(storage, &old_path, &sha256, size).await?;
+        let mut tx = db.0.begin().await?;
+        blob.add_ref(&mut tx).await?;
+        sqlx::query("UPDATE files SET stored_name = ?, sha256 = ?, size_bytes = ? WHERE id = ?")
+            .bind(&blob.stored_name)
+            .bind(&sha256)
+            .bind(size)
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        migrated += 1;
+    }
+
+    log::info!(
+        "Dedupe: migrated {} files ({} duplicates removed), {} missing on disk",
+        migrated,
+        duplicates,
+        missing
+    );
+    Ok(())
+}
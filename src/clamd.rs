@@ -0,0 +1,225 @@
+use crate::config::ClamdConfig;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// clamd's default StreamMaxLength is 25 MB; chunks just need to be well below it.
+const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_REPLY_LEN: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    /// The signature name clamd reported, e.g. `Eicar-Test-Signature`
+    Infected(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ScanError {
+    #[error("clamd connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("clamd timed out")]
+    Timeout,
+    #[error("clamd error: {0}")]
+    Clamd(String),
+}
+
+/// Stream the file at `path` to clamd with the INSTREAM command. `address` is
+/// `tcp://host:port`, `unix:///path/to/clamd.sock`, or either without the scheme.
+pub async fn scan_file(cfg: &ClamdConfig, path: &Path) -> Result<ScanVerdict, ScanError> {
+    let timeout = Duration::from_secs(cfg.timeout_secs.max(1));
+    let scan = async {
+        let address = cfg.address.as_str();
+        if let Some(socket) = address.strip_prefix("unix://").or_else(|| {
+            address.starts_with('/').then_some(address)
+        }) {
+            #[cfg(unix)]
+            {
+                let stream = tokio::net::UnixStream::connect(socket).await?;
+                return instream(stream, path).await;
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = socket;
+                return Err(ScanError::Clamd("unix sockets are not supported here".into()));
+            }
+        }
+        let host = address.strip_prefix("tcp://").unwrap_or(address);
+        let stream = tokio::net::TcpStream::connect(host).await?;
+        instream(stream, path).await
+    };
+    tokio::time::timeout(timeout, scan)
+        .await
+        .map_err(|_| ScanError::Timeout)?
+}
+
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    path: &Path,
+) -> Result<ScanVerdict, ScanError> {
+    // The `z` prefix means commands and replies are NUL-terminated
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&(n as u32).to_be_bytes()).await?;
+        if let Err(e) = stream.write_all(&buf[..n]).await {
+            // clamd hangs up once the stream is over its size limit, after saying so
+            return match read_reply(&mut stream).await {
+                Ok(reply) => parse_reply(&reply),
+                Err(_) => Err(e.into()),
+            };
+        }
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+    let reply = read_reply(&mut stream).await?;
+    parse_reply(&reply)
+}
+
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String, ScanError> {
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..n]);
+        if reply.contains(&0) || reply.len() > MAX_REPLY_LEN {
+            break;
+        }
+    }
+    let end = reply.iter().position(|&b| b == 0).unwrap_or(reply.len());
+    Ok(String::from_utf8_lossy(&reply[..end]).trim().to_string())
+}
+
+/// Replies look like `stream: OK`, `stream: Eicar-Test-Signature FOUND` or
+/// `INSTREAM size limit exceeded. ERROR`.
+fn parse_reply(reply: &str) -> Result<ScanVerdict, ScanError> {
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.trim().to_string()))
+    } else if reply.is_empty() {
+        Err(ScanError::Clamd("empty reply".into()))
+    } else {
+        Err(ScanError::Clamd(reply.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A one-connection clamd that reads an INSTREAM and answers with `reply(data)`.
+    async fn fake_clamd(reply: fn(&[u8]) -> String) -> (ClamdConfig, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let len = stream.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                stream.read_exact(&mut chunk).await.unwrap();
+                data.extend_from_slice(&chunk);
+            }
+            stream.write_all(reply(&data).as_bytes()).await.unwrap();
+            stream.write_all(b"\0").await.unwrap();
+            data
+        });
+        let cfg = ClamdConfig {
+            address,
+            timeout_secs: 5,
+            fail_open: false,
+        };
+        (cfg, server)
+    }
+
+    async fn temp_file(contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("clamd-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, contents).await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn clean_file() {
+        // Several chunks, to check they're reassembled in order
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        let path = temp_file(&contents).await;
+        let (cfg, server) = fake_clamd(|_| "stream: OK".into()).await;
+        let verdict = scan_file(&cfg, &path).await.unwrap();
+        assert_eq!(verdict, ScanVerdict::Clean);
+        assert_eq!(server.await.unwrap(), contents);
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn infected_file() {
+        let path = temp_file(b"X5O!P%@AP EICAR").await;
+        let (cfg, server) = fake_clamd(|data| {
+            if data.windows(5).any(|w| w == b"EICAR") {
+                "stream: Eicar-Test-Signature FOUND".into()
+            } else {
+                "stream: OK".into()
+            }
+        })
+        .await;
+        let verdict = scan_file(&cfg, &path).await.unwrap();
+        assert_eq!(verdict, ScanVerdict::Infected("Eicar-Test-Signature".into()));
+        server.await.unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn clamd_error() {
+        let path = temp_file(b"too big").await;
+        let (cfg, server) = fake_clamd(|_| "INSTREAM size limit exceeded. ERROR".into()).await;
+        match scan_file(&cfg, &path).await {
+            Err(ScanError::Clamd(msg)) => assert!(msg.contains("size limit exceeded")),
+            other => panic!("expected a clamd error, got {:?}", other),
+        }
+        server.await.unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // Bind and drop to get a port nothing listens on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let path = temp_file(b"data").await;
+        let cfg = ClamdConfig {
+            address,
+            timeout_secs: 5,
+            fail_open: false,
+        };
+        assert!(matches!(scan_file(&cfg, &path).await, Err(ScanError::Io(_))));
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[test]
+    fn replies() {
+        assert_eq!(parse_reply("stream: OK").unwrap(), ScanVerdict::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+            ScanVerdict::Infected("Win.Test.EICAR_HDB-1".into())
+        );
+        assert!(matches!(parse_reply(""), Err(ScanError::Clamd(_))));
+    }
+}
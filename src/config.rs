@@ -1,17 +1,100 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::path::Path;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub listen: String,
     pub database_path: String,
     pub uploads_dir: String,
     pub jwt_secret: Option<String>,
     pub allowed_origins: Vec<String>,
+    /// Where clients reach the server, e.g. "https://chat.example.org"; absolute links to
+    /// uploaded files are only recognized on this origin
+    pub public_url: Option<String>,
     pub max_upload_size: usize,
+    /// Total bytes each user may upload; 0 means unlimited
+    pub storage_quota_bytes: u64,
+    /// Per-role overrides of `storage_quota_bytes`, by role name
+    pub role_storage_quotas: HashMap<String, u64>,
+    pub upload_expiry_hours: i64,
+    pub file_url_ttl_secs: i64,
     pub presence_timeout_secs: i64,
     pub invite_only: bool,
+    pub link_previews: bool,
+    pub link_previews_allow_private_networks: bool,
+    /// Remove EXIF/GPS and other metadata from uploaded JPEG, PNG and WebP images
+    pub strip_image_metadata: bool,
+    /// Rotate images upright according to their EXIF orientation before stripping it
+    pub fix_image_orientation: bool,
+    /// Where SharePlay songs are downloaded; the download cache is its `cache` subdirectory
+    pub shareplay_temp_dir: String,
+    /// Bitrate of the Opus copy of SharePlay songs served for `?quality=low`; 0 disables it
+    pub shareplay_low_bitrate_kbps: u32,
+    /// Disk budget of the SharePlay download cache; songs nobody has queued are evicted past it
+    pub shareplay_cache_bytes: u64,
+    /// SharePlay downloads run at once across all channels; others wait in their queue
+    pub shareplay_max_downloads: usize,
+    /// yt-dlp is killed if a SharePlay download takes longer than this
+    pub shareplay_download_timeout_secs: u64,
+    /// Resolve every SharePlay request to a generated tone instead of downloading it
+    pub shareplay_fake_media: bool,
+    /// Share of the voice call that must vote to skip a SharePlay track
+    pub shareplay_skip_vote_fraction: f64,
+    /// Loudness SharePlay tracks are brought to by clients, in LUFS; 0 disables it
+    pub shareplay_target_lufs: f64,
+    /// Watch-party videos are downloaded at no more than this height, in pixels
+    pub shareplay_video_max_height: u32,
+    /// Scan uploads with a ClamAV daemon before accepting them
+    pub clamd: Option<ClamdConfig>,
+    /// Store uploads in an S3-compatible bucket instead of `uploads_dir`
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClamdConfig {
+    pub address: String, // "tcp://127.0.0.1:3310" or "unix:///run/clamav/clamd.ctl"
+    #[serde(default = "default_clamd_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Accept uploads (marked as unscanned) when clamd can't be reached, instead of rejecting them
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_clamd_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Config {
+    pub endpoint: String, // e.g. "https://s3.us-east-1.amazonaws.com" or "http://localhost:9000"
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// `endpoint/bucket/key` URLs (MinIO) instead of `bucket.endpoint/key`
+    #[serde(default = "default_true")]
+    pub path_style: bool,
+    /// Redirect downloads to presigned bucket URLs instead of proxying them
+    #[serde(default = "default_true")]
+    pub presigned_redirects: bool,
+    #[serde(default = "default_presign_ttl_secs")]
+    pub presign_ttl_secs: i64,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_presign_ttl_secs() -> i64 {
+    300
 }
 
 impl Default for Config {
@@ -22,9 +105,29 @@ impl Default for Config {
             uploads_dir: "./uploads".to_string(),
             jwt_secret: None,
             allowed_origins: vec!["example.org".to_string()],
+            public_url: None,
             max_upload_size: 500 * 1024 * 1024,
+            storage_quota_bytes: 0,
+            role_storage_quotas: HashMap::new(),
+            upload_expiry_hours: 24,
+            file_url_ttl_secs: 6 * 3600,
             presence_timeout_secs: 60,
             invite_only: false,
+            link_previews: true,
+            link_previews_allow_private_networks: false,
+            strip_image_metadata: true,
+            fix_image_orientation: true,
+            shareplay_temp_dir: "temp".to_string(),
+            shareplay_low_bitrate_kbps: 48,
+            shareplay_cache_bytes: 2 * 1024 * 1024 * 1024,
+            shareplay_max_downloads: 4,
+            shareplay_download_timeout_secs: 600,
+            shareplay_fake_media: false,
+            shareplay_skip_vote_fraction: 0.5,
+            shareplay_target_lufs: -14.0,
+            shareplay_video_max_height: 720,
+            clamd: None,
+            s3: None,
         }
     }
 }
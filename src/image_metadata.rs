@@ -0,0 +1,213 @@
+use image::ImageFormat;
+use image::metadata::Orientation;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// Formats we know how to strip. Anything else is stored untouched.
+pub fn is_strippable(mime: &str) -> bool {
+    matches!(mime, "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// Remove EXIF, XMP, IPTC and text metadata (GPS position, camera serial numbers,
+/// editing history...) from the image at `path`, rewriting it in place. When
+/// `fix_orientation` is set and the EXIF orientation isn't upright, the image is
+/// rotated and re-encoded first, since dropping the tag would leave it sideways.
+/// Otherwise the pixel data is kept byte for byte.
+///
+/// Returns whether the file changed. Blocking; run it with `spawn_blocking`.
+pub fn strip_metadata(path: &Path, mime: &str, fix_orientation: bool) -> std::io::Result<bool> {
+    let format = match mime {
+        "image/jpeg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        "image/webp" => ImageFormat::WebP,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "unsupported format")),
+    };
+
+    if fix_orientation && let Some(data) = reencode_upright(path, format)? {
+        replace_file(path, &data)?;
+        return Ok(true);
+    }
+
+    let data = std::fs::read(path)?;
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(&data)?,
+        ImageFormat::Png => strip_png(&data)?,
+        _ => strip_webp(&data)?,
+    };
+    if stripped == data {
+        return Ok(false);
+    }
+    replace_file(path, &stripped)?;
+    Ok(true)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn replace_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".strip");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// `None` if the image is already upright. Encoders don't carry metadata over, so
+/// the result is clean too.
+fn reencode_upright(path: &Path, format: ImageFormat) -> std::io::Result<Option<Vec<u8>>> {
+    let to_io = |e: image::ImageError| Error::new(ErrorKind::InvalidData, e);
+    let mut reader = image::ImageReader::open(path)?;
+    reader.set_format(format);
+    let mut decoder = reader.into_decoder().map_err(to_io)?;
+    let orientation = image::ImageDecoder::orientation(&mut decoder).map_err(to_io)?;
+    if orientation == Orientation::NoTransforms {
+        return Ok(None);
+    }
+    let mut img = image::DynamicImage::from_decoder(decoder).map_err(to_io)?;
+    img.apply_orientation(orientation);
+
+    let mut out = std::io::Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, 90);
+            img.to_rgb8().write_with_encoder(encoder).map_err(to_io)?;
+        }
+        // The WebP encoder is lossless and only takes 8-bit RGB(A)
+        ImageFormat::WebP => {
+            image::DynamicImage::ImageRgba8(img.to_rgba8())
+                .write_to(&mut out, format)
+                .map_err(to_io)?;
+        }
+        _ => img.write_to(&mut out, format).map_err(to_io)?,
+    }
+    Ok(Some(out.into_inner()))
+}
+
+/// Keep only the segments needed to display the image: JFIF (APP0), ICC profiles
+/// (APP2) and Adobe colour info (APP14). Everything after EOI is dropped too, which
+/// is where phones put extra (EXIF-carrying) preview images.
+fn strip_jpeg(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid("not a JPEG"));
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut i = 2;
+    loop {
+        if i + 1 >= data.len() || data[i] != 0xFF {
+            return Err(invalid("truncated JPEG"));
+        }
+        let marker = data[i + 1];
+        match marker {
+            // Fill byte before a marker
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            0xD9 => {
+                out.extend_from_slice(&[0xFF, 0xD9]);
+                return Ok(out);
+            }
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[i..i + 2]);
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        if i + 4 > data.len() {
+            return Err(invalid("truncated JPEG"));
+        }
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        let end = i + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(invalid("truncated JPEG"));
+        }
+        let payload = &data[i + 4..end];
+        let keep = match marker {
+            0xE0 | 0xEE => true,
+            0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+            0xE1 | 0xE3..=0xED | 0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(&data[i..end]);
+        }
+        i = end;
+
+        if marker == 0xDA {
+            // Entropy-coded data runs until the next marker that isn't a stuffed
+            // zero or a restart marker
+            let start = i;
+            while i + 1 < data.len()
+                && (data[i] != 0xFF || matches!(data[i + 1], 0x00 | 0xD0..=0xD7))
+            {
+                i += 1;
+            }
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+}
+
+/// Drop the chunks that carry metadata (EXIF, text, modification time) and anything
+/// after IEND.
+fn strip_png(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return Err(invalid("not a PNG"));
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(SIGNATURE);
+    let mut i = SIGNATURE.len();
+    while i + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+        let kind = &data[i + 4..i + 8];
+        let end = i + 12 + len; // length, type, data, CRC
+        if end > data.len() {
+            return Err(invalid("truncated PNG"));
+        }
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            out.extend_from_slice(&data[i..end]);
+        }
+        if kind == b"IEND" {
+            return Ok(out);
+        }
+        i = end;
+    }
+    Err(invalid("PNG without IEND"))
+}
+
+/// Drop the EXIF and XMP chunks and clear their flags in the VP8X header.
+fn strip_webp(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(invalid("not a WebP"));
+    }
+    let riff_end = (u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize + 8)
+        .min(data.len());
+    let mut body = Vec::with_capacity(data.len());
+    body.extend_from_slice(b"WEBP");
+    let mut i = 12;
+    while i + 8 <= riff_end {
+        let kind = &data[i..i + 4];
+        let len = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        let end = (i + 8 + len + (len & 1)).min(riff_end); // chunks are padded to even sizes
+        if i + 8 + len > riff_end {
+            return Err(invalid("truncated WebP"));
+        }
+        match kind {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if len >= 1 => {
+                let start = body.len();
+                body.extend_from_slice(&data[i..end]);
+                body[start + 8] &= !(0x08 | 0x04); // EXIF and XMP present
+            }
+            _ => body.extend_from_slice(&data[i..end]),
+        }
+        i = end;
+    }
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
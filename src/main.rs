@@ -1,11 +1,22 @@
+mod audio;
 mod auth;
+mod blobs;
+mod clamd;
 mod config;
 mod db;
 mod errors;
+mod image_metadata;
+mod media_resolver;
 mod models;
 mod permissions;
+mod quotas;
 mod routes;
+mod scheduler;
 mod shareplay;
+mod shareplay_cache;
+mod storage;
+mod thumbnails;
+mod unfurl;
 mod utils;
 mod ws;
 
@@ -14,7 +25,9 @@ use crate::db::Db;
 use crate::routes::{
     admin as admin_routes, auth as auth_routes, channels as channels_routes, emojis as emojis_routes,
     files as files_routes, invites as invites_routes, messages as messages_routes,
-    reactions as reactions_routes, users as users_routes,
+    playlists as playlists_routes, polls as polls_routes,
+    reactions as reactions_routes, saved as saved_routes, scheduled as scheduled_routes,
+    tus as tus_routes, users as users_routes,
 };
 use actix::Actor;
 use actix_cors::Cors;
@@ -26,6 +39,10 @@ use env_logger::Env;
 use sqlx::Row;
 use ws::server::ChatServer;
 
+fn has_flag(flag: &str) -> bool {
+    std::env::args().skip(1).any(|arg| arg == flag)
+}
+
 fn parse_admin_arg() -> Option<String> {
     let mut args = std::env::args().skip(1);
     while let Some(arg) = args.next() {
@@ -101,7 +118,69 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    let chat_server = ChatServer::new().start();
+    let storage = storage::from_config(&cfg);
+
+    // One-off: move pre-existing uploads into the content-addressed blob store, then exit
+    if has_flag("--dedupe-uploads") {
+        if let Err(e) = blobs::dedupe_uploads_dir(&cfg, &db, storage.as_ref()).await {
+            log::error!("Dedupe failed: {}", e);
+        }
+        return Ok(());
+    }
+
+    // Restore SharePlay queues, then clear out temp files none of them use
+    let shareplay_states = match shareplay::load_states(&db).await {
+        Ok(states) => states,
+        Err(e) => {
+            log::warn!("Failed to restore SharePlay queues: {}", e);
+            Default::default()
+        }
+    };
+    if !shareplay_states.is_empty() {
+        log::info!("Startup: Restored {} SharePlay queues", shareplay_states.len());
+    }
+    let shareplay_temp_dir = std::path::PathBuf::from(&cfg.shareplay_temp_dir);
+    match shareplay::remove_orphaned_files(&shareplay_temp_dir, &shareplay_states) {
+        Ok(count) => {
+            if count > 0 {
+                log::info!("Startup: Removed {} unused SharePlay files", count);
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to clean temp directory on startup: {}", e);
+        }
+    }
+    if let Err(e) = std::fs::create_dir_all(&shareplay_temp_dir) {
+        log::warn!("Failed to create temp directory: {}", e);
+    }
+    let shareplay_cache = std::sync::Arc::new(
+        shareplay_cache::SharePlayCache::load(
+            &db,
+            shareplay_temp_dir.join("cache"),
+            cfg.shareplay_cache_bytes,
+            &shareplay_states,
+        )
+            .await
+            .expect("SharePlay cache init failed"),
+    );
+    let chat_server = ChatServer::new(
+        shareplay_states,
+        shareplay::spawn_state_writer(db.clone()),
+        shareplay::spawn_history_writer(db.clone()),
+        shareplay_cache.clone(),
+        shareplay::DownloadJobs::new(
+            cfg.shareplay_max_downloads,
+            std::time::Duration::from_secs(cfg.shareplay_download_timeout_secs),
+            shareplay_temp_dir,
+            shareplay_cache.clone(),
+            media_resolver::Resolvers::from_config(&cfg, &db, storage.clone()),
+            cfg.shareplay_target_lufs,
+        ),
+        cfg.shareplay_skip_vote_fraction,
+    )
+    .start();
+    let link_fetcher = unfurl::LinkFetcher::new(cfg.link_previews_allow_private_networks);
+    let tus_locks = Data::new(tus_routes::TusLocks::default());
     log::info!("Starting server at {}", cfg.listen);
 
     // Background task: Cleanup refresh tokens
@@ -136,18 +215,91 @@ async fn main() -> std::io::Result<()> {
         }
     });
 
-    // Clean up temp folder on startup
-    let temp_dir = std::path::Path::new("temp");
-    if temp_dir.exists() {
-        if let Err(e) = std::fs::remove_dir_all(temp_dir) {
-            log::warn!("Failed to clean temp directory on startup: {}", e);
-        } else {
-            log::info!("Cleaned temp directory on startup");
+    // Background task: Run due scheduled jobs (reminders, scheduled messages)
+    let cfg_clone = cfg.clone();
+    let db_clone = db.clone();
+    let chat_clone = chat_server.clone();
+    let fetcher_clone = link_fetcher.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            scheduler::POLL_INTERVAL_SECS,
+        ));
+        match scheduler::recover_interrupted_jobs(&db_clone).await {
+            Ok(count) => {
+                if count > 0 {
+                    log::warn!("Startup: Marked {} interrupted scheduled jobs as failed", count);
+                }
+            }
+            Err(e) => {
+                log::error!("Startup: Failed to recover scheduled jobs: {}", e);
+            }
+        }
+        loop {
+            interval.tick().await;
+            match scheduler::run_due_jobs(&cfg_clone, &db_clone, &chat_clone, &fetcher_clone).await {
+                Ok(count) => {
+                    if count > 0 {
+                        log::info!("Ran {} scheduled jobs", count);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to run scheduled jobs: {}", e);
+                }
+            }
+        }
+    });
+
+    // Background task: Expire unfinished resumable uploads, delete unreferenced blobs
+    let db_clone = db.clone();
+    let cfg_clone = cfg.clone();
+    let storage_clone = storage.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600)); // Every hour
+        loop {
+            interval.tick().await;
+            match tus_routes::cleanup_expired_uploads(&cfg_clone, &db_clone).await {
+                Ok(count) => {
+                    if count > 0 {
+                        log::info!("Removed {} expired resumable uploads", count);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to clean up expired uploads: {}", e);
+                }
+            }
+            match blobs::gc_unreferenced_blobs(storage_clone.as_ref(), &db_clone).await {
+                Ok(count) => {
+                    if count > 0 {
+                        log::info!("Deleted {} unreferenced blobs", count);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to collect unreferenced blobs: {}", e);
+                }
+            }
+        }
+    });
+
+    // Drop uploads that were cut off by the last shutdown
+    match files_routes::remove_partial_uploads(&cfg.uploads_dir) {
+        Ok(count) => {
+            if count > 0 {
+                log::info!("Startup: Removed {} partial uploads", count);
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to clean partial uploads: {}", e);
         }
     }
-    // Recreate empty temp directory
-    if let Err(e) = std::fs::create_dir_all(temp_dir) {
-        log::warn!("Failed to create temp directory: {}", e);
+    match files_routes::remove_interrupted_scans(&db).await {
+        Ok(count) => {
+            if count > 0 {
+                log::info!("Startup: Removed {} uploads whose virus scan was interrupted", count);
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to clean interrupted scans: {}", e);
+        }
     }
 
     let listen_addr = cfg.listen.clone();
@@ -160,11 +312,25 @@ async fn main() -> std::io::Result<()> {
                     .map(|s| allowed_origins.iter().any(|o| o == s))
                     .unwrap_or(false)
             })
-            .allowed_methods(vec!["GET", "POST", "PATCH", "PUT", "DELETE"])
+            .allowed_methods(vec!["GET", "HEAD", "POST", "PATCH", "PUT", "DELETE"])
             .allowed_headers(vec![
                 header::AUTHORIZATION,
                 header::ACCEPT,
                 header::CONTENT_TYPE,
+                header::HeaderName::from_static("tus-resumable"),
+                header::HeaderName::from_static("upload-length"),
+                header::HeaderName::from_static("upload-metadata"),
+                header::HeaderName::from_static("upload-offset"),
+            ])
+            .expose_headers(vec![
+                header::LOCATION,
+                header::HeaderName::from_static("tus-resumable"),
+                header::HeaderName::from_static("tus-version"),
+                header::HeaderName::from_static("tus-extension"),
+                header::HeaderName::from_static("tus-max-size"),
+                header::HeaderName::from_static("upload-length"),
+                header::HeaderName::from_static("upload-offset"),
+                header::HeaderName::from_static("upload-expires"),
             ])
             .supports_credentials()
             .max_age(3600);
@@ -175,6 +341,10 @@ async fn main() -> std::io::Result<()> {
             .app_data(Data::new(cfg.clone()))
             .app_data(Data::new(db.clone()))
             .app_data(Data::new(chat_server.clone()))
+            .app_data(Data::new(link_fetcher.clone()))
+            .app_data(tus_locks.clone())
+            .app_data(Data::from(storage.clone()))
+            .app_data(Data::from(shareplay_cache.clone()))
             .service(
                 web::scope("/api")
                     .route("/health", web::get().to(routes::health::health_check))
@@ -192,6 +362,8 @@ async fn main() -> std::io::Result<()> {
                             .route("/me", web::patch().to(users_routes::update_me))
                             .route("/me/password", web::put().to(users_routes::change_password))
                             .route("/me/avatar", web::put().to(users_routes::upload_avatar))
+                            .route("/me/saved", web::get().to(saved_routes::list_saved))
+                            .route("/me/storage", web::get().to(users_routes::my_storage))
                             .route("/{id}", web::get().to(users_routes::get_user))
                             .route("/{id}/avatar", web::get().to(users_routes::get_user_avatar)),
                     )
@@ -211,6 +383,11 @@ async fn main() -> std::io::Result<()> {
                                 "/users/{id}/roles",
                                 web::put().to(admin_routes::update_user_roles),
                             )
+                            .route("/storage", web::get().to(admin_routes::storage_report))
+                            .route(
+                                "/shareplay/cache",
+                                web::get().to(admin_routes::shareplay_cache_stats),
+                            )
                             .route("/roles", web::get().to(admin_routes::list_roles))
                             .route("/roles", web::post().to(admin_routes::create_role))
                             .route("/roles/{id}", web::delete().to(admin_routes::delete_role)),
@@ -275,6 +452,24 @@ async fn main() -> std::io::Result<()> {
                         "/messages/{id}/reactions/{emoji}",
                         web::put().to(reactions_routes::toggle_reaction),
                     )
+                    // Polls
+                    .route(
+                        "/messages/{id}/poll/votes",
+                        web::put().to(polls_routes::vote),
+                    )
+                    .route(
+                        "/messages/{id}/poll/close",
+                        web::post().to(polls_routes::close_poll),
+                    )
+                    // Saved messages
+                    .route(
+                        "/messages/{id}/saved",
+                        web::put().to(saved_routes::save_message),
+                    )
+                    .route(
+                        "/messages/{id}/saved",
+                        web::delete().to(saved_routes::unsave_message),
+                    )
                     // Presence API
                     .service(
                         web::scope("/presence")
@@ -290,7 +485,27 @@ async fn main() -> std::io::Result<()> {
                             .route("", web::get().to(invites_routes::list_my_invites)),
                     )
                     .service(
-                        web::scope("/files").route("", web::post().to(files_routes::upload_file)),
+                        web::scope("/scheduled")
+                            .route("", web::get().to(scheduled_routes::list_scheduled))
+                            .route("", web::post().to(scheduled_routes::create_scheduled))
+                            .route("/reminders", web::get().to(scheduled_routes::list_reminders))
+                            .route("/{id}/dismiss", web::post().to(scheduled_routes::dismiss_reminder))
+                            .route("/{id}", web::patch().to(scheduled_routes::edit_scheduled))
+                            .route("/{id}", web::delete().to(scheduled_routes::cancel_scheduled)),
+                    )
+                    .service(
+                        web::scope("/files")
+                            .route("", web::post().to(files_routes::upload_file))
+                            .route("/{id}/url", web::get().to(files_routes::get_file_url))
+                            // Resumable uploads (tus 1.0)
+                            .route(
+                                "/tus",
+                                web::method(actix_web::http::Method::OPTIONS).to(tus_routes::options),
+                            )
+                            .route("/tus", web::post().to(tus_routes::create_upload))
+                            .route("/tus/{id}", web::head().to(tus_routes::upload_offset))
+                            .route("/tus/{id}", web::patch().to(tus_routes::append_upload))
+                            .route("/tus/{id}", web::delete().to(tus_routes::terminate_upload)),
                     )
                     .service(
                         web::scope("/emojis")
@@ -298,20 +513,47 @@ async fn main() -> std::io::Result<()> {
                             .route("", web::post().to(emojis_routes::upload_emoji))
                             .route("/{name}", web::delete().to(emojis_routes::delete_emoji)),
                     )
+                    .service(
+                        web::scope("/shareplay/playlists")
+                            .route("", web::get().to(playlists_routes::list_playlists))
+                            .route("", web::post().to(playlists_routes::create_playlist))
+                            .route("/{id}", web::get().to(playlists_routes::get_playlist))
+                            .route("/{id}", web::patch().to(playlists_routes::edit_playlist))
+                            .route("/{id}", web::delete().to(playlists_routes::delete_playlist)),
+                    )
                     .route(
                         "/shareplay/{channel_id}/current",
                         web::get().to(routes::shareplay::get_current_track),
                     )
                     .route(
-                        "/shareplay/song/{song_id}",
-                        web::get().to(routes::shareplay::get_song_by_id),
+                        "/shareplay/{channel_id}/urls",
+                        web::get().to(routes::shareplay::get_queue_urls),
+                    )
+                    .route(
+                        "/shareplay/{channel_id}/history",
+                        web::get().to(routes::shareplay::get_history),
                     )
                     .route(
-                        "/shareplay/thumbnail/{item_id}",
-                        web::get().to(routes::shareplay::get_thumbnail_by_id),
+                        "/shareplay/{channel_id}/history/stats",
+                        web::get().to(routes::shareplay::get_history_stats),
+                    )
+                    .service(
+                        web::resource("/shareplay/song/{song_id}")
+                            .route(web::get().to(routes::shareplay::get_song_by_id))
+                            .route(web::head().to(routes::shareplay::get_song_by_id)),
+                    )
+                    .service(
+                        web::resource("/shareplay/thumbnail/{item_id}")
+                            .route(web::get().to(routes::shareplay::get_thumbnail_by_id))
+                            .route(web::head().to(routes::shareplay::get_thumbnail_by_id)),
                     ),
             )
             .route("/ws", web::get().to(ws::session::ws_route))
+            .service(
+                web::resource("/files/{id}/thumbnails/{size}")
+                    .route(web::get().to(files_routes::get_thumbnail))
+                    .route(web::head().to(files_routes::get_thumbnail)),
+            )
             .service(
                 web::resource("/files/{id}/{filename:.*}")
                     .route(web::get().to(files_routes::get_file))
@@ -321,6 +563,10 @@ async fn main() -> std::io::Result<()> {
                 "/emojis/{name}/image",
                 web::get().to(emojis_routes::get_emoji_image),
             )
+            .route(
+                "/link_previews/{id}/image",
+                web::get().to(routes::link_previews::get_preview_image),
+            )
     })
     .bind(listen_addr)?
     .run()
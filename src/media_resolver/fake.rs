@@ -0,0 +1,104 @@
+use super::{MediaInfo, MediaRequest, MediaResolver, PlaylistEntry, ProgressFn};
+use std::path::{Path, PathBuf};
+
+const SAMPLE_RATE: u32 = 8000;
+const DURATION_SECS: u64 = 10;
+
+/// Resolves everything locally, for developing and exercising SharePlay without a
+/// network or yt-dlp. Every request becomes a ten second tone named after it, with
+/// two special forms:
+/// - `playlist:a,b,c` expands to the songs `a`, `b` and `c`
+/// - `error:...` fails, like a link yt-dlp can't handle
+pub struct FakeResolver;
+
+/// A mono 16-bit WAV of a sine wave, pitched from the title so songs sound different.
+fn tone(title: &str, secs: u64) -> Vec<u8> {
+    let freq = 220.0 + (title.bytes().map(u32::from).sum::<u32>() % 440) as f32;
+    let samples = SAMPLE_RATE as usize * secs as usize;
+    let data_len = (samples * 2) as u32;
+    let mut wav = Vec::with_capacity(44 + samples * 2);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for i in 0..samples {
+        let t = i as f32 / SAMPLE_RATE as f32;
+        let sample = (t * freq * std::f32::consts::TAU).sin() * i16::MAX as f32 * 0.3;
+        wav.extend_from_slice(&(sample as i16).to_le_bytes());
+    }
+    wav
+}
+
+#[async_trait::async_trait]
+impl MediaResolver for FakeResolver {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn accepts(&self, _req: &MediaRequest) -> bool {
+        true
+    }
+
+    /// Video requests get the same tone; a `<video>` element plays it without a picture.
+    fn supports_video(&self) -> bool {
+        true
+    }
+
+    fn url_cacheable(&self, _input: &str) -> bool {
+        false
+    }
+
+    async fn playlist(&self, req: &MediaRequest) -> Result<Option<Vec<PlaylistEntry>>, String> {
+        let Some(list) = req.input.strip_prefix("playlist:") else {
+            return Ok(None);
+        };
+        Ok(Some(
+            list.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| PlaylistEntry {
+                    url: s.to_string(),
+                    title: s.to_string(),
+                    duration_seconds: DURATION_SECS,
+                    video: req.video,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn metadata(&self, req: &MediaRequest) -> Result<MediaInfo, String> {
+        if let Some(reason) = req.input.strip_prefix("error:") {
+            return Err(format!("Failed to get metadata: {}", reason));
+        }
+        Ok(MediaInfo {
+            cache_key: format!("fake:{}", req.input),
+            title: req.input.clone(),
+            duration_seconds: DURATION_SECS,
+            has_thumbnail: false,
+            source: req.input.clone(),
+        })
+    }
+
+    async fn download(
+        &self,
+        _req: &MediaRequest,
+        info: &MediaInfo,
+        stem: &Path,
+        progress: &ProgressFn<'_>,
+    ) -> Result<PathBuf, String> {
+        let path = PathBuf::from(format!("{}.wav", stem.display()));
+        tokio::fs::write(&path, tone(&info.title, info.duration_seconds))
+            .await
+            .map_err(|e| e.to_string())?;
+        progress(100.0, Some(0));
+        Ok(path)
+    }
+}
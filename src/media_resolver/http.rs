@@ -0,0 +1,133 @@
+use super::{MediaInfo, MediaRequest, MediaResolver, ProgressFn};
+use crate::unfurl::{check_url, guarded_client_builder};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+const AUDIO_EXTENSIONS: [&str; 9] = ["mp3", "m4a", "aac", "ogg", "oga", "opus", "flac", "wav", "webm"];
+
+/// Links straight to an audio file (`https://example.com/song.mp3`), fetched as is
+/// with the link preview SSRF protection. The duration is read from the file once
+/// it's downloaded.
+pub struct HttpAudioResolver {
+    client: reqwest::Client,
+    max_bytes: u64,
+    allow_private: bool,
+}
+
+impl HttpAudioResolver {
+    pub fn new(max_bytes: u64, allow_private_networks: bool) -> Self {
+        let client = guarded_client_builder(allow_private_networks)
+            .user_agent("stuffchat-shareplay/1.0")
+            .build()
+            .expect("failed to build SharePlay HTTP client");
+        Self {
+            client,
+            max_bytes,
+            allow_private: allow_private_networks,
+        }
+    }
+}
+
+/// The URL if it points at a file with an audio extension.
+fn audio_url(input: &str) -> Option<Url> {
+    let url = Url::parse(input.trim()).ok()?;
+    let name = url.path_segments()?.next_back()?.to_lowercase();
+    let (_, ext) = name.rsplit_once('.')?;
+    AUDIO_EXTENSIONS.contains(&ext).then_some(url)
+}
+
+#[async_trait::async_trait]
+impl MediaResolver for HttpAudioResolver {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn accepts(&self, req: &MediaRequest) -> bool {
+        audio_url(&req.input).is_some()
+    }
+
+    async fn metadata(&self, req: &MediaRequest) -> Result<MediaInfo, String> {
+        let url = audio_url(&req.input).ok_or("Not an audio file URL")?;
+        check_url(&url, self.allow_private).map_err(|e| e.to_string())?;
+        let file_name = url
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .unwrap_or_default();
+        let file_name = urlencoding::decode(file_name)
+            .map(|n| n.into_owned())
+            .unwrap_or_else(|_| file_name.to_string());
+        let title = file_name
+            .rsplit_once('.')
+            .map(|(stem, _)| stem.to_string())
+            .unwrap_or(file_name);
+        let normalized = crate::shareplay_cache::normalize_url(url.as_str())
+            .unwrap_or_else(|| url.to_string());
+        Ok(MediaInfo {
+            cache_key: format!("url:{}", normalized),
+            title,
+            duration_seconds: 0,
+            has_thumbnail: false,
+            source: url.to_string(),
+        })
+    }
+
+    async fn download(
+        &self,
+        _req: &MediaRequest,
+        info: &MediaInfo,
+        stem: &Path,
+        progress: &ProgressFn<'_>,
+    ) -> Result<PathBuf, String> {
+        let url = Url::parse(&info.source).map_err(|e| e.to_string())?;
+        check_url(&url, self.allow_private).map_err(|e| e.to_string())?;
+        let mut resp = self
+            .client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| format!("Download failed: {}", e))?;
+        if !resp.status().is_success() {
+            return Err(format!("Download failed: status {}", resp.status()));
+        }
+        let total = resp.content_length();
+        if total.is_some_and(|t| t > self.max_bytes) {
+            return Err("File is too large".to_string());
+        }
+
+        let ext = url
+            .path()
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_lowercase())
+            .filter(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str()))
+            .unwrap_or_else(|| "bin".to_string());
+        let path = PathBuf::from(format!("{}.{}", stem.display(), ext));
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .map_err(|e| e.to_string())?;
+        let started = Instant::now();
+        let mut received: u64 = 0;
+        while let Some(chunk) = resp
+            .chunk()
+            .await
+            .map_err(|e| format!("Download failed: {}", e))?
+        {
+            received += chunk.len() as u64;
+            if received > self.max_bytes {
+                drop(file);
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err("File is too large".to_string());
+            }
+            file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+            if let Some(total) = total.filter(|t| *t > 0) {
+                let elapsed = started.elapsed().as_secs_f64();
+                let eta = (elapsed > 0.0 && received > 0)
+                    .then(|| ((total - received.min(total)) as f64 * elapsed / received as f64) as u64);
+                progress((received as f64 / total as f64 * 100.0) as f32, eta);
+            }
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+        Ok(path)
+    }
+}
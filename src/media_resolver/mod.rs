@@ -0,0 +1,125 @@
+mod fake;
+mod http;
+mod uploads;
+mod ytdlp;
+
+pub use fake::FakeResolver;
+pub use http::HttpAudioResolver;
+pub use uploads::UploadResolver;
+pub use ytdlp::YtDlpResolver;
+
+use crate::config::Config;
+use crate::db::Db;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A song someone asked SharePlay for: a link or search terms, and who queued it.
+#[derive(Debug, Clone)]
+pub struct MediaRequest {
+    pub input: String,
+    pub user_id: Option<String>,
+    /// For a watch party: download the video too, not just the audio
+    pub video: bool,
+}
+
+/// A song in a playlist, queued and resolved on its own later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    pub url: String,
+    pub title: String,
+    pub duration_seconds: u64,
+    /// Queue it as a watch-party video
+    #[serde(default)]
+    pub video: bool,
+}
+
+/// What a resolver found out about a song before downloading it.
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    /// Names the song whatever link it came from, for the download cache
+    pub cache_key: String,
+    pub title: String,
+    /// 0 if only known once downloaded
+    pub duration_seconds: u64,
+    pub has_thumbnail: bool,
+    /// Where the resolver will fetch the song from, e.g. the URL a search settled on
+    pub source: String,
+}
+
+/// Percent done and seconds left.
+pub type ProgressFn<'a> = dyn Fn(f32, Option<u64>) + Send + Sync + 'a;
+
+/// Turns a SharePlay request into a downloaded audio file. `Resolvers` asks each
+/// resolver in turn whether it `accepts` a request; the download job then calls
+/// `playlist`, `metadata`, `thumbnail` and `download` in that order, and takes care
+/// of timeouts, cancellation and the cache. `metadata` is where a resolver checks
+/// that the requester may have the song; only `url_cacheable` links skip it.
+#[async_trait::async_trait]
+pub trait MediaResolver: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn accepts(&self, req: &MediaRequest) -> bool;
+    /// Whether `download` can fetch video for requests with `video` set; others are
+    /// never offered those requests.
+    fn supports_video(&self) -> bool {
+        false
+    }
+    /// Whether `input` is a link to the same song for everyone (rather than search
+    /// terms, or a file only some users may open), so the cache can be checked and
+    /// remember it by URL before `metadata`.
+    fn url_cacheable(&self, input: &str) -> bool {
+        input.starts_with("http://") || input.starts_with("https://")
+    }
+    /// The songs to queue instead, if the request is a playlist.
+    async fn playlist(&self, _req: &MediaRequest) -> Result<Option<Vec<PlaylistEntry>>, String> {
+        Ok(None)
+    }
+    async fn metadata(&self, req: &MediaRequest) -> Result<MediaInfo, String>;
+    /// Fetch the thumbnail as `{prefix}.*`, in any image format; the job crops it.
+    async fn thumbnail(&self, _info: &MediaInfo, _prefix: &Path) -> Option<PathBuf> {
+        None
+    }
+    /// Download the audio as `{stem}.*` and return its path.
+    async fn download(
+        &self,
+        req: &MediaRequest,
+        info: &MediaInfo,
+        stem: &Path,
+        progress: &ProgressFn<'_>,
+    ) -> Result<PathBuf, String>;
+}
+
+/// The resolvers to try, most specific first.
+#[derive(Clone)]
+pub struct Resolvers(Vec<Arc<dyn MediaResolver>>);
+
+impl Resolvers {
+    pub fn new(resolvers: Vec<Arc<dyn MediaResolver>>) -> Self {
+        Self(resolvers)
+    }
+
+    /// Uploaded files, then direct links to audio files, then whatever yt-dlp can find.
+    /// With `shareplay_fake_media` every request becomes a generated tone instead.
+    pub fn from_config(cfg: &Config, db: &Db, storage: Arc<dyn Storage>) -> Self {
+        if cfg.shareplay_fake_media {
+            log::warn!("SharePlay: fake media resolver enabled, nothing will be downloaded");
+            return Self::new(vec![Arc::new(FakeResolver)]);
+        }
+        Self::new(vec![
+            Arc::new(UploadResolver::new(cfg.clone(), db.clone(), storage)),
+            Arc::new(HttpAudioResolver::new(
+                cfg.max_upload_size as u64,
+                cfg.link_previews_allow_private_networks,
+            )),
+            Arc::new(YtDlpResolver::new(cfg.shareplay_video_max_height)),
+        ])
+    }
+
+    pub fn pick(&self, req: &MediaRequest) -> Option<Arc<dyn MediaResolver>> {
+        self.0
+            .iter()
+            .find(|r| (!req.video || r.supports_video()) && r.accepts(req))
+            .cloned()
+    }
+}
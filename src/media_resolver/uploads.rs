@@ -0,0 +1,228 @@
+use super::{MediaInfo, MediaRequest, MediaResolver, ProgressFn};
+use crate::config::Config;
+use crate::db::Db;
+use crate::routes::files::{can_access_file, verify_file_signature};
+use crate::storage::Storage;
+use sqlx::Row;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use url::Url;
+
+/// Audio files already uploaded to stuffchat, by their file link
+/// (`/files/{id}/{filename}`, signed or not), and video files for watch parties.
+/// Whoever queues one must be able to open it, either through the link's signature or
+/// as a user who passes `can_access_file`.
+pub struct UploadResolver {
+    cfg: Config,
+    db: Db,
+    storage: Arc<dyn Storage>,
+    /// `public_url`, the only origin absolute file links are accepted from
+    public_url: Option<Url>,
+}
+
+impl UploadResolver {
+    pub fn new(cfg: Config, db: Db, storage: Arc<dyn Storage>) -> Self {
+        let public_url = cfg.public_url.as_deref().and_then(|u| match Url::parse(u) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                log::warn!("Ignoring invalid public_url {:?}: {}", u, e);
+                None
+            }
+        });
+        Self {
+            cfg,
+            db,
+            storage,
+            public_url,
+        }
+    }
+
+    fn file_link(&self, input: &str) -> Option<FileLink> {
+        parse_file_link(input, self.public_url.as_ref())
+    }
+}
+
+/// What a file link points at.
+struct FileLink {
+    id: String,
+    /// The file name after the id, if the link has one
+    name: Option<String>,
+    /// `expires` and `sig`, for signed links
+    signature: Option<(i64, String)>,
+}
+
+/// Parses `/files/{id}/{filename}` paths, and absolute links to them on `public_url`.
+/// Links to other hosts are left to the other resolvers, even if their path looks the same.
+fn parse_file_link(input: &str, public_url: Option<&Url>) -> Option<FileLink> {
+    let input = input.trim();
+    let (url, root) = if input.starts_with('/') && !input.starts_with("//") {
+        (Url::parse("http://localhost").ok()?.join(input).ok()?, "")
+    } else {
+        let url = Url::parse(input).ok()?;
+        let base = public_url?;
+        if url.origin() != base.origin() {
+            return None;
+        }
+        (url, base.path().trim_end_matches('/'))
+    };
+    let mut segments = url.path().strip_prefix(root)?.strip_prefix("/files/")?.split('/');
+    let id = uuid::Uuid::parse_str(segments.next()?).ok()?.to_string();
+    let name = segments
+        .next()
+        .filter(|n| !n.is_empty())
+        .and_then(|n| urlencoding::decode(n).ok())
+        .map(|n| n.into_owned());
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let signature = param("expires")
+        .and_then(|e| e.parse().ok())
+        .zip(param("sig"));
+    Some(FileLink {
+        id,
+        name,
+        signature,
+    })
+}
+
+#[async_trait::async_trait]
+impl MediaResolver for UploadResolver {
+    fn name(&self) -> &'static str {
+        "uploads"
+    }
+
+    fn accepts(&self, req: &MediaRequest) -> bool {
+        self.file_link(&req.input).is_some()
+    }
+
+    /// Uploaded videos are played as they are, whatever their resolution.
+    fn supports_video(&self) -> bool {
+        true
+    }
+
+    /// Who may open a file depends on the user and on the link's signature, which
+    /// expires, so every request goes through the access check in `metadata`.
+    fn url_cacheable(&self, _input: &str) -> bool {
+        false
+    }
+
+    async fn metadata(&self, req: &MediaRequest) -> Result<MediaInfo, String> {
+        let FileLink { id, signature, .. } = self.file_link(&req.input).ok_or("Not a file link")?;
+        let signed = signature
+            .is_some_and(|(expires, sig)| verify_file_signature(&self.cfg, &id, expires, &sig));
+        let allowed = signed
+            || can_access_file(&self.db, req.user_id.as_deref(), &id)
+                .await
+                .map_err(|e| e.to_string())?;
+        if !allowed {
+            return Err("File not found".to_string());
+        }
+
+        let row = sqlx::query(
+            "SELECT original_name, stored_name, mime_type, sha256, duration_ms FROM files
+             WHERE id = ? AND (scan_status IS NULL OR scan_status IN ('clean', 'error'))",
+        )
+        .bind(&id)
+        .fetch_optional(&self.db.0)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("File not found")?;
+        let mime: Option<String> = row.get("mime_type");
+        let playable = mime.as_deref().is_some_and(|m| {
+            m.starts_with("audio/") || (req.video && m.starts_with("video/"))
+        });
+        if !playable {
+            return Err(if req.video {
+                "Only audio and video files can be played".to_string()
+            } else {
+                "Only audio files can be played".to_string()
+            });
+        }
+
+        let original: String = row.get("original_name");
+        let title = Path::new(&original)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or(original);
+        let sha256: Option<String> = row.get("sha256");
+        Ok(MediaInfo {
+            // Identical uploads share a cache entry
+            cache_key: format!("stuffchat:{}", sha256.unwrap_or_else(|| id.clone())),
+            title,
+            duration_seconds: row
+                .get::<Option<i64>, _>("duration_ms")
+                .map(|ms| (ms / 1000) as u64)
+                .unwrap_or(0),
+            has_thumbnail: false,
+            source: row.get("stored_name"),
+        })
+    }
+
+    async fn download(
+        &self,
+        req: &MediaRequest,
+        info: &MediaInfo,
+        stem: &Path,
+        progress: &ProgressFn<'_>,
+    ) -> Result<PathBuf, String> {
+        // Keep the extension; the song is served with the content type it implies
+        let ext = self.file_link(&req.input)
+            .and_then(|link| link.name)
+            .and_then(|name| {
+                Path::new(&name)
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_lowercase())
+            })
+            .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()))
+            .unwrap_or_else(|| "bin".to_string());
+        let path = PathBuf::from(format!("{}.{}", stem.display(), ext));
+        self.storage
+            .get_file(&info.source, &path)
+            .await
+            .map_err(|e| format!("Failed to read uploaded file: {}", e))?;
+        progress(100.0, Some(0));
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "0b6c3f8e-2f7a-4d0e-9c1a-5e4b3a2f1d0c";
+
+    #[test]
+    fn file_links() {
+        let public = Url::parse("https://chat.example.org").unwrap();
+
+        let link = parse_file_link(&format!("/files/{}/My%20Song.mp3?expires=1700000000&sig=abc", ID), None).unwrap();
+        assert_eq!(link.id, ID);
+        assert_eq!(link.name.as_deref(), Some("My Song.mp3"));
+        assert_eq!(link.signature, Some((1700000000, "abc".to_string())));
+
+        let link = parse_file_link(&format!("https://chat.example.org/files/{}/a.ogg", ID), Some(&public)).unwrap();
+        assert_eq!(link.name.as_deref(), Some("a.ogg"));
+        assert!(link.signature.is_none());
+
+        // Served under a path prefix
+        let prefixed = Url::parse("https://example.org/chat/").unwrap();
+        assert!(parse_file_link(&format!("https://example.org/chat/files/{}/a.ogg", ID), Some(&prefixed)).is_some());
+        assert!(parse_file_link(&format!("https://example.org/files/{}/a.ogg", ID), Some(&prefixed)).is_none());
+
+        for input in [
+            // Other hosts are for the http and yt-dlp resolvers
+            format!("https://cdn.example.com/files/{}/song.mp3", ID),
+            format!("http://chat.example.org/files/{}/song.mp3", ID),
+            format!("//cdn.example.com/files/{}/song.mp3", ID),
+            format!("/api/files/{}/song.mp3", ID),
+            format!("/music/files/{}/song.mp3", ID),
+            "/files/not-a-uuid/song.mp3".to_string(),
+        ] {
+            assert!(parse_file_link(&input, Some(&public)).is_none(), "{}", input);
+        }
+        // Without public_url only paths are accepted
+        assert!(parse_file_link(&format!("https://chat.example.org/files/{}/a.ogg", ID), None).is_none());
+    }
+}
@@ -0,0 +1,296 @@
+use super::{MediaInfo, MediaRequest, MediaResolver, PlaylistEntry, ProgressFn};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+
+/// Marks the lines our `--progress-template` makes yt-dlp print.
+const PROGRESS_PREFIX: &str = "shareplay-progress";
+
+/// Anything yt-dlp can download, and search terms (the first YouTube result). It
+/// accepts every request, so it goes last.
+pub struct YtDlpResolver {
+    /// Watch-party videos are downloaded at no more than this many lines
+    video_max_height: u32,
+}
+
+impl YtDlpResolver {
+    pub fn new(video_max_height: u32) -> Self {
+        Self { video_max_height }
+    }
+}
+
+/// Determines if the input looks like a URL or a search term.
+fn is_url(input: &str) -> bool {
+    let input_lower = input.to_lowercase();
+    // Check for common URL schemes
+    if input_lower.starts_with("http://") || input_lower.starts_with("https://") {
+        return true;
+    }
+    // Check for common video site patterns (without scheme)
+    let video_patterns = [
+        "youtube.com",
+        "youtu.be",
+        "vimeo.com",
+        "dailymotion.com",
+        "twitch.tv",
+        "soundcloud.com",
+        "bandcamp.com",
+    ];
+    for pattern in video_patterns {
+        if input_lower.contains(pattern) {
+            return true;
+        }
+    }
+    false
+}
+
+fn is_youtube_playlist(url: &str) -> bool {
+    let url_lower = url.to_lowercase();
+    (url_lower.contains("youtube.com") || url_lower.contains("youtu.be"))
+        && (url_lower.contains("list=") || url_lower.contains("/playlist?"))
+}
+
+/// User input is passed after `--` so yt-dlp never reads it as options; refuse anything
+/// that looks like one anyway.
+fn check_input(input: &str) -> Result<(), String> {
+    if input.trim_start().starts_with('-') {
+        return Err("Invalid link or search".to_string());
+    }
+    Ok(())
+}
+
+/// Run yt-dlp to completion. It is killed if the future is dropped, e.g. on timeout.
+async fn yt_dlp(args: &[&str]) -> Result<std::process::Output, String> {
+    Command::new("yt-dlp")
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("yt-dlp execution failed: {}", e))
+}
+
+/// Percent done and seconds left, from a line printed by our `--progress-template`.
+fn parse_progress(line: &str) -> Option<(f32, Option<u64>)> {
+    let mut fields = line.strip_prefix(PROGRESS_PREFIX)?.split_whitespace();
+    let downloaded: f64 = fields.next()?.parse().ok()?;
+    let total = fields.next()?.parse::<f64>().ok();
+    let estimate = fields.next()?.parse::<f64>().ok();
+    let eta = fields.next().and_then(|s| s.parse::<f64>().ok()).map(|s| s as u64);
+    let total = total.or(estimate).filter(|t| *t > 0.0)?;
+    Some(((downloaded / total * 100.0).clamp(0.0, 100.0) as f32, eta))
+}
+
+/// Find the first file in `dir` whose name starts with `prefix`.
+/// yt-dlp may change the extension, so we search for files starting with the prefix.
+/// Excludes thumbnail files (containing "_thumb") unless `thumbnails` is set.
+fn find_file(dir: &Path, prefix: &str, thumbnails: bool) -> Option<PathBuf> {
+    for entry in std::fs::read_dir(dir).ok()?.flatten() {
+        let file_name = entry.file_name();
+        let file_name_str = file_name.to_string_lossy();
+        if file_name_str.starts_with(prefix) && (thumbnails || !file_name_str.contains("_thumb")) {
+            return Some(entry.path());
+        }
+    }
+    None
+}
+
+/// The directory and file name prefix of a `{prefix}.*` destination.
+fn split_prefix(prefix: &Path) -> Option<(&Path, String)> {
+    Some((
+        prefix.parent()?,
+        prefix.file_name()?.to_string_lossy().to_string(),
+    ))
+}
+
+#[async_trait::async_trait]
+impl MediaResolver for YtDlpResolver {
+    fn name(&self) -> &'static str {
+        "yt-dlp"
+    }
+
+    fn accepts(&self, _req: &MediaRequest) -> bool {
+        true
+    }
+
+    fn supports_video(&self) -> bool {
+        true
+    }
+
+    fn url_cacheable(&self, input: &str) -> bool {
+        is_url(input)
+    }
+
+    async fn playlist(&self, req: &MediaRequest) -> Result<Option<Vec<PlaylistEntry>>, String> {
+        if !is_youtube_playlist(&req.input) {
+            return Ok(None);
+        }
+        check_input(&req.input)?;
+        log::info!("Detected YouTube playlist: {}", req.input);
+        let out = yt_dlp(&["--flat-playlist", "-J", "--", &req.input]).await?;
+        if !out.status.success() {
+            return Err(format!(
+                "yt-dlp playlist resolve failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let info = serde_json::from_str::<serde_json::Value>(&stdout)
+            .map_err(|e| format!("Failed to parse playlist JSON: {}", e))?;
+        let entries = info["entries"]
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|entry| {
+                        let url = entry["url"].as_str().map(|s| {
+                            if s.starts_with("http") {
+                                s.to_string()
+                            } else {
+                                format!("https://www.youtube.com/watch?v={}", s)
+                            }
+                        })?;
+                        Some(PlaylistEntry {
+                            url,
+                            title: entry["title"]
+                                .as_str()
+                                .unwrap_or("Unknown Title")
+                                .to_string(),
+                            duration_seconds: entry["duration"].as_f64().unwrap_or(0.0) as u64,
+                            video: req.video,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        Ok(Some(entries))
+    }
+
+    async fn metadata(&self, req: &MediaRequest) -> Result<MediaInfo, String> {
+        check_input(&req.input)?;
+        // Determine if input is a URL or a search term
+        let effective_url = if is_url(&req.input) {
+            req.input.clone()
+        } else {
+            // Treat as a search term - use ytsearch1: to get first result
+            format!("ytsearch1:{}", req.input)
+        };
+        log::info!(
+            "Input '{}' resolved to effective URL: {}",
+            req.input,
+            effective_url
+        );
+
+        // Simulate to get metadata
+        let out = yt_dlp(&["--simulate", "--print-json", "--", &effective_url]).await?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(format!("Failed to get metadata: {}", stderr));
+        }
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let json_line = stdout.lines().last().unwrap_or("{}");
+        let info = serde_json::from_str::<serde_json::Value>(json_line).map_err(|_| {
+            log::error!("Failed to parse metadata JSON: {}", json_line);
+            "Failed to parse metadata".to_string()
+        })?;
+
+        Ok(MediaInfo {
+            cache_key: crate::shareplay_cache::cache_key(&info, &effective_url),
+            title: info["title"]
+                .as_str()
+                .unwrap_or("Unknown Title")
+                .to_string(),
+            duration_seconds: info["duration"].as_f64().unwrap_or(0.0) as u64,
+            has_thumbnail: info["thumbnail"].is_string(),
+            source: effective_url,
+        })
+    }
+
+    async fn thumbnail(&self, info: &MediaInfo, prefix: &Path) -> Option<PathBuf> {
+        let (dir, name) = split_prefix(prefix)?;
+        let out = yt_dlp(&[
+            "--skip-download",
+            "--write-thumbnail",
+            "--convert-thumbnails",
+            "jpg",
+            "-o",
+            prefix.to_str()?,
+            "--",
+            &info.source,
+        ])
+        .await
+        .ok()?;
+        if !out.status.success() {
+            return None;
+        }
+        // yt-dlp might have saved it as prefix.jpg or similar
+        find_file(dir, &name, true)
+    }
+
+    async fn download(
+        &self,
+        req: &MediaRequest,
+        info: &MediaInfo,
+        stem: &Path,
+        progress: &ProgressFn<'_>,
+    ) -> Result<PathBuf, String> {
+        let (dir, name) = split_prefix(stem).ok_or("Invalid download path")?;
+        let output_template = format!("{}.%(ext)s", stem.display());
+        let template = format!(
+            "download:{} %(progress.downloaded_bytes)s %(progress.total_bytes)s %(progress.total_bytes_estimate)s %(progress.eta)s",
+            PROGRESS_PREFIX
+        );
+        let mut cmd = Command::new("yt-dlp");
+        if req.video {
+            // H.264 and AAC in MP4 play in every browser; other codecs are only a fallback
+            let h = self.video_max_height;
+            let format = format!(
+                "bv*[height<={h}][vcodec^=avc1]+ba[ext=m4a]/b[height<={h}][ext=mp4]/bv*[height<={h}]+ba/b[height<={h}]"
+            );
+            cmd.args(["-f", &format, "--merge-output-format", "mp4"]);
+        } else {
+            cmd.args(["-x", "--audio-format", "opus", "--audio-quality", "0"]); // Best quality
+        }
+        let mut child = cmd
+            //.arg("--extractor-args")
+            //.arg("youtube:player_client=default,-android_sdkless")
+            .args(["--cookies-from-browser", "firefox"])
+            .args(["--newline", "--progress-template", &template])
+            .args(["-o", &output_template])
+            .arg("--")
+            .arg(&info.source)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("yt-dlp execution failed: {}", e))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        let report = async {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Some((percent, eta_secs)) = parse_progress(&line) {
+                    progress(percent, eta_secs);
+                }
+            }
+        };
+        let mut errors = String::new();
+        let _ = tokio::join!(report, stderr.read_to_string(&mut errors));
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| format!("yt-dlp execution failed: {}", e))?;
+        if !status.success() {
+            return Err(format!("Download failed: {}", errors));
+        }
+
+        find_file(dir, &name, false).ok_or_else(|| {
+            log::error!("yt-dlp reported success but file not found in {}", dir.display());
+            "Downloaded file not found".to_string()
+        })
+    }
+}
@@ -1,5 +1,8 @@
 use crate::{db::Db, errors::ApiError};
 
+/// Role permission bit: may control SharePlay in channels limited to DJs.
+pub const PERM_SHAREPLAY_DJ: i64 = 1 << 0;
+
 pub async fn require_admin(db: &Db, user_id: &str) -> Result<(), ApiError> {
     let row = sqlx::query(
         "SELECT 1 FROM user_roles ur INNER JOIN roles r ON ur.role_id = r.id WHERE ur.user_id = ? AND r.name = 'admin' LIMIT 1",
@@ -14,3 +17,26 @@ pub async fn require_admin(db: &Db, user_id: &str) -> Result<(), ApiError> {
         Err(ApiError::Forbidden)
     }
 }
+
+/// Whether the user may control SharePlay in a channel limited to DJs: server admins,
+/// users with a role that has `PERM_SHAREPLAY_DJ`, the channel's owner and its managers.
+pub async fn is_shareplay_dj(db: &Db, user_id: &str, channel_id: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT 1 FROM user_roles ur INNER JOIN roles r ON ur.role_id = r.id
+         WHERE ur.user_id = ? AND (r.name = 'admin' OR r.permissions & ? != 0)
+         UNION ALL
+         SELECT 1 FROM channels WHERE id = ? AND created_by = ?
+         UNION ALL
+         SELECT 1 FROM channel_members WHERE channel_id = ? AND user_id = ? AND can_manage = 1
+         LIMIT 1",
+    )
+    .bind(user_id)
+    .bind(PERM_SHAREPLAY_DJ)
+    .bind(channel_id)
+    .bind(user_id)
+    .bind(channel_id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    Ok(row.is_some())
+}
@@ -0,0 +1,111 @@
+use crate::config::Config;
+use crate::db::Db;
+use crate::errors::ApiError;
+use sqlx::Row;
+use std::collections::HashMap;
+
+/// The quota in bytes of a user with the given roles, `None` if unlimited. Role
+/// overrides win over `storage_quota_bytes`; with several overridden roles the most
+/// generous one applies. A quota of 0 means unlimited.
+fn quota_for_roles<'a>(cfg: &Config, roles: impl IntoIterator<Item = &'a str>) -> Option<i64> {
+    let overrides: Vec<u64> = roles
+        .into_iter()
+        .filter_map(|name| cfg.role_storage_quotas.get(name).copied())
+        .collect();
+    let quota = if overrides.is_empty() {
+        cfg.storage_quota_bytes
+    } else if overrides.contains(&0) {
+        0
+    } else {
+        overrides.into_iter().max().unwrap_or(0)
+    };
+    (quota > 0).then_some(quota as i64)
+}
+
+/// The user's storage quota in bytes, `None` if unlimited.
+pub async fn storage_quota(cfg: &Config, db: &Db, user_id: &str) -> Result<Option<i64>, ApiError> {
+    if cfg.role_storage_quotas.is_empty() {
+        return Ok(quota_for_roles(cfg, []));
+    }
+    let rows = sqlx::query(
+        "SELECT r.name FROM user_roles ur INNER JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = ?",
+    )
+    .bind(user_id)
+    .fetch_all(&db.0)
+    .await?;
+    Ok(quota_for_roles(cfg, rows.iter().map(|r| r.get::<&str, _>("name"))))
+}
+
+/// `storage_quota` of every user, by user id, in one query.
+pub async fn storage_quotas(cfg: &Config, db: &Db) -> Result<HashMap<String, Option<i64>>, ApiError> {
+    let rows = sqlx::query(
+        "SELECT u.id, r.name FROM users u
+         LEFT JOIN user_roles ur ON ur.user_id = u.id
+         LEFT JOIN roles r ON r.id = ur.role_id",
+    )
+    .fetch_all(&db.0)
+    .await?;
+    let mut roles: HashMap<String, Vec<String>> = HashMap::new();
+    for r in rows {
+        let names = roles.entry(r.get("id")).or_default();
+        if let Some(name) = r.get::<Option<String>, _>("name") {
+            names.push(name);
+        }
+    }
+    Ok(roles
+        .into_iter()
+        .map(|(id, names)| {
+            let quota = quota_for_roles(cfg, names.iter().map(String::as_str));
+            (id, quota)
+        })
+        .collect())
+}
+
+/// What counts against quotas: one row (`user_id`, `files`, `bytes`) per stored upload
+/// or unfinished resumable upload. Identical uploads are stored once, but each counts
+/// against the uploader's quota. Quarantined files don't count. Unfinished resumable
+/// uploads count at their full `Upload-Length`, so several started at once can't
+/// together go over quota. Bind the current time as its one parameter.
+pub const COUNTED_STORAGE: &str = "SELECT user_id, 1 AS files, size_bytes AS bytes FROM files
+     WHERE scan_status IS NULL OR scan_status != 'infected'
+     UNION ALL
+     SELECT user_id, 0, upload_length FROM tus_uploads WHERE expires_at > ?";
+
+/// Bytes counted against the user's quota; see `COUNTED_STORAGE`.
+pub async fn storage_used<'e, E>(db: E, user_id: &str) -> Result<i64, ApiError>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let row = sqlx::query(&format!(
+        "SELECT COALESCE(SUM(bytes), 0) AS used FROM ({}) WHERE user_id = ?",
+        COUNTED_STORAGE
+    ))
+    .bind(chrono::Utc::now())
+    .bind(user_id)
+    .fetch_one(db)
+    .await?;
+    Ok(row.get("used"))
+}
+
+/// Fail with `QuotaExceeded` if the user is over `quota` now that `incoming` bytes
+/// have been written in `tx`. Call it after that write, before committing: SQLite
+/// lets one writer in at a time, so of several uploads racing for the last of a
+/// quota, only those that fit get through.
+pub async fn recheck_quota(
+    tx: &mut sqlx::SqliteConnection,
+    user_id: &str,
+    quota: Option<i64>,
+    incoming: i64,
+) -> Result<(), ApiError> {
+    let Some(quota) = quota else {
+        return Ok(());
+    };
+    let used = storage_used(&mut *tx, user_id).await?;
+    if used > quota {
+        return Err(ApiError::QuotaExceeded {
+            used: used - incoming,
+            quota,
+        });
+    }
+    Ok(())
+}
@@ -10,6 +10,7 @@ use crate::{
     db::Db,
     errors::ApiError,
     permissions::require_admin,
+    shareplay_cache::SharePlayCache,
     ws::server::{BroadcastAll, ChatServer},
 };
 
@@ -158,6 +159,7 @@ pub async fn set_user_password(
 pub async fn upload_user_avatar(
     cfg: web::Data<Config>,
     db: web::Data<Db>,
+    storage: web::Data<dyn crate::storage::Storage>,
     chat: web::Data<actix::Addr<ChatServer>>,
     user: AuthUser,
     path: web::Path<String>,
@@ -174,12 +176,18 @@ pub async fn upload_user_avatar(
         .map_err(|_| ApiError::BadRequest("invalid multipart".into()))?
     {
         let field = item;
-        let s = save_multipart_file(&cfg, &db, &target_id, field).await?;
+        let s = save_multipart_file(&cfg, &db, storage.get_ref(), &target_id, field, false).await?;
         saved = Some(s);
         break;
     }
     let saved = saved.ok_or(ApiError::BadRequest("no file".into()))?;
 
+    let previous: Option<String> =
+        sqlx::query("SELECT avatar_file_id FROM users WHERE id = ?")
+            .bind(&target_id)
+            .fetch_optional(&db.0)
+            .await?
+            .and_then(|r| r.get("avatar_file_id"));
     sqlx::query("UPDATE users SET avatar_file_id = ?, updated_at = ? WHERE id = ?")
         .bind(&saved.file_id)
         .bind(chrono::Utc::now())
@@ -187,6 +195,11 @@ pub async fn upload_user_avatar(
         .execute(&db.0)
         .await?;
 
+    // The old avatar's blob is freed once nothing else uses that file
+    if let Some(old_id) = previous {
+        crate::blobs::release_file(&db, &old_id).await?;
+    }
+
     // Broadcast profile update
     chat.do_send(BroadcastAll {
         payload: serde_json::json!({
@@ -206,6 +219,107 @@ pub async fn upload_user_avatar(
     Ok(HttpResponse::Ok().json(serde_json::json!({"avatar_file_id": saved.file_id})))
 }
 
+#[derive(Serialize)]
+struct UserStorage {
+    user_id: String,
+    username: String,
+    file_count: i64,
+    used_bytes: i64,
+    quota_bytes: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ChannelStorage {
+    channel_id: String,
+    name: String,
+    file_count: i64,
+    bytes: i64,
+}
+
+// GET /api/admin/storage - storage by uploader and by channel the files were posted in.
+// `total_bytes` counts every upload; `stored_bytes` is what is actually stored after
+// identical uploads are deduplicated.
+pub async fn storage_report(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    require_admin(&db, &user.user_id).await?;
+
+    // Usage as the quotas see it, so it matches GET /api/users/me/storage
+    let mut quotas = crate::quotas::storage_quotas(&cfg, &db).await?;
+    let users: Vec<UserStorage> = sqlx::query(&format!(
+        "SELECT u.id, u.username, COALESCE(s.file_count, 0) AS file_count, COALESCE(s.used, 0) AS used
+         FROM users u
+         LEFT JOIN (SELECT user_id, SUM(files) AS file_count, SUM(bytes) AS used
+                    FROM ({}) GROUP BY user_id) s ON s.user_id = u.id
+         ORDER BY used DESC, u.username ASC",
+        crate::quotas::COUNTED_STORAGE
+    ))
+    .bind(chrono::Utc::now())
+    .fetch_all(&db.0)
+    .await?
+    .into_iter()
+    .map(|r| {
+        let user_id: String = r.get("id");
+        UserStorage {
+            quota_bytes: quotas.remove(&user_id).flatten(),
+            user_id,
+            username: r.get("username"),
+            file_count: r.get("file_count"),
+            used_bytes: r.get("used"),
+        }
+    })
+    .collect();
+
+    // A file posted twice in the same channel only counts once there
+    let channels: Vec<ChannelStorage> = sqlx::query(
+        "SELECT c.id, c.name, COUNT(*) AS file_count, COALESCE(SUM(f.size_bytes), 0) AS bytes
+         FROM (SELECT DISTINCT channel_id, file_id FROM messages
+               WHERE file_id IS NOT NULL AND deleted_at IS NULL) mf
+         INNER JOIN files f ON f.id = mf.file_id
+         INNER JOIN channels c ON c.id = mf.channel_id
+         WHERE c.deleted_at IS NULL
+         GROUP BY c.id
+         ORDER BY bytes DESC, c.name ASC",
+    )
+    .fetch_all(&db.0)
+    .await?
+    .into_iter()
+    .map(|r| ChannelStorage {
+        channel_id: r.get("id"),
+        name: r.get("name"),
+        file_count: r.get("file_count"),
+        bytes: r.get("bytes"),
+    })
+    .collect();
+
+    let totals = sqlx::query(
+        "SELECT (SELECT COALESCE(SUM(size_bytes), 0) FROM files) AS total,
+                (SELECT COALESCE(SUM(size_bytes), 0) FROM blobs WHERE refcount > 0)
+                + (SELECT COALESCE(SUM(size_bytes), 0) FROM files WHERE stored_name NOT LIKE 'blobs/%') AS stored",
+    )
+    .fetch_one(&db.0)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "total_bytes": totals.get::<i64, _>("total"),
+        "stored_bytes": totals.get::<i64, _>("stored"),
+        "users": users,
+        "channels": channels,
+    })))
+}
+
+// GET /api/admin/shareplay/cache - size, budget and hit rate of the SharePlay download cache
+pub async fn shareplay_cache_stats(
+    db: web::Data<Db>,
+    user: AuthUser,
+    cache: web::Data<SharePlayCache>,
+) -> Result<HttpResponse, ApiError> {
+    require_admin(&db, &user.user_id).await?;
+    Ok(HttpResponse::Ok().json(cache.stats()))
+}
+
 pub async fn list_roles(db: web::Data<Db>, user: AuthUser) -> Result<HttpResponse, ApiError> {
     require_admin(&db, &user.user_id).await?;
     let rows = sqlx::query("SELECT id, name, permissions, created_at FROM roles ORDER BY name ASC")
@@ -1,3 +1,4 @@
+use crate::shareplay::SharePlayControl;
 use crate::{auth::AuthUser, db::Db, errors::ApiError};
 use actix_web::{HttpResponse, web};
 use chrono::Utc;
@@ -11,12 +12,13 @@ struct ChannelResp {
     is_voice: bool,
     is_private: bool,
     is_owner: bool,
+    shareplay_control: String,
     last_message_at: Option<chrono::DateTime<Utc>>,
 }
 
 pub async fn list_channels(db: web::Data<Db>, user: AuthUser) -> Result<HttpResponse, ApiError> {
     let rows = sqlx::query(
-        "SELECT c.id, c.name, c.is_voice, c.is_private, c.created_by,
+        "SELECT c.id, c.name, c.is_voice, c.is_private, c.created_by, c.shareplay_control,
         (SELECT created_at FROM messages WHERE channel_id = c.id AND deleted_at IS NULL ORDER BY created_at DESC LIMIT 1) as last_message_at
         FROM channels c
         INNER JOIN channel_members m ON m.channel_id = c.id
@@ -33,6 +35,7 @@ pub async fn list_channels(db: web::Data<Db>, user: AuthUser) -> Result<HttpResp
             is_voice: r.get::<i64, _>("is_voice") != 0,
             is_private: r.get::<i64, _>("is_private") != 0,
             is_owner: r.get::<String, _>("created_by") == user.user_id,
+            shareplay_control: r.get("shareplay_control"),
             last_message_at: r.get("last_message_at"),
         })
         .collect();
@@ -171,6 +174,7 @@ pub struct EditChannelReq {
     pub name: Option<String>,
     pub is_voice: Option<bool>,
     pub is_private: Option<bool>,
+    pub shareplay_control: Option<String>,
 }
 
 pub async fn edit_channel(
@@ -211,6 +215,13 @@ pub async fn edit_channel(
         updates.push("is_private = ?");
         params.push(if is_private { "1" } else { "0" }.to_string());
     }
+    if let Some(control) = &body.shareplay_control {
+        let control = SharePlayControl::parse(control).ok_or_else(|| {
+            ApiError::BadRequest("shareplay_control must be everyone, voice or djs".into())
+        })?;
+        updates.push("shareplay_control = ?");
+        params.push(control.as_str().to_string());
+    }
 
     if updates.is_empty() {
         return Ok(HttpResponse::Ok().finish());
@@ -1,11 +1,21 @@
-use crate::{auth::AuthUser, config::Config, db::Db, errors::ApiError};
+use crate::{
+    auth::AuthUser,
+    config::Config,
+    db::Db,
+    errors::ApiError,
+    storage::{ServeMeta, Storage},
+};
 use actix_multipart::Multipart;
-use actix_web::{HttpResponse, web};
+use actix_web::{HttpRequest, HttpResponse, web};
 use futures_util::TryStreamExt as _;
 use image::ImageFormat;
 use sqlx::Row;
 use std::io::Cursor;
-use std::path::Path;
+
+// Emoji images are stored as `emojis/{id}.png`; the id is the custom_emojis row id.
+fn emoji_key(id: &str) -> String {
+    format!("emojis/{}.png", id)
+}
 
 #[derive(serde::Serialize, sqlx::FromRow)]
 pub struct Emoji {
@@ -26,6 +36,7 @@ pub async fn list_emojis(db: web::Data<Db>) -> Result<HttpResponse, ApiError> {
 pub async fn upload_emoji(
     cfg: web::Data<Config>,
     db: web::Data<Db>,
+    storage: web::Data<dyn Storage>,
     user: AuthUser,
     mut payload: Multipart,
 ) -> Result<HttpResponse, ApiError> {
@@ -85,16 +96,10 @@ pub async fn upload_emoji(
     let png_bytes = png_data.into_inner();
 
     let id = uuid::Uuid::new_v4().to_string();
-    let filename = format!("{}.png", id);
-    let emoji_dir = Path::new(&cfg.uploads_dir).join("emojis");
-
-    // Ensure emoji dir exists
-    if !emoji_dir.exists() {
-        std::fs::create_dir_all(&emoji_dir).map_err(|_| ApiError::Internal)?;
-    }
-
-    let file_path = emoji_dir.join(&filename);
-    std::fs::write(&file_path, &png_bytes).map_err(|_| ApiError::Internal)?;
+    storage
+        .put_bytes(&emoji_key(&id), png_bytes)
+        .await
+        .map_err(|_| ApiError::Internal)?;
 
     // Insert into DB. We use the 'id' for the filename but 'name' for the unique identifier.
     // Store filename in id column for simplicity in serving, or add a column.
@@ -143,8 +148,9 @@ pub async fn delete_emoji(
 }
 
 pub async fn get_emoji_image(
-    cfg: web::Data<Config>,
     db: web::Data<Db>,
+    storage: web::Data<dyn Storage>,
+    req: HttpRequest,
     path: web::Path<String>,
 ) -> Result<HttpResponse, ApiError> {
     let name = path.into_inner();
@@ -156,14 +162,14 @@ pub async fn get_emoji_image(
 
     let row = row.ok_or(ApiError::NotFound)?;
     let id: String = row.get("id");
-    let filename = format!("{}.png", id);
-    let p = Path::new(&cfg.uploads_dir).join("emojis").join(&filename);
-
-    if !p.exists() {
-        return Err(ApiError::NotFound);
-    }
-
-    Ok(HttpResponse::Ok()
-        .content_type("image/png")
-        .body(std::fs::read(p).map_err(|_| ApiError::Internal)?))
+    storage
+        .serve(
+            &req,
+            &emoji_key(&id),
+            &ServeMeta {
+                content_type: "image/png",
+                filename: &format!("{}.png", name),
+            },
+        )
+        .await
 }
@@ -1,31 +1,43 @@
-use crate::{auth::AuthUser, config::Config, db::Db, errors::ApiError};
+use crate::{auth::AuthUser, config::Config, db::Db, errors::ApiError, storage::{ServeMeta, Storage}};
 use actix_multipart::Multipart;
 use actix_web::{HttpRequest, HttpResponse, web};
 use futures_util::TryStreamExt as _;
+use hmac::{Hmac, Mac};
 use sanitize_filename::sanitize;
+use serde::Deserialize;
 use sqlx::Row;
-use std::io::Write;
-use std::path::Path;
-use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
 
 #[derive(serde::Serialize)]
 pub struct UploadResp {
     pub file_id: String,
 }
 
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// Opt out of metadata stripping for this upload
+    pub keep_metadata: Option<bool>,
+}
+
 pub async fn upload_file(
     cfg: web::Data<Config>,
     db: web::Data<Db>,
+    storage: web::Data<dyn Storage>,
     user: AuthUser,
+    q: web::Query<UploadQuery>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, ApiError> {
+    let keep_metadata = q.keep_metadata.unwrap_or(false);
     let mut saved: Option<SavedFile> = None;
     while let Some(item) = payload
         .try_next()
         .await
         .map_err(|_| ApiError::BadRequest("invalid multipart".into()))?
     {
-        let s = save_multipart_file(&cfg, &db, &user.user_id, item).await?;
+        let s = save_multipart_file(&cfg, &db, storage.get_ref(), &user.user_id, item, keep_metadata)
+            .await?;
         saved = Some(s);
         break;
     }
@@ -39,88 +51,610 @@ pub struct SavedFile {
     pub file_id: String
 }
 
+/// In-progress uploads are written to `uploads_dir` under this prefix and renamed
+/// into place once complete. Anything still carrying it after a restart was
+/// interrupted and can be deleted.
+const PARTIAL_PREFIX: &str = ".partial-";
+/// Enough leading bytes for `infer` to recognise every format it knows.
+const SNIFF_LEN: usize = 8192;
+
+/// Deletes the file at `path` on drop unless `keep` is set, so rejected or aborted
+/// uploads (including the handler future being dropped on disconnect) leave nothing behind.
+struct PartialFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
 pub async fn save_multipart_file(
     cfg: &Config,
     db: &Db,
+    storage: &dyn Storage,
     user_id: &str,
     mut field: actix_multipart::Field,
+    keep_metadata: bool,
 ) -> Result<SavedFile, ApiError> {
     let content_disposition = field.content_disposition().cloned();
     let original = content_disposition
         .and_then(|cd| cd.get_filename().map(|s| s.to_string()))
         .unwrap_or_else(|| "upload.bin".into());
     let original_safe = sanitize(&original);
-    let mut data: Vec<u8> = Vec::new();
+    let id = uuid::Uuid::new_v4().to_string();
+    let quota = crate::quotas::storage_quota(cfg, db, user_id).await?;
+    let used = match quota {
+        // Checked again when the upload is stored; this only stops it early
+        Some(_) => crate::quotas::storage_used(&db.0, user_id).await?,
+        None => 0,
+    };
+
+    // Stream to a temp file instead of buffering, hashing and keeping the head for sniffing
+    let mut partial = PartialFile {
+        path: Path::new(&cfg.uploads_dir).join(format!("{}{}", PARTIAL_PREFIX, id)),
+        keep: false,
+    };
+    let mut out = tokio::fs::File::create(&partial.path)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    let mut hasher = Sha256::new();
+    let mut head: Vec<u8> = Vec::with_capacity(SNIFF_LEN);
+    let mut size: usize = 0;
     while let Some(chunk) = field
         .try_next()
         .await
         .map_err(|_| ApiError::BadRequest("upload read error".into()))?
     {
-        data.extend_from_slice(&chunk);
-        if data.len() > cfg.max_upload_size {
+        size += chunk.len();
+        if size > cfg.max_upload_size {
             return Err(ApiError::BadRequest("file too large".into()));
         }
+        if let Some(quota) = quota
+            && used + size as i64 > quota
+        {
+            return Err(ApiError::QuotaExceeded { used, quota });
+        }
+        if head.len() < SNIFF_LEN {
+            let take = (SNIFF_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..take]);
+        }
+        hasher.update(&chunk);
+        out.write_all(&chunk).await.map_err(|_| ApiError::Internal)?;
     }
-    let mime = infer::get(&data).map(|t| t.mime_type().to_string());
-    let id = uuid::Uuid::new_v4().to_string();
-    let ext = Path::new(&original_safe)
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("bin");
-    let stored_name = format!("{}.{}", id, ext);
-    let path = std::path::Path::new(&cfg.uploads_dir).join(&stored_name);
-    let mut f = std::fs::File::create(&path).map_err(|_| ApiError::Internal)?;
-    f.write_all(&data).map_err(|_| ApiError::Internal)?;
-
-    sqlx::query("INSERT INTO files(id, user_id, original_name, stored_name, mime_type, size_bytes, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)")
-        .bind(&id).bind(user_id).bind(&original_safe).bind(&stored_name).bind(&mime)
-        .bind(data.len() as i64).bind(chrono::Utc::now())
-        .execute(&db.0).await?;
+    out.sync_all().await.map_err(|_| ApiError::Internal)?;
+    drop(out);
+
+    store_completed_upload(
+        cfg,
+        db,
+        storage,
+        user_id,
+        CompletedUpload {
+            id: &id,
+            original_name: &original_safe,
+            temp_path: &partial.path,
+            head: &head,
+            sha256: hex::encode(hasher.finalize()),
+            size: size as i64,
+            keep_metadata,
+        },
+    )
+    .await?;
+    partial.keep = true;
 
     Ok(SavedFile {
         file_id: id
     })
 }
 
+/// A fully received upload sitting in a temp file, ready to be moved into place.
+pub(crate) struct CompletedUpload<'a> {
+    pub id: &'a str,
+    pub original_name: &'a str, // already sanitized
+    pub temp_path: &'a Path,
+    pub head: &'a [u8], // first bytes of the content, for MIME sniffing
+    pub sha256: String,
+    pub size: i64,
+    pub keep_metadata: bool, // skip `strip_image_metadata` for this upload
+}
+
+/// Move an upload into the blob store and record it in `files`.
+/// Shared by multipart and resumable uploads.
+pub(crate) async fn store_completed_upload(
+    cfg: &Config,
+    db: &Db,
+    storage: &dyn Storage,
+    user_id: &str,
+    upload: CompletedUpload<'_>,
+) -> Result<(), ApiError> {
+    let mime = infer::get(upload.head).map(|t| t.mime_type().to_string());
+
+    // Scan first, so nothing else parses a file that turns out to be malicious
+    let scan = scan_upload(cfg, db, user_id, &upload, mime.as_deref()).await?;
+    let id = upload.id;
+    let res = finish_upload(cfg, db, storage, user_id, upload, mime, scan.as_ref()).await;
+    if res.is_err() && scan.is_some() {
+        // Don't leave the `pending_scan` row behind for an upload that never completed
+        sqlx::query("DELETE FROM files WHERE id = ? AND scan_status = 'pending_scan'")
+            .bind(id)
+            .execute(&db.0)
+            .await?;
+    }
+    res
+}
+
+async fn finish_upload(
+    cfg: &Config,
+    db: &Db,
+    storage: &dyn Storage,
+    user_id: &str,
+    mut upload: CompletedUpload<'_>,
+    mime: Option<String>,
+    scan: Option<&ScanRecord>,
+) -> Result<(), ApiError> {
+    // Strip metadata before anything else sees the file; this changes its hash
+    let mut metadata_stripped = false;
+    if let Some(m) = mime.as_deref()
+        && cfg.strip_image_metadata
+        && !upload.keep_metadata
+        && crate::image_metadata::is_strippable(m)
+    {
+        let path = upload.temp_path.to_path_buf();
+        let m = m.to_string();
+        let fix_orientation = cfg.fix_image_orientation;
+        match tokio::task::spawn_blocking(move || {
+            crate::image_metadata::strip_metadata(&path, &m, fix_orientation)
+        })
+        .await
+        {
+            Ok(Ok(changed)) => {
+                if changed {
+                    upload.sha256 = crate::blobs::hash_file(upload.temp_path)
+                        .await
+                        .map_err(|_| ApiError::Internal)?;
+                    upload.size = tokio::fs::metadata(upload.temp_path)
+                        .await
+                        .map_err(|_| ApiError::Internal)?
+                        .len() as i64;
+                }
+                // Only if there was something to remove
+                metadata_stripped = changed;
+            }
+            // Store it as uploaded rather than reject it; the row records that it wasn't stripped
+            Ok(Err(e)) => log::warn!("Could not strip metadata from upload {}: {}", upload.id, e),
+            Err(e) => log::error!("Metadata stripping task failed: {}", e),
+        }
+    }
+
+    // Thumbnails are made from the temp file, so before it's handed to storage
+    let media = match crate::thumbnails::known_media_info(db, &upload.sha256).await? {
+        Some(known) => Some(known),
+        None => crate::thumbnails::analyze(upload.temp_path, mime.as_deref()).await,
+    };
+
+    // Audio, and audio-only containers that gave no poster frame (e.g. WebM voice notes)
+    let audio = match &media {
+        Some(_) => None,
+        None => match crate::audio::known_audio_info(db, &upload.sha256).await? {
+            Some(known) => Some(known),
+            None => crate::audio::analyze(upload.temp_path, mime.as_deref()).await,
+        },
+    };
+
+    let quota = crate::quotas::storage_quota(cfg, db, user_id).await?;
+    let blob =
+        crate::blobs::put_blob(storage, upload.temp_path, &upload.sha256, upload.size).await?;
+    let thumbnails = match &media {
+        Some(m) => crate::thumbnails::put_thumbnails(storage, &upload.sha256, &m.thumbnails).await,
+        None => Vec::new(),
+    };
+    // A blob stored for an upload that then fails (e.g. over quota) is left to the GC
+    let res = async {
+        let mut tx = db.0.begin().await?;
+        blob.add_ref(&mut tx).await?;
+        crate::thumbnails::insert_thumbnails(&mut tx, &upload.sha256, &thumbnails).await?;
+        // Scanned uploads already have a `pending_scan` row, which this completes
+        let now = chrono::Utc::now();
+        sqlx::query(
+            "INSERT INTO files(id, user_id, original_name, stored_name, mime_type, size_bytes, sha256, width, height, blurhash, duration_ms, waveform, metadata_stripped, scan_status, scan_result, scanned_at, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET
+               stored_name = excluded.stored_name, mime_type = excluded.mime_type, size_bytes = excluded.size_bytes,
+               sha256 = excluded.sha256, width = excluded.width, height = excluded.height, blurhash = excluded.blurhash,
+               duration_ms = excluded.duration_ms, waveform = excluded.waveform, metadata_stripped = excluded.metadata_stripped, scan_status = excluded.scan_status,
+               scan_result = excluded.scan_result, scanned_at = excluded.scanned_at",
+        )
+        .bind(upload.id).bind(user_id).bind(upload.original_name).bind(&blob.stored_name).bind(&mime)
+        .bind(upload.size).bind(&upload.sha256)
+        .bind(media.as_ref().map(|m| m.width)).bind(media.as_ref().map(|m| m.height))
+        .bind(media.as_ref().and_then(|m| m.blurhash.as_deref()))
+        .bind(audio.as_ref().map(|a| a.duration_ms)).bind(audio.as_ref().map(|a| &a.waveform))
+        .bind(metadata_stripped)
+        .bind(scan.map(|s| s.status)).bind(scan.and_then(|s| s.result.as_deref()))
+        .bind(scan.map(|_| now))
+        .bind(now)
+        .execute(&mut *tx).await?;
+        // A finished resumable upload stops counting at its full length
+        sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+            .bind(upload.id)
+            .execute(&mut *tx)
+            .await?;
+        crate::quotas::recheck_quota(&mut tx, user_id, quota, upload.size).await?;
+        tx.commit().await?;
+        Ok::<_, ApiError>(())
+    }
+    .await;
+    if res.is_err() {
+        blob.abandon(db).await?;
+    }
+    res
+}
+
+/// Infected uploads are moved here, under `uploads_dir`, for an admin to look at.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Scan result to record on the `files` row.
+struct ScanRecord {
+    status: &'static str,
+    result: Option<String>,
+}
+
+/// With `[clamd]` configured, record the upload as `pending_scan` and stream it to
+/// clamd. Infected files are quarantined and rejected; the row stays behind as a
+/// record of the result. `None` when scanning is off.
+async fn scan_upload(
+    cfg: &Config,
+    db: &Db,
+    user_id: &str,
+    upload: &CompletedUpload<'_>,
+    mime: Option<&str>,
+) -> Result<Option<ScanRecord>, ApiError> {
+    let Some(clamd) = &cfg.clamd else {
+        return Ok(None);
+    };
+    let temp_name = upload
+        .temp_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    sqlx::query(
+        "INSERT INTO files(id, user_id, original_name, stored_name, mime_type, size_bytes, sha256, scan_status, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, 'pending_scan', ?)",
+    )
+    .bind(upload.id)
+    .bind(user_id)
+    .bind(upload.original_name)
+    .bind(&temp_name)
+    .bind(mime)
+    .bind(upload.size)
+    .bind(&upload.sha256)
+    .bind(chrono::Utc::now())
+    .execute(&db.0)
+    .await?;
+
+    match crate::clamd::scan_file(clamd, upload.temp_path).await {
+        Ok(crate::clamd::ScanVerdict::Clean) => Ok(Some(ScanRecord {
+            status: "clean",
+            result: None,
+        })),
+        Ok(crate::clamd::ScanVerdict::Infected(signature)) => {
+            let stored = format!("{}/{}", QUARANTINE_DIR, upload.id);
+            let dest = Path::new(&cfg.uploads_dir).join(&stored);
+            if let Err(e) = quarantine(upload.temp_path, &dest).await {
+                log::error!("Failed to quarantine upload {}: {}", upload.id, e);
+            }
+            let mut tx = db.0.begin().await?;
+            sqlx::query(
+                "UPDATE files SET stored_name = ?, scan_status = 'infected', scan_result = ?, scanned_at = ? WHERE id = ?",
+            )
+            .bind(&stored)
+            .bind(&signature)
+            .bind(chrono::Utc::now())
+            .bind(upload.id)
+            .execute(&mut *tx)
+            .await?;
+            // A rejected resumable upload can't be resumed; its file is in quarantine
+            sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+                .bind(upload.id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            log::warn!(
+                "Upload {} ({}) by user {} is infected with {}; quarantined",
+                upload.id,
+                upload.original_name,
+                user_id,
+                signature
+            );
+            Err(ApiError::BadRequest(format!(
+                "file rejected: malware detected ({})",
+                signature
+            )))
+        }
+        Err(e) if clamd.fail_open => {
+            log::warn!("Upload {} accepted unscanned: {}", upload.id, e);
+            Ok(Some(ScanRecord {
+                status: "error",
+                result: Some(e.to_string()),
+            }))
+        }
+        Err(e) => {
+            log::error!("Scanning upload {} failed: {}", upload.id, e);
+            sqlx::query("DELETE FROM files WHERE id = ?")
+                .bind(upload.id)
+                .execute(&db.0)
+                .await?;
+            Err(ApiError::Internal)
+        }
+    }
+}
+
+async fn quarantine(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::rename(from, to).await
+}
+
+/// Drop the rows of uploads whose scan was cut off by a restart; their temp files
+/// are gone too.
+pub async fn remove_interrupted_scans(db: &Db) -> Result<u64, ApiError> {
+    let res = sqlx::query("DELETE FROM files WHERE scan_status = 'pending_scan'")
+        .execute(&db.0)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+/// Delete temp files left behind by uploads that were interrupted by a restart.
+pub fn remove_partial_uploads(uploads_dir: &str) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(uploads_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(PARTIAL_PREFIX) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn file_url_mac(cfg: &Config, file_id: &str, expires: i64) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(cfg.jwt_secret_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}:{}", file_id, expires).as_bytes());
+    mac
+}
+
+/// Path to a file that loads without an Authorization header until it expires,
+/// for `<img>`/`<video>` tags and the desktop app. Only hand these out to users
+/// who passed `can_access_file`.
+pub fn signed_file_url(cfg: &Config, file_id: &str, original_name: &str) -> String {
+    let (expires, sig) = file_signature(cfg, file_id);
+    format!(
+        "/files/{}/{}?expires={}&sig={}",
+        file_id,
+        urlencoding::encode(original_name),
+        expires,
+        sig
+    )
+}
+
+/// Like `signed_file_url`, for one of the file's thumbnails. The signature covers the
+/// file, so it is valid for all of them.
+pub fn signed_thumbnail_url(cfg: &Config, file_id: &str, size: i64) -> String {
+    let (expires, sig) = file_signature(cfg, file_id);
+    format!(
+        "/files/{}/thumbnails/{}?expires={}&sig={}",
+        file_id, size, expires, sig
+    )
+}
+
+pub(crate) fn file_signature(cfg: &Config, file_id: &str) -> (i64, String) {
+    // Round the expiry up so repeated listings produce the same URL and browsers can cache it
+    let expires = (chrono::Utc::now().timestamp() + cfg.file_url_ttl_secs.max(60) + 299) / 300 * 300;
+    let sig = hex::encode(file_url_mac(cfg, file_id, expires).finalize().into_bytes());
+    (expires, sig)
+}
+
+pub(crate) fn verify_file_signature(cfg: &Config, file_id: &str, expires: i64, sig: &str) -> bool {
+    if expires < chrono::Utc::now().timestamp() {
+        return false;
+    }
+    let Ok(sig) = hex::decode(sig) else {
+        return false;
+    };
+    file_url_mac(cfg, file_id, expires).verify_slice(&sig).is_ok()
+}
+
+/// Avatars are public. Anything else needs a user who uploaded the file or can read
+/// a channel with a live message that references it.
+pub async fn can_access_file(
+    db: &Db,
+    user_id: Option<&str>,
+    file_id: &str,
+) -> Result<bool, ApiError> {
+    let row = sqlx::query(
+        "SELECT 1 FROM files f
+         WHERE f.id = ? AND (f.scan_status IS NULL OR f.scan_status IN ('clean', 'error')) AND (
+           EXISTS (SELECT 1 FROM users u WHERE u.avatar_file_id = f.id)
+           OR f.user_id = ?
+           OR EXISTS (
+             SELECT 1 FROM messages m
+             INNER JOIN channels c ON c.id = m.channel_id
+             INNER JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = ?
+             WHERE m.file_id = f.id AND m.deleted_at IS NULL AND c.deleted_at IS NULL AND cm.can_read = 1
+           )
+         )",
+    )
+    .bind(file_id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    Ok(row.is_some())
+}
+
+// Get a fresh signed URL for a file the caller can access, e.g. once an old one expired.
+pub async fn get_file_url(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let row = sqlx::query("SELECT original_name FROM files WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&db.0)
+        .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+    if !can_access_file(&db, Some(&user.user_id), &id).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let original: String = row.get("original_name");
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "url": signed_file_url(&cfg, &id, &original),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct FileAccessQuery {
+    pub expires: Option<i64>,
+    pub sig: Option<String>,
+}
+
+/// A download needs either a valid signature from `signed_file_url` or a bearer token
+/// for a user who passes `can_access_file`.
+async fn authorize_download(
+    cfg: &Config,
+    db: &Db,
+    user: Option<&AuthUser>,
+    file_id: &str,
+    q: &FileAccessQuery,
+) -> Result<(), ApiError> {
+    let signed = match (q.expires, q.sig.as_deref()) {
+        (Some(expires), Some(sig)) => verify_file_signature(cfg, file_id, expires, sig),
+        _ => false,
+    };
+    if signed {
+        return Ok(());
+    }
+    let user_id = user.map(|u| u.user_id.as_str());
+    if can_access_file(db, user_id, file_id).await? {
+        Ok(())
+    } else if user.is_some() {
+        Err(ApiError::Forbidden)
+    } else {
+        Err(ApiError::Unauthorized)
+    }
+}
+
 // Updated to accept a filename segment for the URL, but only use the ID for lookup.
 // Route pattern should be something like: .route("/files/{id}/{filename:.*}", web::get().to(get_file))
+// Requires either a valid signature from `signed_file_url` or a bearer token for a user
+// who passes `can_access_file`.
 pub async fn get_file(
     cfg: web::Data<Config>,
     db: web::Data<Db>,
+    storage: web::Data<dyn Storage>,
+    user: Option<AuthUser>,
     req: HttpRequest,
     path: web::Path<(String, String)>, // (id, filename) - filename is ignored for lookup
+    q: web::Query<FileAccessQuery>,
 ) -> Result<HttpResponse, ApiError> {
     let (id, _filename) = path.into_inner();
 
-    let row = sqlx::query("SELECT stored_name, original_name, mime_type FROM files WHERE id = ?")
+    let row = sqlx::query("SELECT stored_name, original_name, mime_type, scan_status FROM files WHERE id = ?")
         .bind(&id)
         .fetch_optional(&db.0)
         .await?;
     let row = row.ok_or(ApiError::NotFound)?;
+    match row.get::<Option<String>, _>("scan_status").as_deref() {
+        Some("pending_scan") => return Err(ApiError::Conflict("file is still being scanned".into())),
+        Some("infected") => return Err(ApiError::NotFound),
+        _ => {}
+    }
+
+    authorize_download(&cfg, &db, user.as_ref(), &id, &q).await?;
+
     let stored: String = row.get("stored_name");
     let original: String = row.get("original_name"); // already sanitized at upload
     let mime: Option<String> = row.get("mime_type");
 
-    let p = std::path::Path::new(&cfg.uploads_dir).join(&stored);
-    if !p.exists() { return Err(ApiError::NotFound); }
-
-    let named = actix_files::NamedFile::open_async(p).await
-        .map_err(|_| ApiError::Internal)?
-        .use_last_modified(true)
-        .prefer_utf8(true)
-        .set_content_disposition(ContentDisposition {
-            disposition: DispositionType::Inline,
-            parameters: vec![DispositionParam::Filename(original.clone())],
-        });
-
-    let mut resp = named.into_response(&req);
-    if let Some(m) = mime {
-        if let Ok(val) = actix_web::http::header::HeaderValue::from_str(&m) {
-            resp.headers_mut().insert(
-                actix_web::http::header::CONTENT_TYPE,
-                val,
-            );
-        }
-    }
-    Ok(resp)
-}
\ No newline at end of file
+    // Blobs have no extension, so guess from the original name when sniffing found nothing
+    let content_type = mime.unwrap_or_else(|| {
+        Path::new(&original)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(actix_files::file_extension_to_mime)
+            .unwrap_or(actix_web::mime::APPLICATION_OCTET_STREAM)
+            .to_string()
+    });
+
+    let resp = storage
+        .serve(
+            &req,
+            &stored,
+            &ServeMeta {
+                content_type: &content_type,
+                filename: &original,
+            },
+        )
+        .await?;
+    Ok(private(resp))
+}
+
+// GET /files/{id}/thumbnails/{size} - same access rules (and signatures) as the file
+pub async fn get_thumbnail(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    storage: web::Data<dyn Storage>,
+    user: Option<AuthUser>,
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    q: web::Query<FileAccessQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, size) = path.into_inner();
+
+    let row = sqlx::query(
+        "SELECT t.stored_name FROM files f
+         INNER JOIN thumbnails t ON t.sha256 = f.sha256
+         WHERE f.id = ? AND t.size = ?",
+    )
+    .bind(&id)
+    .bind(size)
+    .fetch_optional(&db.0)
+    .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+
+    authorize_download(&cfg, &db, user.as_ref(), &id, &q).await?;
+
+    let stored: String = row.get("stored_name");
+    let filename = format!("{}.jpg", size);
+    let resp = storage
+        .serve(
+            &req,
+            &stored,
+            &ServeMeta {
+                content_type: "image/jpeg",
+                filename: &filename,
+            },
+        )
+        .await?;
+    Ok(private(resp))
+}
+
+/// Access-controlled content must not end up in shared caches.
+fn private(mut resp: HttpResponse) -> HttpResponse {
+    resp.headers_mut().insert(
+        actix_web::http::header::CACHE_CONTROL,
+        actix_web::http::header::HeaderValue::from_static("private"),
+    );
+    resp
+}
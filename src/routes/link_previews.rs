@@ -0,0 +1,32 @@
+use crate::{config::Config, db::Db, errors::ApiError};
+use actix_web::{HttpResponse, web};
+use sqlx::Row;
+use std::path::Path;
+
+pub async fn get_preview_image(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+
+    let row = sqlx::query("SELECT image_stored_name FROM link_previews WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&db.0)
+        .await?;
+    let stored: Option<String> = row.ok_or(ApiError::NotFound)?.get("image_stored_name");
+    let stored = stored.ok_or(ApiError::NotFound)?;
+    let p = Path::new(&cfg.uploads_dir).join("previews").join(&stored);
+
+    if !p.exists() {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/jpeg")
+        .insert_header((
+            actix_web::http::header::CACHE_CONTROL,
+            "public, max-age=604800",
+        ))
+        .body(std::fs::read(p).map_err(|_| ApiError::Internal)?))
+}
@@ -1,4 +1,6 @@
-use crate::{auth::AuthUser, db::Db, errors::ApiError, ws::server::Broadcast};
+use crate::{
+    auth::AuthUser, db::Db, errors::ApiError, routes::files::signed_file_url, ws::server::Broadcast,
+};
 use actix_web::{HttpResponse, web};
 use chrono::Utc;
 use serde::Deserialize;
@@ -11,6 +13,7 @@ pub struct ListQuery {
 }
 
 pub async fn list_messages(
+    cfg: web::Data<crate::config::Config>,
     db: web::Data<Db>,
     user: AuthUser,
     path: web::Path<String>,
@@ -40,7 +43,7 @@ pub async fn list_messages(
         let ts: chrono::DateTime<chrono::Utc> =
             ref_row.map(|r| r.get("created_at")).unwrap_or(Utc::now());
         sqlx::query(
-            "SELECT m.id, m.user_id, m.content, m.file_id, m.created_at, m.edited_at, f.original_name, f.size_bytes
+            "SELECT m.id, m.user_id, m.content, m.file_id, m.created_at, m.edited_at, m.voice_note, f.original_name, f.size_bytes, f.width, f.height, f.blurhash, f.duration_ms, f.waveform
              FROM messages m
              LEFT JOIN files f ON f.id = m.file_id
              WHERE m.channel_id = ? AND m.deleted_at IS NULL AND m.created_at < ?
@@ -49,7 +52,7 @@ pub async fn list_messages(
             .bind(&channel_id).bind(ts).bind(limit).fetch_all(&db.0).await?
     } else {
         sqlx::query(
-            "SELECT m.id, m.user_id, m.content, m.file_id, m.created_at, m.edited_at, f.original_name, f.size_bytes
+            "SELECT m.id, m.user_id, m.content, m.file_id, m.created_at, m.edited_at, m.voice_note, f.original_name, f.size_bytes, f.width, f.height, f.blurhash, f.duration_ms, f.waveform
              FROM messages m
              LEFT JOIN files f ON f.id = m.file_id
              WHERE m.channel_id = ? AND m.deleted_at IS NULL
@@ -108,6 +111,20 @@ pub async fn list_messages(
         std::collections::HashMap::new()
     };
 
+    // Batch-fetch poll state, including the caller's own votes
+    let mut polls_map =
+        crate::routes::polls::load_polls(&db, &msg_ids, Some(&user.user_id)).await?;
+
+    // Batch-fetch cached link previews
+    let mut embeds_map = crate::unfurl::load_embeds(&db, &msg_ids).await?;
+
+    // Batch-fetch attachment thumbnails
+    let file_ids: Vec<String> = rows
+        .iter()
+        .filter_map(|r| r.get::<Option<String>, _>("file_id"))
+        .collect();
+    let mut thumbnails_map = crate::thumbnails::load_thumbnails(&cfg, &db, &file_ids).await?;
+
     let msgs: Vec<_> = rows
         .into_iter()
         .map(|r| {
@@ -116,7 +133,7 @@ pub async fn list_messages(
             let original_name: Option<String> = r.get("original_name");
             let size_bytes: Option<i64> = r.get("size_bytes");
             let file_url = match (file_id.as_deref(), original_name.as_deref()) {
-                (Some(fid), Some(name)) => Some(format!("/files/{}/{}", fid, name)),
+                (Some(fid), Some(name)) => Some(signed_file_url(&cfg, fid, name)),
                 _ => None,
             };
 
@@ -144,9 +161,18 @@ pub async fn list_messages(
                 "file_url": file_url,
                 "filename": original_name,
                 "file_size": size_bytes,
+                "width": r.get::<Option<i64>,_>("width"),
+                "height": r.get::<Option<i64>,_>("height"),
+                "blurhash": r.get::<Option<String>,_>("blurhash"),
+                "thumbnails": file_id.and_then(|fid| thumbnails_map.remove(&fid)).unwrap_or_default(),
+                "duration_ms": r.get::<Option<i64>,_>("duration_ms"),
+                "waveform": r.get::<Option<Vec<u8>>,_>("waveform"),
+                "voice_note": r.get::<bool,_>("voice_note"),
                 "created_at": r.get::<chrono::DateTime<chrono::Utc>,_>("created_at"),
                 "edited_at": r.get::<Option<chrono::DateTime<chrono::Utc>>,_>("edited_at"),
                 "reactions": reactions,
+                "poll": polls_map.remove(&id),
+                "embeds": embeds_map.remove(&id).unwrap_or_default(),
             })
         })
         .collect();
@@ -158,20 +184,57 @@ pub async fn list_messages(
 pub struct PostMessageReq {
     pub content: Option<String>,
     pub file_id: Option<String>,
+    pub poll: Option<crate::routes::polls::CreatePollReq>,
+    /// Post `file_id` as a voice note; it must be audio and the message can't have text
+    #[serde(default)]
+    pub voice_note: bool,
 }
 
 pub async fn post_message(
+    cfg: web::Data<crate::config::Config>,
     db: web::Data<Db>,
     chat: web::Data<actix::Addr<crate::ws::server::ChatServer>>,
+    fetcher: web::Data<crate::unfurl::LinkFetcher>,
     user: AuthUser,
     path: web::Path<String>,
     body: web::Json<PostMessageReq>,
 ) -> Result<HttpResponse, ApiError> {
     let channel_id = path.into_inner();
+    let id = create_message(
+        &cfg,
+        &db,
+        &chat,
+        &fetcher,
+        &user.user_id,
+        &channel_id,
+        body.into_inner(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "id": id })))
+}
+
+/// Insert a message on behalf of `user_id` and fan it out to the channel and its members.
+/// Shared by `post_message` and the scheduler so both paths enforce the same checks and
+/// get link previews.
+pub async fn create_message(
+    cfg: &crate::config::Config,
+    db: &Db,
+    chat: &actix::Addr<crate::ws::server::ChatServer>,
+    fetcher: &crate::unfurl::LinkFetcher,
+    user_id: &str,
+    channel_id: &str,
+    msg: PostMessageReq,
+) -> Result<String, ApiError> {
+    let PostMessageReq {
+        content,
+        file_id,
+        poll,
+        voice_note,
+    } = msg;
     let m =
         sqlx::query("SELECT can_write FROM channel_members WHERE channel_id = ? AND user_id = ?")
-            .bind(&channel_id)
-            .bind(&user.user_id)
+            .bind(channel_id)
+            .bind(user_id)
             .fetch_optional(&db.0)
             .await?;
     let m = m.ok_or(ApiError::Forbidden)?;
@@ -179,84 +242,153 @@ pub async fn post_message(
         return Err(ApiError::Forbidden);
     }
 
-    if body
-        .content
+    if content
         .as_deref()
         .map(|s| s.trim().is_empty())
         .unwrap_or(true)
-        && body.file_id.is_none()
+        && file_id.is_none()
+        && poll.is_none()
     {
         return Err(ApiError::BadRequest(
-            "message must have content or file".into(),
+            "message must have content, file or poll".into(),
+        ));
+    }
+    if let Some(p) = &poll {
+        crate::routes::polls::validate_poll(p)?;
+    }
+    if voice_note
+        && (file_id.is_none()
+            || poll.is_some()
+            || content.as_deref().is_some_and(|s| !s.trim().is_empty()))
+    {
+        return Err(ApiError::BadRequest(
+            "a voice note must have a file and nothing else".into(),
         ));
     }
 
-    // Resolve original filename for broadcast (if a file is attached)
-    let (file_url, filename, file_size) = if let Some(fid) = &body.file_id {
-        let row = sqlx::query("SELECT original_name, size_bytes FROM files WHERE id = ?")
-            .bind(fid)
-            .fetch_optional(&db.0)
-            .await?;
+    // Resolve original filename for broadcast (if a file is attached). Attaching grants
+    // channel members access to the file, so the author must be able to access it first.
+    let mut file_info = serde_json::json!({
+        "file_url": null,
+        "filename": null,
+        "file_size": null,
+        "width": null,
+        "height": null,
+        "blurhash": null,
+        "thumbnails": [],
+        "duration_ms": null,
+        "waveform": null,
+    });
+    if let Some(fid) = &file_id {
+        if !crate::routes::files::can_access_file(db, Some(user_id), fid).await? {
+            return Err(ApiError::BadRequest("invalid file_id".into()));
+        }
+        let row = sqlx::query(
+            "SELECT original_name, size_bytes, width, height, blurhash, duration_ms, waveform FROM files WHERE id = ?",
+        )
+        .bind(fid)
+        .fetch_optional(&db.0)
+        .await?;
         if let Some(r) = row {
+            let duration_ms: Option<i64> = r.get("duration_ms");
+            if voice_note && duration_ms.is_none() {
+                return Err(ApiError::BadRequest("voice note file is not audio".into()));
+            }
             let original: String = r.get("original_name");
-            let size: i64 = r.get("size_bytes");
-            (
-                Some(format!("/files/{}/{}", fid, original)),
-                Some(original),
-                Some(size),
-            )
-        } else {
-            (None, None, None)
+            let thumbnails = crate::thumbnails::load_thumbnails(cfg, db, std::slice::from_ref(fid))
+                .await?
+                .remove(fid)
+                .unwrap_or_default();
+            file_info = serde_json::json!({
+                "file_url": signed_file_url(cfg, fid, &original),
+                "filename": original,
+                "file_size": r.get::<i64, _>("size_bytes"),
+                "width": r.get::<Option<i64>, _>("width"),
+                "height": r.get::<Option<i64>, _>("height"),
+                "blurhash": r.get::<Option<String>, _>("blurhash"),
+                "thumbnails": thumbnails,
+                "duration_ms": duration_ms,
+                "waveform": r.get::<Option<Vec<u8>>, _>("waveform"),
+            });
         }
-    } else {
-        (None, None, None)
-    };
+    }
 
     let id = uuid::Uuid::new_v4().to_string();
     let now = Utc::now();
-    sqlx::query("INSERT INTO messages(id, channel_id, user_id, content, file_id, created_at) VALUES (?, ?, ?, ?, ?, ?)")
-        .bind(&id).bind(&channel_id).bind(&user.user_id).bind(&body.content).bind(&body.file_id).bind(now)
-        .execute(&db.0).await?;
+    let mut tx = db.0.begin().await?;
+    sqlx::query("INSERT INTO messages(id, channel_id, user_id, content, file_id, voice_note, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)")
+        .bind(&id).bind(channel_id).bind(user_id).bind(&content).bind(&file_id).bind(voice_note).bind(now)
+        .execute(&mut *tx).await?;
+    if let Some(p) = &poll {
+        crate::routes::polls::insert_poll(&mut tx, &id, p, now).await?;
+    }
+    tx.commit().await?;
+
+    let poll = if poll.is_some() {
+        crate::routes::polls::load_polls(db, std::slice::from_ref(&id), None)
+            .await?
+            .remove(&id)
+    } else {
+        None
+    };
 
     // Broadcast to WS
-    // Broadcast to WS
-    let payload = serde_json::json!({
+    let mut payload = serde_json::json!({
         "type": "message_created",
         "id": id,
         "channel_id": channel_id,
-        "user_id": user.user_id,
-        "content": body.content,
-        "file_url": file_url,
-        "filename": filename,
-        "file_size": file_size,
+        "user_id": user_id,
+        "content": content,
+        "poll": poll,
+        "voice_note": voice_note,
         "created_at": now,
-    })
-    .to_string();
+    });
+    if let (Some(obj), serde_json::Value::Object(file)) = (payload.as_object_mut(), file_info) {
+        obj.extend(file);
+    }
+    let payload = payload.to_string();
     chat.do_send(Broadcast {
-        channel_id: channel_id.clone(),
+        channel_id: channel_id.to_string(),
         payload: payload.clone(),
     });
 
     // Notify other members (skipping those in the channel room)
     let member_rows = sqlx::query("SELECT user_id FROM channel_members WHERE channel_id = ?")
-        .bind(&channel_id)
+        .bind(channel_id)
         .fetch_all(&db.0)
         .await?;
     let member_ids: Vec<String> = member_rows
         .into_iter()
         .map(|r| r.get("user_id"))
-        .filter(|uid| uid != &user.user_id)
+        .filter(|uid: &String| uid != user_id)
         .collect();
 
     if !member_ids.is_empty() {
         chat.do_send(crate::ws::server::NotifyUsers {
             user_ids: member_ids,
             payload,
-            skip_channel: Some(channel_id.clone()),
+            skip_channel: Some(channel_id.to_string()),
         });
     }
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({ "id": id })))
+    // Link previews are fetched in the background and pushed as message_embeds_updated
+    let urls = match content.as_deref() {
+        Some(text) if cfg.link_previews => crate::unfurl::extract_urls(text),
+        _ => Vec::new(),
+    };
+    if !urls.is_empty() {
+        crate::unfurl::spawn_unfurl(
+            fetcher.clone(),
+            db.clone(),
+            chat.clone(),
+            cfg.uploads_dir.clone(),
+            id.clone(),
+            channel_id.to_string(),
+            urls,
+        );
+    }
+
+    Ok(id)
 }
 
 #[derive(Deserialize)]
@@ -278,7 +410,7 @@ pub async fn edit_message(
     let id = path.into_inner();
     // Load message with channel and author
     let row =
-        sqlx::query("SELECT channel_id, user_id FROM messages WHERE id = ? AND deleted_at IS NULL")
+        sqlx::query("SELECT channel_id, user_id, voice_note FROM messages WHERE id = ? AND deleted_at IS NULL")
             .bind(&id)
             .fetch_optional(&db.0)
             .await?;
@@ -298,6 +430,9 @@ pub async fn edit_message(
     if user.user_id != author_id && !can_manage {
         return Err(ApiError::Forbidden);
     }
+    if row.get::<bool, _>("voice_note") {
+        return Err(ApiError::BadRequest("voice notes can't be edited".into()));
+    }
 
     let now = Utc::now();
     sqlx::query("UPDATE messages SET content = ?, edited_at = ? WHERE id = ?")
@@ -6,8 +6,14 @@ pub mod emojis;
 pub mod files;
 pub mod health;
 pub mod invites;
+pub mod link_previews;
 pub mod messages;
+pub mod playlists;
+pub mod polls;
 pub mod presence;
 pub mod reactions;
+pub mod saved;
+pub mod scheduled;
 pub mod shareplay;
+pub mod tus;
 pub mod users;
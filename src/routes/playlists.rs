@@ -0,0 +1,317 @@
+use crate::{
+    auth::AuthUser,
+    db::Db,
+    errors::ApiError,
+    media_resolver::PlaylistEntry,
+    ws::server::{ChatServer, GetSharePlayEntries},
+};
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Serialize)]
+struct PlaylistResp {
+    id: String,
+    name: String,
+    owner_id: String,
+    channel_id: Option<String>,
+    shared: bool,
+    entry_count: i64,
+    duration_seconds: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<Vec<PlaylistEntry>>,
+}
+
+const PLAYLIST_COLUMNS: &str = "p.id, p.name, p.owner_id, p.channel_id, p.shared, p.created_at, p.updated_at,
+    (SELECT COUNT(*) FROM shareplay_playlist_entries e WHERE e.playlist_id = p.id) AS entry_count,
+    (SELECT COALESCE(SUM(duration_seconds), 0) FROM shareplay_playlist_entries e WHERE e.playlist_id = p.id) AS duration_seconds";
+
+/// Playlists a user may see and load: their own, and those shared with a channel they can read.
+const VISIBLE: &str = "(p.owner_id = ? OR (p.shared = 1 AND EXISTS (
+    SELECT 1 FROM channel_members cm WHERE cm.channel_id = p.channel_id AND cm.user_id = ? AND cm.can_read = 1)))";
+
+fn playlist_from_row(r: &sqlx::sqlite::SqliteRow) -> PlaylistResp {
+    PlaylistResp {
+        id: r.get("id"),
+        name: r.get("name"),
+        owner_id: r.get("owner_id"),
+        channel_id: r.get("channel_id"),
+        shared: r.get::<i64, _>("shared") != 0,
+        entry_count: r.get("entry_count"),
+        duration_seconds: r.get("duration_seconds"),
+        created_at: r.get("created_at"),
+        updated_at: r.get("updated_at"),
+        entries: None,
+    }
+}
+
+async fn fetch_visible(db: &Db, user_id: &str, id: &str) -> Result<PlaylistResp, ApiError> {
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM shareplay_playlists p WHERE p.id = ? AND {}",
+        PLAYLIST_COLUMNS, VISIBLE
+    ))
+    .bind(id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    row.map(|r| playlist_from_row(&r)).ok_or(ApiError::NotFound)
+}
+
+async fn fetch_entries(db: &Db, id: &str) -> Result<Vec<PlaylistEntry>, ApiError> {
+    let rows = sqlx::query(
+        "SELECT url, title, duration_seconds, video FROM shareplay_playlist_entries
+         WHERE playlist_id = ? ORDER BY position ASC",
+    )
+    .bind(id)
+    .fetch_all(&db.0)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| PlaylistEntry {
+            url: r.get("url"),
+            title: r.get("title"),
+            duration_seconds: r.get::<i64, _>("duration_seconds") as u64,
+            video: r.get::<i64, _>("video") != 0,
+        })
+        .collect())
+}
+
+/// The songs of a playlist the user may load, for the `load_playlist` SharePlay action.
+pub async fn loadable_entries(
+    db: &Db,
+    user_id: &str,
+    id: &str,
+) -> Result<Vec<PlaylistEntry>, ApiError> {
+    fetch_visible(db, user_id, id).await?;
+    fetch_entries(db, id).await
+}
+
+fn validate_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "name must be 1 to {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(name.to_string())
+}
+
+fn validate_entries(entries: &[PlaylistEntry]) -> Result<(), ApiError> {
+    if entries.len() > MAX_ENTRIES {
+        return Err(ApiError::BadRequest(format!(
+            "a playlist holds at most {} songs",
+            MAX_ENTRIES
+        )));
+    }
+    if entries.iter().any(|e| e.url.trim().is_empty()) {
+        return Err(ApiError::BadRequest("every song needs a url".into()));
+    }
+    Ok(())
+}
+
+async fn can_read(db: &Db, user_id: &str, channel_id: &str) -> Result<bool, ApiError> {
+    let v = sqlx::query_scalar::<_, i64>(
+        "SELECT can_read FROM channel_members WHERE channel_id = ? AND user_id = ?",
+    )
+    .bind(channel_id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    Ok(v.is_some_and(|v| v != 0))
+}
+
+/// Replace a playlist's songs.
+async fn write_entries(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    id: &str,
+    entries: &[PlaylistEntry],
+) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM shareplay_playlist_entries WHERE playlist_id = ?")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    for (position, entry) in entries.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO shareplay_playlist_entries (playlist_id, position, url, title, duration_seconds, video)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(position as i64)
+        .bind(entry.url.trim())
+        .bind(&entry.title)
+        .bind(entry.duration_seconds as i64)
+        .bind(entry.video)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ListPlaylistsQuery {
+    pub channel_id: Option<String>, // only own playlists and those shared with this channel
+}
+
+pub async fn list_playlists(
+    db: web::Data<Db>,
+    user: AuthUser,
+    q: web::Query<ListPlaylistsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM shareplay_playlists p
+         WHERE {} AND (? IS NULL OR p.owner_id = ? OR p.channel_id = ?)
+         ORDER BY p.updated_at DESC LIMIT 200",
+        PLAYLIST_COLUMNS, VISIBLE
+    ))
+    .bind(&user.user_id)
+    .bind(&user.user_id)
+    .bind(&q.channel_id)
+    .bind(&user.user_id)
+    .bind(&q.channel_id)
+    .fetch_all(&db.0)
+    .await?;
+    let playlists: Vec<PlaylistResp> = rows.iter().map(playlist_from_row).collect();
+    Ok(HttpResponse::Ok().json(playlists))
+}
+
+pub async fn get_playlist(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let mut playlist = fetch_visible(&db, &user.user_id, &id).await?;
+    playlist.entries = Some(fetch_entries(&db, &id).await?);
+    Ok(HttpResponse::Ok().json(playlist))
+}
+
+#[derive(Deserialize)]
+pub struct CreatePlaylistReq {
+    pub name: String,
+    pub channel_id: String, // whose queue to save, and who to share it with
+    pub shared: Option<bool>,
+    pub entries: Option<Vec<PlaylistEntry>>, // instead of the channel's queue
+}
+
+pub async fn create_playlist(
+    db: web::Data<Db>,
+    user: AuthUser,
+    body: web::Json<CreatePlaylistReq>,
+    app_state: web::Data<actix::Addr<ChatServer>>,
+) -> Result<HttpResponse, ApiError> {
+    let name = validate_name(&body.name)?;
+    if !can_read(&db, &user.user_id, &body.channel_id).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let entries = match &body.entries {
+        Some(entries) => entries.clone(),
+        None => app_state
+            .send(GetSharePlayEntries {
+                channel_id: body.channel_id.clone(),
+            })
+            .await
+            .map_err(|_| ApiError::Internal)?
+            .map_err(|_| ApiError::Internal)?,
+    };
+    if entries.is_empty() {
+        return Err(ApiError::BadRequest("the queue is empty".into()));
+    }
+    validate_entries(&entries)?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let mut tx = db.0.begin().await?;
+    sqlx::query(
+        "INSERT INTO shareplay_playlists (id, owner_id, name, channel_id, shared, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&id)
+    .bind(&user.user_id)
+    .bind(&name)
+    .bind(&body.channel_id)
+    .bind(body.shared.unwrap_or(false))
+    .bind(now)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    write_entries(&mut tx, &id, &entries).await?;
+    tx.commit().await?;
+
+    let mut playlist = fetch_visible(&db, &user.user_id, &id).await?;
+    playlist.entries = Some(entries);
+    Ok(HttpResponse::Ok().json(playlist))
+}
+
+#[derive(Deserialize)]
+pub struct EditPlaylistReq {
+    pub name: Option<String>,
+    pub shared: Option<bool>,
+    pub entries: Option<Vec<PlaylistEntry>>, // replaces the songs, e.g. reordered
+}
+
+pub async fn edit_playlist(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+    body: web::Json<EditPlaylistReq>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let playlist = fetch_visible(&db, &user.user_id, &id).await?;
+    if playlist.owner_id != user.user_id {
+        return Err(ApiError::Forbidden);
+    }
+    let name = body.name.as_deref().map(validate_name).transpose()?;
+    if body.shared == Some(true) && playlist.channel_id.is_none() {
+        return Err(ApiError::Conflict(
+            "the playlist's channel was deleted".into(),
+        ));
+    }
+    if let Some(entries) = &body.entries {
+        validate_entries(entries)?;
+    }
+
+    let mut tx = db.0.begin().await?;
+    sqlx::query(
+        "UPDATE shareplay_playlists SET name = COALESCE(?, name), shared = COALESCE(?, shared), updated_at = ?
+         WHERE id = ?",
+    )
+    .bind(&name)
+    .bind(body.shared)
+    .bind(Utc::now())
+    .bind(&id)
+    .execute(&mut *tx)
+    .await?;
+    if let Some(entries) = &body.entries {
+        write_entries(&mut tx, &id, entries).await?;
+    }
+    tx.commit().await?;
+
+    let mut playlist = fetch_visible(&db, &user.user_id, &id).await?;
+    playlist.entries = Some(fetch_entries(&db, &id).await?);
+    Ok(HttpResponse::Ok().json(playlist))
+}
+
+pub async fn delete_playlist(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let playlist = fetch_visible(&db, &user.user_id, &id).await?;
+    if playlist.owner_id != user.user_id {
+        return Err(ApiError::Forbidden);
+    }
+    sqlx::query("DELETE FROM shareplay_playlists WHERE id = ?")
+        .bind(&id)
+        .execute(&db.0)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
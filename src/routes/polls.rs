@@ -0,0 +1,374 @@
+use crate::{auth::AuthUser, db::Db, errors::ApiError, ws::server::Broadcast};
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::Row;
+use std::collections::HashMap;
+
+const MAX_OPTIONS: usize = 10;
+const MAX_QUESTION_LEN: usize = 300;
+const MAX_OPTION_LEN: usize = 200;
+
+/// Poll attached to a new message via `POST /api/channels/{id}/messages`.
+#[derive(Deserialize, Clone)]
+pub struct CreatePollReq {
+    pub question: String,
+    pub options: Vec<String>,
+    pub multi_choice: Option<bool>,
+    pub anonymous: Option<bool>,
+    pub closes_at: Option<DateTime<Utc>>,
+}
+
+pub fn validate_poll(poll: &CreatePollReq) -> Result<(), ApiError> {
+    let question = poll.question.trim();
+    if question.is_empty() || question.chars().count() > MAX_QUESTION_LEN {
+        return Err(ApiError::BadRequest("invalid poll question".into()));
+    }
+    if poll.options.len() < 2 || poll.options.len() > MAX_OPTIONS {
+        return Err(ApiError::BadRequest(format!(
+            "poll needs between 2 and {} options",
+            MAX_OPTIONS
+        )));
+    }
+    if poll
+        .options
+        .iter()
+        .any(|o| o.trim().is_empty() || o.trim().chars().count() > MAX_OPTION_LEN)
+    {
+        return Err(ApiError::BadRequest("invalid poll option".into()));
+    }
+    if poll.closes_at.map(|t| t <= Utc::now()).unwrap_or(false) {
+        return Err(ApiError::BadRequest("closes_at must be in the future".into()));
+    }
+    Ok(())
+}
+
+/// Insert the poll rows for `message_id`. Runs inside the message insert transaction.
+pub async fn insert_poll(
+    conn: &mut sqlx::SqliteConnection,
+    message_id: &str,
+    poll: &CreatePollReq,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "INSERT INTO polls (message_id, question, multi_choice, anonymous, closes_at, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(message_id)
+    .bind(poll.question.trim())
+    .bind(poll.multi_choice.unwrap_or(false))
+    .bind(poll.anonymous.unwrap_or(false))
+    .bind(poll.closes_at)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+
+    for (position, label) in poll.options.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO poll_options (id, message_id, position, label) VALUES (?, ?, ?, ?)",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(message_id)
+        .bind(position as i64)
+        .bind(label.trim())
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Batch-load poll state for the given messages, keyed by message_id.
+/// Voter ids are omitted for anonymous polls. When `viewer_id` is set, the
+/// viewer's own choices are included as `my_votes`.
+pub async fn load_polls(
+    db: &Db,
+    message_ids: &[String],
+    viewer_id: Option<&str>,
+) -> Result<HashMap<String, serde_json::Value>, ApiError> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let placeholders: String = message_ids
+        .iter()
+        .map(|_| "?")
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let query_str = format!(
+        "SELECT message_id, question, multi_choice, anonymous, closes_at FROM polls WHERE message_id IN ({})",
+        placeholders
+    );
+    let mut q = sqlx::query(&query_str);
+    for mid in message_ids {
+        q = q.bind(mid);
+    }
+    let poll_rows = q.fetch_all(&db.0).await?;
+    if poll_rows.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let query_str = format!(
+        "SELECT id, message_id, label FROM poll_options WHERE message_id IN ({}) ORDER BY position ASC",
+        placeholders
+    );
+    let mut q = sqlx::query(&query_str);
+    for mid in message_ids {
+        q = q.bind(mid);
+    }
+    let option_rows = q.fetch_all(&db.0).await?;
+
+    let query_str = format!(
+        "SELECT option_id, user_id FROM poll_votes WHERE message_id IN ({}) ORDER BY created_at ASC",
+        placeholders
+    );
+    let mut q = sqlx::query(&query_str);
+    for mid in message_ids {
+        q = q.bind(mid);
+    }
+    let vote_rows = q.fetch_all(&db.0).await?;
+
+    // option_id -> [user_ids]
+    let mut votes: HashMap<String, Vec<String>> = HashMap::new();
+    for r in vote_rows {
+        votes
+            .entry(r.get("option_id"))
+            .or_default()
+            .push(r.get("user_id"));
+    }
+
+    // message_id -> [(option_id, label)]
+    let mut options: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for r in option_rows {
+        options
+            .entry(r.get("message_id"))
+            .or_default()
+            .push((r.get("id"), r.get("label")));
+    }
+
+    let now = Utc::now();
+    let mut out = HashMap::new();
+    for r in poll_rows {
+        let mid: String = r.get("message_id");
+        let anonymous = r.get::<i64, _>("anonymous") != 0;
+        let closes_at: Option<DateTime<Utc>> = r.get("closes_at");
+
+        let mut voters_total: std::collections::HashSet<&str> = std::collections::HashSet::new();
+        let mut my_votes: Vec<&str> = Vec::new();
+        let opts: Vec<serde_json::Value> = options
+            .get(&mid)
+            .map(|opts| {
+                opts.iter()
+                    .map(|(oid, label)| {
+                        let users = votes.get(oid).map(|v| v.as_slice()).unwrap_or(&[]);
+                        for u in users {
+                            voters_total.insert(u);
+                            if Some(u.as_str()) == viewer_id {
+                                my_votes.push(oid);
+                            }
+                        }
+                        let mut opt = serde_json::json!({
+                            "id": oid,
+                            "label": label,
+                            "count": users.len(),
+                        });
+                        if !anonymous {
+                            opt["voters"] = serde_json::json!(users);
+                        }
+                        opt
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut poll = serde_json::json!({
+            "question": r.get::<String,_>("question"),
+            "multi_choice": r.get::<i64,_>("multi_choice") != 0,
+            "anonymous": anonymous,
+            "closes_at": closes_at,
+            "closed": closes_at.map(|t| t <= now).unwrap_or(false),
+            "options": opts,
+            "total_voters": voters_total.len(),
+        });
+        if viewer_id.is_some() {
+            poll["my_votes"] = serde_json::json!(my_votes);
+        }
+        out.insert(mid, poll);
+    }
+    Ok(out)
+}
+
+async fn build_poll(
+    db: &Db,
+    message_id: &str,
+    viewer_id: Option<&str>,
+) -> Result<serde_json::Value, ApiError> {
+    load_polls(db, &[message_id.to_string()], viewer_id)
+        .await?
+        .remove(message_id)
+        .ok_or(ApiError::NotFound)
+}
+
+fn broadcast_poll_updated(
+    chat: &actix::Addr<crate::ws::server::ChatServer>,
+    channel_id: &str,
+    message_id: &str,
+    poll: serde_json::Value,
+) {
+    let payload = serde_json::json!({
+        "type": "poll_updated",
+        "message_id": message_id,
+        "channel_id": channel_id,
+        "poll": poll,
+    })
+    .to_string();
+    chat.do_send(Broadcast {
+        channel_id: channel_id.to_string(),
+        payload,
+    });
+}
+
+#[derive(Deserialize)]
+pub struct VoteReq {
+    pub option_ids: Vec<String>, // replaces the caller's votes; empty retracts them
+}
+
+pub async fn vote(
+    db: web::Data<Db>,
+    chat: web::Data<actix::Addr<crate::ws::server::ChatServer>>,
+    user: AuthUser,
+    path: web::Path<String>,
+    body: web::Json<VoteReq>,
+) -> Result<HttpResponse, ApiError> {
+    let message_id = path.into_inner();
+
+    // Look up message and poll
+    let row = sqlx::query(
+        "SELECT m.channel_id, p.multi_choice, p.closes_at FROM messages m
+         INNER JOIN polls p ON p.message_id = m.id
+         WHERE m.id = ? AND m.deleted_at IS NULL",
+    )
+    .bind(&message_id)
+    .fetch_optional(&db.0)
+    .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+    let channel_id: String = row.get("channel_id");
+    let multi_choice = row.get::<i64, _>("multi_choice") != 0;
+    let closes_at: Option<DateTime<Utc>> = row.get("closes_at");
+
+    // Check user can read the channel
+    let perm =
+        sqlx::query("SELECT can_read FROM channel_members WHERE channel_id = ? AND user_id = ?")
+            .bind(&channel_id)
+            .bind(&user.user_id)
+            .fetch_optional(&db.0)
+            .await?;
+    let perm = perm.ok_or(ApiError::Forbidden)?;
+    if perm.get::<i64, _>("can_read") == 0 {
+        return Err(ApiError::Forbidden);
+    }
+
+    if closes_at.map(|t| t <= Utc::now()).unwrap_or(false) {
+        return Err(ApiError::Conflict("poll is closed".into()));
+    }
+
+    let mut option_ids = body.option_ids.clone();
+    option_ids.sort();
+    option_ids.dedup();
+    if !multi_choice && option_ids.len() > 1 {
+        return Err(ApiError::BadRequest("poll allows a single choice".into()));
+    }
+
+    let valid: std::collections::HashSet<String> =
+        sqlx::query("SELECT id FROM poll_options WHERE message_id = ?")
+            .bind(&message_id)
+            .fetch_all(&db.0)
+            .await?
+            .into_iter()
+            .map(|r| r.get("id"))
+            .collect();
+    if option_ids.iter().any(|o| !valid.contains(o)) {
+        return Err(ApiError::BadRequest("unknown poll option".into()));
+    }
+
+    let now = Utc::now();
+    let mut tx = db.0.begin().await?;
+    sqlx::query("DELETE FROM poll_votes WHERE message_id = ? AND user_id = ?")
+        .bind(&message_id)
+        .bind(&user.user_id)
+        .execute(&mut *tx)
+        .await?;
+    for oid in &option_ids {
+        sqlx::query(
+            "INSERT INTO poll_votes (message_id, option_id, user_id, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&message_id)
+        .bind(oid)
+        .bind(&user.user_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    broadcast_poll_updated(
+        &chat,
+        &channel_id,
+        &message_id,
+        build_poll(&db, &message_id, None).await?,
+    );
+
+    let poll = build_poll(&db, &message_id, Some(&user.user_id)).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "poll": poll })))
+}
+
+pub async fn close_poll(
+    db: web::Data<Db>,
+    chat: web::Data<actix::Addr<crate::ws::server::ChatServer>>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let message_id = path.into_inner();
+    let row = sqlx::query(
+        "SELECT m.channel_id, m.user_id FROM messages m
+         INNER JOIN polls p ON p.message_id = m.id
+         WHERE m.id = ? AND m.deleted_at IS NULL",
+    )
+    .bind(&message_id)
+    .fetch_optional(&db.0)
+    .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+    let channel_id: String = row.get("channel_id");
+    let author_id: String = row.get("user_id");
+
+    // Permission: author or channel manager
+    let can_manage =
+        sqlx::query("SELECT can_manage FROM channel_members WHERE channel_id = ? AND user_id = ?")
+            .bind(&channel_id)
+            .bind(&user.user_id)
+            .fetch_optional(&db.0)
+            .await?
+            .map(|r| r.get::<i64, _>("can_manage") != 0)
+            .unwrap_or(false);
+    if user.user_id != author_id && !can_manage {
+        return Err(ApiError::Forbidden);
+    }
+
+    let now = Utc::now();
+    sqlx::query(
+        "UPDATE polls SET closes_at = ? WHERE message_id = ? AND (closes_at IS NULL OR closes_at > ?)",
+    )
+    .bind(now)
+    .bind(&message_id)
+    .bind(now)
+    .execute(&db.0)
+    .await?;
+
+    broadcast_poll_updated(
+        &chat,
+        &channel_id,
+        &message_id,
+        build_poll(&db, &message_id, None).await?,
+    );
+
+    let poll = build_poll(&db, &message_id, Some(&user.user_id)).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "poll": poll })))
+}
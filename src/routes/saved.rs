@@ -0,0 +1,196 @@
+use crate::{
+    auth::AuthUser, config::Config, db::Db, errors::ApiError, routes::files::signed_file_url,
+};
+use actix_web::{HttpResponse, web};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::Row;
+
+#[derive(Deserialize)]
+pub struct SavedListQuery {
+    pub before: Option<String>, // message_id of the last bookmark on the previous page
+    pub limit: Option<i64>,
+}
+
+// List the current user's bookmarks, newest first. Bookmarks pointing at deleted
+// messages, deleted channels, or channels the user can no longer read are skipped.
+pub async fn list_saved(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: AuthUser,
+    q: web::Query<SavedListQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = q.limit.unwrap_or(50).clamp(1, 200);
+    let rows = if let Some(before_id) = &q.before {
+        // Get saved_at of the reference bookmark for pagination
+        let ref_row =
+            sqlx::query("SELECT created_at FROM saved_messages WHERE user_id = ? AND message_id = ?")
+                .bind(&user.user_id)
+                .bind(before_id)
+                .fetch_optional(&db.0)
+                .await?;
+        let ts: chrono::DateTime<chrono::Utc> =
+            ref_row.map(|r| r.get("created_at")).unwrap_or(Utc::now());
+        sqlx::query(
+            "SELECT s.note, s.remind_at, s.created_at AS saved_at,
+                    m.id, m.channel_id, m.user_id, m.content, m.file_id, m.created_at, m.edited_at,
+                    f.original_name, f.size_bytes
+             FROM saved_messages s
+             INNER JOIN messages m ON m.id = s.message_id
+             INNER JOIN channels c ON c.id = m.channel_id
+             INNER JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = s.user_id
+             LEFT JOIN files f ON f.id = m.file_id
+             WHERE s.user_id = ? AND m.deleted_at IS NULL AND c.deleted_at IS NULL AND cm.can_read = 1
+               AND s.created_at < ?
+             ORDER BY s.created_at DESC LIMIT ?",
+        )
+        .bind(&user.user_id)
+        .bind(ts)
+        .bind(limit)
+        .fetch_all(&db.0)
+        .await?
+    } else {
+        sqlx::query(
+            "SELECT s.note, s.remind_at, s.created_at AS saved_at,
+                    m.id, m.channel_id, m.user_id, m.content, m.file_id, m.created_at, m.edited_at,
+                    f.original_name, f.size_bytes
+             FROM saved_messages s
+             INNER JOIN messages m ON m.id = s.message_id
+             INNER JOIN channels c ON c.id = m.channel_id
+             INNER JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = s.user_id
+             LEFT JOIN files f ON f.id = m.file_id
+             WHERE s.user_id = ? AND m.deleted_at IS NULL AND c.deleted_at IS NULL AND cm.can_read = 1
+             ORDER BY s.created_at DESC LIMIT ?",
+        )
+        .bind(&user.user_id)
+        .bind(limit)
+        .fetch_all(&db.0)
+        .await?
+    };
+
+    let saved: Vec<_> = rows
+        .into_iter()
+        .map(|r| {
+            let file_id: Option<String> = r.get("file_id");
+            let original_name: Option<String> = r.get("original_name");
+            let size_bytes: Option<i64> = r.get("size_bytes");
+            let file_url = match (file_id.as_deref(), original_name.as_deref()) {
+                (Some(fid), Some(name)) => Some(signed_file_url(&cfg, fid, name)),
+                _ => None,
+            };
+
+            serde_json::json!({
+                "note": r.get::<Option<String>,_>("note"),
+                "remind_at": r.get::<Option<chrono::DateTime<chrono::Utc>>,_>("remind_at"),
+                "saved_at": r.get::<chrono::DateTime<chrono::Utc>,_>("saved_at"),
+                "message": {
+                    "id": r.get::<String,_>("id"),
+                    "channel_id": r.get::<String,_>("channel_id"),
+                    "user_id": r.get::<String,_>("user_id"),
+                    "content": r.get::<Option<String>,_>("content"),
+                    "file_url": file_url,
+                    "filename": original_name,
+                    "file_size": size_bytes,
+                    "created_at": r.get::<chrono::DateTime<chrono::Utc>,_>("created_at"),
+                    "edited_at": r.get::<Option<chrono::DateTime<chrono::Utc>>,_>("edited_at"),
+                },
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(saved))
+}
+
+#[derive(Deserialize)]
+pub struct SaveMessageReq {
+    pub note: Option<String>,
+    pub remind_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Bookmark a message, or update the note/reminder of an existing bookmark.
+pub async fn save_message(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+    body: web::Json<SaveMessageReq>,
+) -> Result<HttpResponse, ApiError> {
+    let message_id = path.into_inner();
+
+    // Look up message to get channel_id
+    let row = sqlx::query("SELECT channel_id FROM messages WHERE id = ? AND deleted_at IS NULL")
+        .bind(&message_id)
+        .fetch_optional(&db.0)
+        .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+    let channel_id: String = row.get("channel_id");
+
+    // Check user can read the channel
+    let perm =
+        sqlx::query("SELECT can_read FROM channel_members WHERE channel_id = ? AND user_id = ?")
+            .bind(&channel_id)
+            .bind(&user.user_id)
+            .fetch_optional(&db.0)
+            .await?;
+    let perm = perm.ok_or(ApiError::Forbidden)?;
+    if perm.get::<i64, _>("can_read") == 0 {
+        return Err(ApiError::Forbidden);
+    }
+
+    let note = body
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty());
+    if note.map(|n| n.chars().count() > 1000).unwrap_or(false) {
+        return Err(ApiError::BadRequest("note too long".into()));
+    }
+
+    let now = Utc::now();
+    sqlx::query(
+        "INSERT INTO saved_messages (user_id, message_id, note, remind_at, created_at)
+         VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(user_id, message_id) DO UPDATE SET note = excluded.note, remind_at = excluded.remind_at",
+    )
+    .bind(&user.user_id)
+    .bind(&message_id)
+    .bind(note)
+    .bind(body.remind_at)
+    .bind(now)
+    .execute(&db.0)
+    .await?;
+
+    // Reminders that are already in the past are kept on the bookmark but not scheduled
+    crate::scheduler::set_message_reminder(
+        &db,
+        &user.user_id,
+        &message_id,
+        body.remind_at.filter(|t| *t > now),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message_id": message_id,
+        "note": note,
+        "remind_at": body.remind_at,
+    })))
+}
+
+pub async fn unsave_message(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let message_id = path.into_inner();
+    let res = sqlx::query("DELETE FROM saved_messages WHERE user_id = ? AND message_id = ?")
+        .bind(&user.user_id)
+        .bind(&message_id)
+        .execute(&db.0)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    crate::scheduler::set_message_reminder(&db, &user.user_id, &message_id, None).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,301 @@
+use crate::{
+    auth::AuthUser,
+    db::Db,
+    errors::ApiError,
+    scheduler::{KIND_MESSAGE, KIND_REMINDER},
+};
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+
+#[derive(Serialize)]
+struct ScheduledJobResp {
+    id: String,
+    kind: String,
+    run_at: DateTime<Utc>,
+    channel_id: Option<String>,
+    message_id: Option<String>,
+    content: Option<String>,
+    file_id: Option<String>,
+    status: String,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    completed_at: Option<DateTime<Utc>>,
+}
+
+const JOB_COLUMNS: &str = "id, kind, run_at, channel_id, message_id, content, file_id, status, error, created_at, completed_at";
+
+fn job_from_row(r: &sqlx::sqlite::SqliteRow) -> ScheduledJobResp {
+    ScheduledJobResp {
+        id: r.get("id"),
+        kind: r.get("kind"),
+        run_at: r.get("run_at"),
+        channel_id: r.get("channel_id"),
+        message_id: r.get("message_id"),
+        content: r.get("content"),
+        file_id: r.get("file_id"),
+        status: r.get("status"),
+        error: r.get("error"),
+        created_at: r.get("created_at"),
+        completed_at: r.get("completed_at"),
+    }
+}
+
+async fn fetch_job(db: &Db, user_id: &str, id: &str) -> Result<ScheduledJobResp, ApiError> {
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM scheduled_jobs WHERE id = ? AND user_id = ?",
+        JOB_COLUMNS
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    row.map(|r| job_from_row(&r)).ok_or(ApiError::NotFound)
+}
+
+fn validate_run_at(run_at: DateTime<Utc>) -> Result<(), ApiError> {
+    if run_at <= Utc::now() {
+        return Err(ApiError::BadRequest("run_at must be in the future".into()));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ListScheduledQuery {
+    pub status: Option<String>, // defaults to 'pending'
+}
+
+pub async fn list_scheduled(
+    db: web::Data<Db>,
+    user: AuthUser,
+    q: web::Query<ListScheduledQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let status = q.status.as_deref().unwrap_or("pending");
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM scheduled_jobs WHERE user_id = ? AND status = ? ORDER BY run_at ASC LIMIT 200",
+        JOB_COLUMNS
+    ))
+    .bind(&user.user_id)
+    .bind(status)
+    .fetch_all(&db.0)
+    .await?;
+    let jobs: Vec<ScheduledJobResp> = rows.iter().map(job_from_row).collect();
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+#[derive(Deserialize)]
+pub struct CreateScheduledReq {
+    pub kind: String, // 'reminder' | 'message'
+    pub run_at: DateTime<Utc>,
+    pub message_id: Option<String>, // for reminders
+    pub channel_id: Option<String>, // for scheduled messages
+    pub content: Option<String>,
+    pub file_id: Option<String>,
+}
+
+pub async fn create_scheduled(
+    db: web::Data<Db>,
+    user: AuthUser,
+    body: web::Json<CreateScheduledReq>,
+) -> Result<HttpResponse, ApiError> {
+    validate_run_at(body.run_at)?;
+
+    let (channel_id, message_id, content, file_id) = match body.kind.as_str() {
+        KIND_REMINDER => {
+            let message_id = body
+                .message_id
+                .clone()
+                .ok_or(ApiError::BadRequest("message_id required".into()))?;
+            let row = sqlx::query(
+                "SELECT cm.can_read FROM messages m
+                 INNER JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = ?
+                 WHERE m.id = ? AND m.deleted_at IS NULL",
+            )
+            .bind(&user.user_id)
+            .bind(&message_id)
+            .fetch_optional(&db.0)
+            .await?;
+            let row = row.ok_or(ApiError::NotFound)?;
+            if row.get::<i64, _>("can_read") == 0 {
+                return Err(ApiError::Forbidden);
+            }
+            (None, Some(message_id), None, None)
+        }
+        KIND_MESSAGE => {
+            let channel_id = body
+                .channel_id
+                .clone()
+                .ok_or(ApiError::BadRequest("channel_id required".into()))?;
+            let m = sqlx::query(
+                "SELECT can_write FROM channel_members WHERE channel_id = ? AND user_id = ?",
+            )
+            .bind(&channel_id)
+            .bind(&user.user_id)
+            .fetch_optional(&db.0)
+            .await?;
+            let m = m.ok_or(ApiError::Forbidden)?;
+            if m.get::<i64, _>("can_write") == 0 {
+                return Err(ApiError::Forbidden);
+            }
+            if body
+                .content
+                .as_deref()
+                .map(|s| s.trim().is_empty())
+                .unwrap_or(true)
+                && body.file_id.is_none()
+            {
+                return Err(ApiError::BadRequest(
+                    "message must have content or file".into(),
+                ));
+            }
+            (
+                Some(channel_id),
+                None,
+                body.content.clone(),
+                body.file_id.clone(),
+            )
+        }
+        _ => return Err(ApiError::BadRequest("invalid kind".into())),
+    };
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    sqlx::query(
+        "INSERT INTO scheduled_jobs (id, user_id, kind, run_at, channel_id, message_id, content, file_id, status, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, 'pending', ?, ?)",
+    )
+    .bind(&id)
+    .bind(&user.user_id)
+    .bind(&body.kind)
+    .bind(body.run_at)
+    .bind(&channel_id)
+    .bind(&message_id)
+    .bind(&content)
+    .bind(&file_id)
+    .bind(now)
+    .bind(now)
+    .execute(&db.0)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(fetch_job(&db, &user.user_id, &id).await?))
+}
+
+#[derive(Deserialize)]
+pub struct EditScheduledReq {
+    pub run_at: Option<DateTime<Utc>>,
+    pub content: Option<String>,
+}
+
+pub async fn edit_scheduled(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+    body: web::Json<EditScheduledReq>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let job = fetch_job(&db, &user.user_id, &id).await?;
+    if job.status != "pending" {
+        return Err(ApiError::Conflict("job is no longer pending".into()));
+    }
+    if let Some(run_at) = body.run_at {
+        validate_run_at(run_at)?;
+    }
+    if let Some(content) = &body.content {
+        if job.kind != KIND_MESSAGE {
+            return Err(ApiError::BadRequest("only scheduled messages have content".into()));
+        }
+        if content.trim().is_empty() && job.file_id.is_none() {
+            return Err(ApiError::BadRequest(
+                "message must have content or file".into(),
+            ));
+        }
+    }
+
+    // Guard on status so an edit can't land after the scheduler claimed the job
+    let res = sqlx::query(
+        "UPDATE scheduled_jobs SET run_at = COALESCE(?, run_at), content = COALESCE(?, content), updated_at = ?
+         WHERE id = ? AND user_id = ? AND status = 'pending'",
+    )
+    .bind(body.run_at)
+    .bind(&body.content)
+    .bind(Utc::now())
+    .bind(&id)
+    .bind(&user.user_id)
+    .execute(&db.0)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(ApiError::Conflict("job is no longer pending".into()));
+    }
+
+    Ok(HttpResponse::Ok().json(fetch_job(&db, &user.user_id, &id).await?))
+}
+
+pub async fn cancel_scheduled(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let res = sqlx::query(
+        "UPDATE scheduled_jobs SET status = 'cancelled', updated_at = ?
+         WHERE id = ? AND user_id = ? AND status = 'pending'",
+    )
+    .bind(Utc::now())
+    .bind(&id)
+    .bind(&user.user_id)
+    .execute(&db.0)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        // Distinguish "not yours / missing" from "already ran"
+        fetch_job(&db, &user.user_id, &id).await?;
+        return Err(ApiError::Conflict("job is no longer pending".into()));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Reminders that fired and haven't been dismissed, as the `reminder` events sent
+/// when they fired (oldest first).
+pub async fn list_reminders(
+    db: web::Data<Db>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let rows = sqlx::query(
+        "SELECT notification FROM scheduled_jobs
+         WHERE user_id = ? AND kind = 'reminder' AND status = 'done'
+           AND notification IS NOT NULL AND dismissed_at IS NULL
+         ORDER BY completed_at ASC LIMIT 200",
+    )
+    .bind(&user.user_id)
+    .fetch_all(&db.0)
+    .await?;
+    let reminders: Vec<serde_json::Value> = rows
+        .iter()
+        .filter_map(|r| serde_json::from_str(&r.get::<String, _>("notification")).ok())
+        .collect();
+    Ok(HttpResponse::Ok().json(reminders))
+}
+
+pub async fn dismiss_reminder(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let res = sqlx::query(
+        "UPDATE scheduled_jobs SET dismissed_at = COALESCE(dismissed_at, ?), updated_at = ?
+         WHERE id = ? AND user_id = ? AND kind = 'reminder' AND notification IS NOT NULL",
+    )
+    .bind(Utc::now())
+    .bind(Utc::now())
+    .bind(&id)
+    .bind(&user.user_id)
+    .execute(&db.0)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
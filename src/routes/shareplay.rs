@@ -1,121 +1,536 @@
+use crate::auth::AuthUser;
+use crate::config::Config;
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::routes::files::{file_signature, verify_file_signature};
+use crate::shareplay_cache::SharePlayCache;
 use crate::ws::server::{
-    ChatServer, GetSharePlaySongId, GetSharePlaySongPath, GetSharePlayThumbnailPath,
+    ChatServer, GetSharePlayItem, GetSharePlayQueueFiles, GetSharePlaySongId, SharePlayItemFiles,
 };
-use actix_files::NamedFile;
-use actix_web::{Error, HttpResponse, http::header, web};
-use std::path::PathBuf;
+use actix_files::HttpRange;
+use actix_web::http::header::{self, EntityTag};
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::Row;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const TRANSCODE_TIMEOUT: Duration = Duration::from_secs(300);
+const READ_CHUNK: usize = 64 * 1024;
+
+/// One transcode at a time; a long mix keeps ffmpeg busy for a while, so requests
+/// that come in meanwhile get the original rather than waiting.
+static TRANSCODE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+async fn can_read(db: &Db, user_id: &str, channel_id: &str) -> Result<bool, ApiError> {
+    let v = sqlx::query_scalar::<_, i64>(
+        "SELECT can_read FROM channel_members WHERE channel_id = ? AND user_id = ?",
+    )
+    .bind(channel_id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    Ok(v.is_some_and(|v| v != 0))
+}
+
+/// Queue item ids and file ids live in different namespaces of the same signature scheme.
+fn signature_key(item_id: &str) -> String {
+    format!("shareplay/{}", item_id)
+}
+
+/// Path to a queue item's audio that loads without an Authorization header until it
+/// expires, for `<audio>` elements. Only hand these out to members of the channel.
+fn signed_song_url(cfg: &Config, item_id: &str) -> String {
+    let (expires, sig) = file_signature(cfg, &signature_key(item_id));
+    format!(
+        "/api/shareplay/song/{}?expires={}&sig={}",
+        item_id, expires, sig
+    )
+}
+
+/// Like `signed_song_url`, for the item's thumbnail. The signature covers both.
+fn signed_thumbnail_url(cfg: &Config, item_id: &str) -> String {
+    let (expires, sig) = file_signature(cfg, &signature_key(item_id));
+    format!(
+        "/api/shareplay/thumbnail/{}?expires={}&sig={}",
+        item_id, expires, sig
+    )
+}
+
+fn item_urls(cfg: &Config, item: &SharePlayItemFiles) -> serde_json::Value {
+    serde_json::json!({
+        "song_url": item.file_path.as_ref().map(|_| signed_song_url(cfg, &item.item_id)),
+        "thumbnail_url": item.thumbnail_path.as_ref().map(|_| signed_thumbnail_url(cfg, &item.item_id)),
+    })
+}
 
 pub async fn get_current_track(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: AuthUser,
     path: web::Path<String>,
     app_state: web::Data<actix::Addr<ChatServer>>,
-) -> Result<HttpResponse, Error> {
+) -> Result<HttpResponse, ApiError> {
     let channel_id = path.into_inner();
-    log::info!(
-        "get_current_track (ID lookup) called for channel_id={}",
-        channel_id
-    );
+    if !can_read(&db, &user.user_id, &channel_id).await? {
+        return Err(ApiError::Forbidden);
+    }
 
-    let res = app_state
+    let song_id = app_state
         .send(GetSharePlaySongId {
             channel_id: channel_id.clone(),
         })
-        .await;
-
-    match res {
-        Ok(Ok(Some(song_id))) => Ok(HttpResponse::Ok()
-            .insert_header((
-                header::CACHE_CONTROL,
-                "no-store, no-cache, must-revalidate, proxy-revalidate, max-age=0",
-            ))
-            .insert_header((header::PRAGMA, "no-cache"))
-            .insert_header((header::EXPIRES, "0"))
-            .json(serde_json::json!({ "song_id": song_id }))),
-        Ok(Ok(None)) => {
-            log::warn!("No current track ID for channel {}", channel_id);
-            Err(actix_web::error::ErrorNotFound(
-                "No track currently playing",
-            ))
-        }
-        _ => {
-            log::error!("Error getting song ID for channel {}", channel_id);
-            Err(actix_web::error::ErrorInternalServerError(
-                "Internal server error",
-            ))
+        .await
+        .map_err(|_| ApiError::Internal)?
+        .map_err(|_| ApiError::Internal)?;
+    let Some(song_id) = song_id else {
+        log::warn!("No current track ID for channel {}", channel_id);
+        return Err(ApiError::NotFound);
+    };
+    let item = app_state
+        .send(GetSharePlayItem {
+            item_id: song_id.clone(),
+        })
+        .await
+        .map_err(|_| ApiError::Internal)?
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::NotFound)?;
+
+    let mut body = item_urls(&cfg, &item);
+    body["song_id"] = serde_json::Value::String(song_id);
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            header::CACHE_CONTROL,
+            "no-store, no-cache, must-revalidate, proxy-revalidate, max-age=0",
+        ))
+        .insert_header((header::PRAGMA, "no-cache"))
+        .insert_header((header::EXPIRES, "0"))
+        .json(body))
+}
+
+// GET /api/shareplay/{channel_id}/urls - signed song and thumbnail URLs for the whole queue
+pub async fn get_queue_urls(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+    app_state: web::Data<actix::Addr<ChatServer>>,
+) -> Result<HttpResponse, ApiError> {
+    let channel_id = path.into_inner();
+    if !can_read(&db, &user.user_id, &channel_id).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let items = app_state
+        .send(GetSharePlayQueueFiles { channel_id })
+        .await
+        .map_err(|_| ApiError::Internal)?
+        .map_err(|_| ApiError::Internal)?;
+    let urls: serde_json::Map<String, serde_json::Value> = items
+        .iter()
+        .map(|item| (item.item_id.clone(), item_urls(&cfg, item)))
+        .collect();
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(urls))
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub before: Option<String>, // id of the last entry on the previous page
+    pub limit: Option<i64>,
+}
+
+// GET /api/shareplay/{channel_id}/history - tracks played in the channel, newest first
+pub async fn get_history(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+    q: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let channel_id = path.into_inner();
+    if !can_read(&db, &user.user_id, &channel_id).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let limit = q.limit.unwrap_or(50).clamp(1, 200);
+    let before: Option<DateTime<Utc>> = match &q.before {
+        Some(id) => sqlx::query_scalar(
+            "SELECT played_at FROM shareplay_history WHERE id = ? AND channel_id = ?",
+        )
+        .bind(id)
+        .bind(&channel_id)
+        .fetch_optional(&db.0)
+        .await?,
+        None => None,
+    };
+    // Keyset on (played_at, id), so tracks logged at the same instant aren't skipped
+    // between pages
+    let rows = sqlx::query(
+        "SELECT h.id, h.url, h.title, h.duration_seconds, h.video, h.added_by, u.username, h.played_at
+         FROM shareplay_history h
+         LEFT JOIN users u ON u.id = h.added_by
+         WHERE h.channel_id = ? AND (? IS NULL OR (h.played_at, h.id) < (?, ?))
+         ORDER BY h.played_at DESC, h.id DESC LIMIT ?",
+    )
+    .bind(&channel_id)
+    .bind(before)
+    .bind(before)
+    .bind(before.and(q.before.as_deref()))
+    .bind(limit)
+    .fetch_all(&db.0)
+    .await?;
+
+    let entries: Vec<_> = rows
+        .into_iter()
+        .map(|r| {
+            serde_json::json!({
+                "id": r.get::<String, _>("id"),
+                "url": r.get::<String, _>("url"),
+                "title": r.get::<String, _>("title"),
+                "duration_seconds": r.get::<i64, _>("duration_seconds"),
+                "video": r.get::<i64, _>("video") != 0,
+                "added_by": r.get::<Option<String>, _>("added_by"),
+                "added_by_username": r.get::<Option<String>, _>("username"),
+                "played_at": r.get::<DateTime<Utc>, _>("played_at"),
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[derive(Deserialize)]
+pub struct HistoryStatsQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+// GET /api/shareplay/{channel_id}/history/stats - most played tracks and top contributors
+pub async fn get_history_stats(
+    db: web::Data<Db>,
+    user: AuthUser,
+    path: web::Path<String>,
+    q: web::Query<HistoryStatsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let channel_id = path.into_inner();
+    if !can_read(&db, &user.user_id, &channel_id).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let limit = q.limit.unwrap_or(10).clamp(1, 100);
+    // Everything, unless only a recent listening session is wanted
+    let since = q.since;
+
+    let totals = sqlx::query(
+        "SELECT COUNT(*) AS plays, COALESCE(SUM(duration_seconds), 0) AS seconds
+         FROM shareplay_history WHERE channel_id = ? AND (? IS NULL OR played_at >= ?)",
+    )
+    .bind(&channel_id)
+    .bind(since)
+    .bind(since)
+    .fetch_one(&db.0)
+    .await?;
+
+    let most_played = sqlx::query(
+        "SELECT url, COUNT(*) AS plays, MAX(played_at) AS last_played_at,
+                (SELECT title FROM shareplay_history t WHERE t.channel_id = h.channel_id AND t.url = h.url
+                 ORDER BY played_at DESC LIMIT 1) AS title
+         FROM shareplay_history h
+         WHERE channel_id = ? AND (? IS NULL OR played_at >= ?)
+         GROUP BY url ORDER BY plays DESC, last_played_at DESC LIMIT ?",
+    )
+    .bind(&channel_id)
+    .bind(since)
+    .bind(since)
+    .bind(limit)
+    .fetch_all(&db.0)
+    .await?
+    .into_iter()
+    .map(|r| {
+        serde_json::json!({
+            "url": r.get::<String, _>("url"),
+            "title": r.get::<String, _>("title"),
+            "plays": r.get::<i64, _>("plays"),
+            "last_played_at": r.get::<DateTime<Utc>, _>("last_played_at"),
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let top_contributors = sqlx::query(
+        "SELECT h.added_by, u.username, COUNT(*) AS plays, SUM(h.duration_seconds) AS seconds
+         FROM shareplay_history h
+         INNER JOIN users u ON u.id = h.added_by
+         WHERE h.channel_id = ? AND (? IS NULL OR h.played_at >= ?)
+         GROUP BY h.added_by ORDER BY plays DESC, seconds DESC LIMIT ?",
+    )
+    .bind(&channel_id)
+    .bind(since)
+    .bind(since)
+    .bind(limit)
+    .fetch_all(&db.0)
+    .await?
+    .into_iter()
+    .map(|r| {
+        serde_json::json!({
+            "user_id": r.get::<String, _>("added_by"),
+            "username": r.get::<String, _>("username"),
+            "plays": r.get::<i64, _>("plays"),
+            "seconds": r.get::<i64, _>("seconds"),
+        })
+    })
+    .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "plays": totals.get::<i64, _>("plays"),
+        "seconds": totals.get::<i64, _>("seconds"),
+        "most_played": most_played,
+        "top_contributors": top_contributors,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct SharePlayFileQuery {
+    pub expires: Option<i64>,
+    pub sig: Option<String>,
+    /// `low` for the transcoded Opus copy; ignored for videos
+    pub quality: Option<String>,
+}
+
+/// Look up a queue item and check that the caller may fetch its files: either a
+/// valid signature from `signed_song_url` or a bearer token for a channel member.
+async fn authorize_item(
+    cfg: &Config,
+    db: &Db,
+    app_state: &actix::Addr<ChatServer>,
+    user: Option<&AuthUser>,
+    item_id: &str,
+    q: &SharePlayFileQuery,
+) -> Result<SharePlayItemFiles, ApiError> {
+    let item = app_state
+        .send(GetSharePlayItem {
+            item_id: item_id.to_string(),
+        })
+        .await
+        .map_err(|_| ApiError::Internal)?
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::NotFound)?;
+
+    let signed = match (q.expires, q.sig.as_deref()) {
+        (Some(expires), Some(sig)) => {
+            verify_file_signature(cfg, &signature_key(item_id), expires, sig)
         }
+        _ => false,
+    };
+    if signed {
+        return Ok(item);
+    }
+    match user {
+        Some(u) if can_read(db, &u.user_id, &item.channel_id).await? => Ok(item),
+        Some(_) => Err(ApiError::Forbidden),
+        None => Err(ApiError::Unauthorized),
     }
 }
 
 pub async fn get_song_by_id(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: Option<AuthUser>,
+    req: HttpRequest,
     path: web::Path<String>,
+    q: web::Query<SharePlayFileQuery>,
     app_state: web::Data<actix::Addr<ChatServer>>,
-) -> Result<NamedFile, Error> {
+) -> Result<HttpResponse, ApiError> {
     let song_id = path.into_inner();
-    log::info!("get_song_by_id called for song_id={}", song_id);
+    let item = authorize_item(&cfg, &db, &app_state, user.as_ref(), &song_id, &q).await?;
+    let Some(file_path) = item.file_path else {
+        log::warn!("Song ID {} not downloaded yet", song_id);
+        return Err(ApiError::NotFound);
+    };
+    let path = PathBuf::from(&file_path);
 
-    let res = app_state
-        .send(GetSharePlaySongPath {
-            song_id: song_id.clone(),
-        })
-        .await;
-
-    match res {
-        Ok(Ok(Some(file_path))) => {
-            let path = PathBuf::from(&file_path);
-            if !path.exists() {
-                log::error!("File does not exist: {}", file_path);
-                return Err(actix_web::error::ErrorNotFound(format!(
-                    "File not found: {}",
-                    file_path
-                )));
+    if q.quality.as_deref() == Some("low") && cfg.shareplay_low_bitrate_kbps > 0 && !item.video {
+        match low_bitrate_copy(&path, cfg.shareplay_low_bitrate_kbps).await {
+            Ok((low, made)) => {
+                // The copy lives as long as the cached song, so it shares its budget
+                if made
+                    && let Some(cache) = req.app_data::<web::Data<SharePlayCache>>()
+                    && cache.is_cached_path(&file_path)
+                {
+                    cache.count_derived_file(&file_path);
+                }
+                let etag = EntityTag::new_strong(format!("{}-low", song_id));
+                return serve_item_file(&req, &low, &etag, "audio/ogg").await;
             }
-            Ok(NamedFile::open(path)?)
-        }
-        Ok(Ok(None)) => {
-            log::warn!("Song ID {} not found", song_id);
-            Err(actix_web::error::ErrorNotFound("Song not found"))
-        }
-        _ => {
-            log::error!("Error getting song path for ID {}", song_id);
-            Err(actix_web::error::ErrorInternalServerError(
-                "Internal server error",
-            ))
+            // Better the full-quality file than no music
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                log::debug!("Serving song {} at full quality: {}", song_id, e)
+            }
+            Err(e) => log::warn!("Transcoding song {} failed: {}", song_id, e),
         }
     }
+
+    let content_type = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(actix_files::file_extension_to_mime)
+        .unwrap_or(actix_web::mime::APPLICATION_OCTET_STREAM)
+        .to_string();
+    serve_item_file(&req, &path, &EntityTag::new_strong(song_id), &content_type).await
 }
+
 pub async fn get_thumbnail_by_id(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: Option<AuthUser>,
+    req: HttpRequest,
     path: web::Path<String>,
+    q: web::Query<SharePlayFileQuery>,
     app_state: web::Data<actix::Addr<ChatServer>>,
-) -> Result<NamedFile, Error> {
+) -> Result<HttpResponse, ApiError> {
     let item_id = path.into_inner();
-    log::info!("get_thumbnail_by_id called for item_id={}", item_id);
+    let item = authorize_item(&cfg, &db, &app_state, user.as_ref(), &item_id, &q).await?;
+    let Some(thumbnail_path) = item.thumbnail_path else {
+        log::warn!("Thumbnail for item ID {} not found", item_id);
+        return Err(ApiError::NotFound);
+    };
+    let etag = EntityTag::new_strong(format!("{}-thumb", item_id));
+    serve_item_file(&req, Path::new(&thumbnail_path), &etag, "image/jpeg").await
+}
+
+/// Serve a queue item's file with an ETag tied to the item id, which never changes
+/// content. Handles `If-None-Match`, `Range` and `If-Range`, so seeking in a long mix
+/// only fetches what's needed.
+async fn serve_item_file(
+    req: &HttpRequest,
+    path: &Path,
+    etag: &EntityTag,
+    content_type: &str,
+) -> Result<HttpResponse, ApiError> {
+    let mut file = tokio::fs::File::open(path).await.map_err(|e| {
+        log::error!("SharePlay file {} unreadable: {}", path.display(), e);
+        ApiError::NotFound
+    })?;
+    let size = file.metadata().await.map_err(|_| ApiError::Internal)?.len();
 
-    let res = app_state
-        .send(GetSharePlayThumbnailPath {
-            item_id: item_id.clone(),
+    let mut resp = HttpResponse::Ok();
+    resp.insert_header((header::ETAG, etag.to_string()))
+        .insert_header((header::CACHE_CONTROL, "private, max-age=86400"))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+
+    if let Some(inm) = req.headers().get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok())
+        && inm.split(',').any(|t| {
+            let t = t.trim();
+            t == "*" || t.parse::<EntityTag>().is_ok_and(|t| t.weak_eq(etag))
         })
-        .await;
-
-    match res {
-        Ok(Ok(Some(file_path))) => {
-            let path = PathBuf::from(&file_path);
-            if !path.exists() {
-                log::error!("Thumbnail file does not exist: {}", file_path);
-                return Err(actix_web::error::ErrorNotFound(format!(
-                    "Thumbnail file not found: {}",
-                    file_path
-                )));
+    {
+        return Ok(resp.status(actix_web::http::StatusCode::NOT_MODIFIED).finish());
+    }
+
+    // A stale If-Range (another ETag, or a date we don't track) means "send it all"
+    let if_range_ok = match req.headers().get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(v) => v.parse::<EntityTag>().is_ok_and(|t| t.strong_eq(etag)),
+        None => true,
+    };
+    let range = match req.headers().get(header::RANGE) {
+        Some(r) if if_range_ok => {
+            let parsed = r.to_str().ok().and_then(|r| HttpRange::parse(r, size).ok());
+            match parsed.as_ref().and_then(|r| r.first()) {
+                Some(r) => Some((r.start, r.length)),
+                None => {
+                    return Ok(resp
+                        .status(actix_web::http::StatusCode::RANGE_NOT_SATISFIABLE)
+                        .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                        .finish());
+                }
             }
-            Ok(NamedFile::open(path)?)
         }
-        Ok(Ok(None)) => {
-            log::warn!("Thumbnail for item ID {} not found", item_id);
-            Err(actix_web::error::ErrorNotFound("Thumbnail not found"))
+        _ => None,
+    };
+
+    let (offset, length) = match range {
+        Some((start, length)) => {
+            resp.status(actix_web::http::StatusCode::PARTIAL_CONTENT)
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, start + length - 1, size),
+                ));
+            (start, length)
         }
-        _ => {
-            log::error!("Error getting thumbnail path for ID {}", item_id);
-            Err(actix_web::error::ErrorInternalServerError(
-                "Internal server error",
-            ))
+        None => (0, size),
+    };
+    resp.insert_header((header::CONTENT_TYPE, content_type.to_string()));
+    if req.method() == actix_web::http::Method::HEAD {
+        return Ok(resp.no_chunking(length).finish());
+    }
+
+    file.seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    let reader = file.take(length);
+    let body = futures_util::stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let mut buf = vec![0u8; READ_CHUNK];
+        match reader.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(web::Bytes::from(buf)), Some(reader)))
+            }
+            Err(e) => Some((Err(e), None)),
         }
+    });
+    Ok(resp.no_chunking(length).streaming(body))
+}
+
+/// The song re-encoded as low-bitrate Opus, made with ffmpeg on first request and
+/// kept next to the original (so it goes when the song is removed or evicted). Also
+/// returns whether it was just made; fails with `WouldBlock` while another transcode
+/// is running.
+async fn low_bitrate_copy(source: &Path, kbps: u32) -> std::io::Result<(PathBuf, bool)> {
+    let stem = source
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dest = source.with_file_name(format!("{}_low.opus", stem));
+    if tokio::fs::try_exists(&dest).await? {
+        return Ok((dest, false));
+    }
+    let Ok(_guard) = TRANSCODE_LOCK.try_lock() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            "another song is being transcoded",
+        ));
+    };
+    // Someone else may have made it before we got the lock
+    if tokio::fs::try_exists(&dest).await? {
+        return Ok((dest, false));
+    }
+
+    let part = source.with_file_name(format!("{}_low.opus.part", stem));
+    let output = tokio::time::timeout(
+        TRANSCODE_TIMEOUT,
+        tokio::process::Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+            .arg(source)
+            .args(["-vn", "-c:a", "libopus", "-b:a", &format!("{}k", kbps), "-f", "ogg"])
+            .arg(&part)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await;
+    let result = match output {
+        Ok(Ok(out)) if out.status.success() => tokio::fs::rename(&part, &dest).await,
+        Ok(Ok(out)) => Err(std::io::Error::other(
+            String::from_utf8_lossy(&out.stderr).trim().to_string(),
+        )),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "ffmpeg timed out",
+        )),
+    };
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&part).await;
     }
+    result.map(|_| (dest, true))
 }
@@ -0,0 +1,490 @@
+use crate::{
+    auth::AuthUser,
+    config::Config,
+    db::Db,
+    errors::ApiError,
+    routes::files::{CompletedUpload, store_completed_upload},
+    storage::Storage,
+};
+use actix_web::{HttpRequest, HttpResponse, web};
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt as _;
+use sanitize_filename::sanitize;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// tus 1.0 resumable uploads: https://tus.io/protocols/resumable-upload
+// Supported extensions: creation, expiration, termination.
+//
+// The upload id doubles as the file id, so once the last byte arrives the upload
+// turns into a normal `files` row that `post_message` accepts like any other.
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Unfinished uploads live in `uploads_dir` under this prefix. Unlike multipart
+/// temp files they must survive restarts, so they are only removed on expiry.
+const TUS_PREFIX: &str = ".tus-";
+/// Same sniffing window as multipart uploads.
+const SNIFF_LEN: usize = 8192;
+
+/// Upload ids that currently have a PATCH in flight. tus clients retry on timeouts,
+/// so two requests appending to the same file must not interleave.
+#[derive(Default)]
+pub struct TusLocks(Mutex<HashSet<String>>);
+
+struct TusLockGuard<'a> {
+    locks: &'a TusLocks,
+    id: String,
+}
+
+impl TusLocks {
+    fn try_lock(&self, id: &str) -> Option<TusLockGuard<'_>> {
+        let mut set = self.0.lock().unwrap();
+        if !set.insert(id.to_string()) {
+            return None;
+        }
+        Some(TusLockGuard {
+            locks: self,
+            id: id.to_string(),
+        })
+    }
+}
+
+impl Drop for TusLockGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn partial_path(cfg: &Config, id: &str) -> PathBuf {
+    Path::new(&cfg.uploads_dir).join(format!("{}{}", TUS_PREFIX, id))
+}
+
+fn http_date(t: DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// All requests except OPTIONS must declare the protocol version.
+fn check_version(req: &HttpRequest) -> Option<HttpResponse> {
+    let ok = req
+        .headers()
+        .get("Tus-Resumable")
+        .and_then(|h| h.to_str().ok())
+        .map(|v| v == TUS_VERSION)
+        .unwrap_or(false);
+    if ok {
+        None
+    } else {
+        Some(
+            HttpResponse::PreconditionFailed()
+                .insert_header(("Tus-Version", TUS_VERSION))
+                .finish(),
+        )
+    }
+}
+
+fn header_u64(req: &HttpRequest, name: &str) -> Result<u64, ApiError> {
+    req.headers()
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .ok_or_else(|| ApiError::BadRequest(format!("missing or invalid {}", name)))
+}
+
+/// Parse `Upload-Metadata`: comma-separated `key base64value` pairs (value optional).
+fn parse_metadata(raw: &str) -> Vec<(String, String)> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next()?.trim();
+            if key.is_empty() {
+                return None;
+            }
+            let value = match parts.next() {
+                Some(v) => base64::engine::general_purpose::STANDARD
+                    .decode(v.trim())
+                    .ok()
+                    .and_then(|b| String::from_utf8(b).ok())?,
+                None => String::new(),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+// OPTIONS /api/files/tus — protocol discovery, no auth.
+pub async fn options(cfg: web::Data<Config>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", cfg.max_upload_size.to_string()))
+        .finish()
+}
+
+// POST /api/files/tus — create an upload. Headers: Upload-Length, Upload-Metadata (filename).
+pub async fn create_upload(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    storage: web::Data<dyn Storage>,
+    user: AuthUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(resp) = check_version(&req) {
+        return Ok(resp);
+    }
+    if req.headers().contains_key("Upload-Defer-Length") {
+        return Err(ApiError::BadRequest("Upload-Defer-Length is not supported".into()));
+    }
+    let length = header_u64(&req, "Upload-Length")?;
+    if length > cfg.max_upload_size as u64 {
+        return Ok(HttpResponse::PayloadTooLarge()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .finish());
+    }
+    let quota = crate::quotas::storage_quota(&cfg, &db, &user.user_id).await?;
+
+    let metadata = req
+        .headers()
+        .get("Upload-Metadata")
+        .and_then(|h| h.to_str().ok())
+        .map(parse_metadata)
+        .unwrap_or_default();
+    let original = metadata
+        .iter()
+        .find(|(k, _)| k == "filename" || k == "name")
+        .map(|(_, v)| v.clone())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "upload.bin".into());
+    let original_safe = sanitize(&original);
+    // Like `?keep_metadata=true` on multipart uploads; tus keys may have no value
+    let keep_metadata = metadata
+        .iter()
+        .any(|(k, v)| k == "keep_metadata" && v != "false");
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::hours(cfg.upload_expiry_hours);
+    // The whole length counts against the quota from now on
+    let mut tx = db.0.begin().await?;
+    sqlx::query(
+        "INSERT INTO tus_uploads (id, user_id, original_name, upload_length, upload_offset, keep_metadata, expires_at, created_at)
+         VALUES (?, ?, ?, ?, 0, ?, ?, ?)",
+    )
+    .bind(&id)
+    .bind(&user.user_id)
+    .bind(&original_safe)
+    .bind(length as i64)
+    .bind(keep_metadata)
+    .bind(expires_at)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    crate::quotas::recheck_quota(&mut tx, &user.user_id, quota, length as i64).await?;
+    tokio::fs::File::create(partial_path(&cfg, &id))
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    tx.commit().await?;
+
+    // An empty file is complete as soon as it exists
+    if length == 0 {
+        finalize_upload(&cfg, &db, storage.get_ref(), &user.user_id, &id, 0).await?;
+    }
+
+    Ok(HttpResponse::Created()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Location", format!("/api/files/tus/{}", id)))
+        .insert_header(("Upload-Expires", http_date(expires_at)))
+        .json(serde_json::json!({ "file_id": id })))
+}
+
+// HEAD /api/files/tus/{id} — current offset, so the client knows where to resume.
+pub async fn upload_offset(
+    db: web::Data<Db>,
+    user: AuthUser,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(resp) = check_version(&req) {
+        return Ok(resp);
+    }
+    let id = path.into_inner();
+
+    let row = sqlx::query(
+        "SELECT upload_length, upload_offset, expires_at FROM tus_uploads WHERE id = ? AND user_id = ?",
+    )
+    .bind(&id)
+    .bind(&user.user_id)
+    .fetch_optional(&db.0)
+    .await?;
+
+    let mut resp = HttpResponse::Ok();
+    resp.insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Cache-Control", "no-store"));
+    if let Some(r) = row {
+        let expires_at: DateTime<Utc> = r.get("expires_at");
+        if expires_at <= Utc::now() {
+            return Err(ApiError::NotFound);
+        }
+        resp.insert_header(("Upload-Offset", r.get::<i64, _>("upload_offset").to_string()))
+            .insert_header(("Upload-Length", r.get::<i64, _>("upload_length").to_string()))
+            .insert_header(("Upload-Expires", http_date(expires_at)));
+        return Ok(resp.finish());
+    }
+
+    // Already finished: report the full length so the client stops resuming
+    let row = sqlx::query("SELECT size_bytes FROM files WHERE id = ? AND user_id = ?")
+        .bind(&id)
+        .bind(&user.user_id)
+        .fetch_optional(&db.0)
+        .await?;
+    let size: i64 = row.ok_or(ApiError::NotFound)?.get("size_bytes");
+    Ok(resp
+        .insert_header(("Upload-Offset", size.to_string()))
+        .insert_header(("Upload-Length", size.to_string()))
+        .finish())
+}
+
+// PATCH /api/files/tus/{id} — append bytes at Upload-Offset.
+pub async fn append_upload(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    locks: web::Data<TusLocks>,
+    user: AuthUser,
+    req: HttpRequest,
+    path: web::Path<String>,
+    mut body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(resp) = check_version(&req) {
+        return Ok(resp);
+    }
+    let id = path.into_inner();
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("");
+    if content_type != OFFSET_CONTENT_TYPE {
+        return Ok(HttpResponse::UnsupportedMediaType()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .finish());
+    }
+    let client_offset = header_u64(&req, "Upload-Offset")? as i64;
+
+    let _lock = locks
+        .try_lock(&id)
+        .ok_or(ApiError::Conflict("upload is busy".into()))?;
+
+    let row = sqlx::query(
+        "SELECT upload_length, upload_offset, expires_at FROM tus_uploads WHERE id = ? AND user_id = ?",
+    )
+    .bind(&id)
+    .bind(&user.user_id)
+    .fetch_optional(&db.0)
+    .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+    let expires_at: DateTime<Utc> = row.get("expires_at");
+    if expires_at <= Utc::now() {
+        return Err(ApiError::NotFound);
+    }
+    let length: i64 = row.get("upload_length");
+    let offset: i64 = row.get("upload_offset");
+    if client_offset != offset {
+        return Err(ApiError::Conflict("Upload-Offset mismatch".into()));
+    }
+
+    // Drop any bytes past the recorded offset, left by a request that was cut off
+    // before it could save its progress
+    let mut out = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(partial_path(&cfg, &id))
+        .await
+        .map_err(|_| ApiError::NotFound)?;
+    out.set_len(offset as u64)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    out.seek(std::io::SeekFrom::Start(offset as u64))
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    let mut new_offset = offset;
+    let mut read_error = false;
+    loop {
+        let chunk = match body.try_next().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => {
+                // Keep what arrived so the client can resume from there
+                read_error = true;
+                break;
+            }
+        };
+        if new_offset + chunk.len() as i64 > length {
+            return Err(ApiError::BadRequest("upload exceeds Upload-Length".into()));
+        }
+        out.write_all(&chunk).await.map_err(|_| ApiError::Internal)?;
+        new_offset += chunk.len() as i64;
+    }
+    out.sync_all().await.map_err(|_| ApiError::Internal)?;
+    drop(out);
+
+    // Each successful PATCH pushes the expiry out again
+    let expires_at = Utc::now() + chrono::Duration::hours(cfg.upload_expiry_hours);
+    sqlx::query("UPDATE tus_uploads SET upload_offset = ?, expires_at = ? WHERE id = ?")
+        .bind(new_offset)
+        .bind(expires_at)
+        .bind(&id)
+        .execute(&db.0)
+        .await?;
+    if read_error {
+        return Err(ApiError::BadRequest("upload read error".into()));
+    }
+
+    if new_offset == length {
+        // Only needed for the last PATCH, so taken from the app data rather than extracted
+        let storage = req
+            .app_data::<web::Data<dyn Storage>>()
+            .ok_or(ApiError::Internal)?;
+        finalize_upload(&cfg, &db, storage.get_ref(), &user.user_id, &id, length).await?;
+    }
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", new_offset.to_string()))
+        .insert_header(("Upload-Expires", http_date(expires_at)))
+        .finish())
+}
+
+// DELETE /api/files/tus/{id} — abandon an unfinished upload.
+pub async fn terminate_upload(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    locks: web::Data<TusLocks>,
+    user: AuthUser,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(resp) = check_version(&req) {
+        return Ok(resp);
+    }
+    let id = path.into_inner();
+    let _lock = locks
+        .try_lock(&id)
+        .ok_or(ApiError::Conflict("upload is busy".into()))?;
+
+    let res = sqlx::query("DELETE FROM tus_uploads WHERE id = ? AND user_id = ?")
+        .bind(&id)
+        .bind(&user.user_id)
+        .execute(&db.0)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    let _ = tokio::fs::remove_file(partial_path(&cfg, &id)).await;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .finish())
+}
+
+/// All bytes are in: store the upload as a regular file. If that fails (over quota,
+/// rejected by the scanner, a storage error...) the upload is discarded, since with
+/// every byte received the client has nothing left to resume.
+async fn finalize_upload(
+    cfg: &Config,
+    db: &Db,
+    storage: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    size: i64,
+) -> Result<(), ApiError> {
+    let res = store_upload(cfg, db, storage, user_id, id, size).await;
+    if res.is_err() {
+        sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+            .bind(id)
+            .execute(&db.0)
+            .await?;
+        let _ = tokio::fs::remove_file(partial_path(cfg, id)).await;
+    }
+    res
+}
+
+/// Hash and sniff the assembled file, then hand it to the same path multipart
+/// uploads use so it becomes a regular `files` row with this id.
+async fn store_upload(
+    cfg: &Config,
+    db: &Db,
+    storage: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    size: i64,
+) -> Result<(), ApiError> {
+    let row = sqlx::query("SELECT original_name, keep_metadata FROM tus_uploads WHERE id = ?")
+        .bind(id)
+        .fetch_one(&db.0)
+        .await?;
+    let original_safe: String = row.get("original_name");
+
+    let temp_path = partial_path(cfg, id);
+    let mut f = tokio::fs::File::open(&temp_path)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    let mut hasher = Sha256::new();
+    let mut head: Vec<u8> = Vec::with_capacity(SNIFF_LEN);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await.map_err(|_| ApiError::Internal)?;
+        if n == 0 {
+            break;
+        }
+        if head.len() < SNIFF_LEN {
+            let take = (SNIFF_LEN - head.len()).min(n);
+            head.extend_from_slice(&buf[..take]);
+        }
+        hasher.update(&buf[..n]);
+    }
+    drop(f);
+
+    store_completed_upload(
+        cfg,
+        db,
+        storage,
+        user_id,
+        CompletedUpload {
+            id,
+            original_name: &original_safe,
+            temp_path: &temp_path,
+            head: &head,
+            sha256: hex::encode(hasher.finalize()),
+            size,
+            keep_metadata: row.get("keep_metadata"),
+        },
+    )
+    .await?;
+    // `store_completed_upload` removed the `tus_uploads` row along with adding the file
+    Ok(())
+}
+
+/// Remove uploads that were never finished before their expiry.
+pub async fn cleanup_expired_uploads(cfg: &Config, db: &Db) -> Result<u64, ApiError> {
+    let rows = sqlx::query("SELECT id FROM tus_uploads WHERE expires_at <= ?")
+        .bind(Utc::now())
+        .fetch_all(&db.0)
+        .await?;
+    for r in &rows {
+        let id: String = r.get("id");
+        sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+            .bind(&id)
+            .execute(&db.0)
+            .await?;
+        let _ = tokio::fs::remove_file(partial_path(cfg, &id)).await;
+    }
+    Ok(rows.len() as u64)
+}
@@ -44,6 +44,28 @@ pub async fn me(
     Ok(HttpResponse::Ok().json(user))
 }
 
+// GET /api/users/me/storage - how much of their quota the caller has used
+pub async fn my_storage(
+    cfg: web::Data<Config>,
+    db: web::Data<Db>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let file_count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM files
+         WHERE user_id = ? AND (scan_status IS NULL OR scan_status != 'infected')",
+    )
+    .bind(&user.user_id)
+    .fetch_one(&db.0)
+    .await?;
+    let used = crate::quotas::storage_used(&db.0, &user.user_id).await?;
+    let quota = crate::quotas::storage_quota(&cfg, &db, &user.user_id).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "used_bytes": used,
+        "quota_bytes": quota,
+        "file_count": file_count,
+    })))
+}
+
 #[derive(Deserialize)]
 pub struct UpdateMeReq {
     pub username: Option<String>,
@@ -113,6 +135,7 @@ pub async fn change_password(
 pub async fn upload_avatar(
     cfg: web::Data<Config>,
     db: web::Data<Db>,
+    storage: web::Data<dyn crate::storage::Storage>,
     chat: web::Data<actix::Addr<ChatServer>>,
     user: AuthUser,
     mut payload: Multipart,
@@ -126,11 +149,17 @@ pub async fn upload_avatar(
         .map_err(|_| ApiError::BadRequest("invalid multipart".into()))?
     {
         let field = item;
-        let s = save_multipart_file(&cfg, &db, &user.user_id, field).await?;
+        let s = save_multipart_file(&cfg, &db, storage.get_ref(), &user.user_id, field, false).await?;
         saved = Some(s);
         break;
     }
     let saved = saved.ok_or(ApiError::BadRequest("no file".into()))?;
+    let previous: Option<String> =
+        sqlx::query("SELECT avatar_file_id FROM users WHERE id = ?")
+            .bind(&user.user_id)
+            .fetch_optional(&db.0)
+            .await?
+            .and_then(|r| r.get("avatar_file_id"));
     sqlx::query("UPDATE users SET avatar_file_id = ?, updated_at = ? WHERE id = ?")
         .bind(&saved.file_id)
         .bind(chrono::Utc::now())
@@ -138,6 +167,11 @@ pub async fn upload_avatar(
         .execute(&db.0)
         .await?;
 
+    // The old avatar's blob is freed once nothing else uses that file
+    if let Some(old_id) = previous {
+        crate::blobs::release_file(&db, &old_id).await?;
+    }
+
     // Broadcast profile update
     chat.do_send(BroadcastAll {
         payload: serde_json::json!({
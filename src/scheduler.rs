@@ -0,0 +1,207 @@
+use crate::config::Config;
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::routes::messages::PostMessageReq;
+use crate::unfurl::LinkFetcher;
+use crate::ws::server::{ChatServer, NotifyUsers};
+use chrono::{DateTime, Utc};
+use sqlx::Row;
+
+/// How often the background task looks for due jobs.
+pub const POLL_INTERVAL_SECS: u64 = 15;
+
+/// Jobs are stored in `scheduled_jobs` and picked up by `run_due_jobs`, so they
+/// survive restarts. Two kinds exist:
+/// - `reminder`: notify the owner about `message_id` at `run_at`
+/// - `message`: post `content`/`file_id` to `channel_id` at `run_at`
+pub const KIND_REMINDER: &str = "reminder";
+pub const KIND_MESSAGE: &str = "message";
+
+/// Create, move or drop the reminder attached to a saved message.
+/// There is at most one pending reminder per (user, message).
+pub async fn set_message_reminder(
+    db: &Db,
+    user_id: &str,
+    message_id: &str,
+    remind_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let now = Utc::now();
+    sqlx::query(
+        "UPDATE scheduled_jobs SET status = 'cancelled', updated_at = ?
+         WHERE user_id = ? AND message_id = ? AND kind = 'reminder' AND status = 'pending'",
+    )
+    .bind(now)
+    .bind(user_id)
+    .bind(message_id)
+    .execute(&db.0)
+    .await?;
+
+    if let Some(run_at) = remind_at {
+        sqlx::query(
+            "INSERT INTO scheduled_jobs (id, user_id, kind, run_at, message_id, status, created_at, updated_at)
+             VALUES (?, ?, 'reminder', ?, ?, 'pending', ?, ?)",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(run_at)
+        .bind(message_id)
+        .bind(now)
+        .bind(now)
+        .execute(&db.0)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Jobs left in `running` were interrupted by a shutdown. We don't know whether the
+/// side effect happened, so mark them failed rather than risk posting twice.
+pub async fn recover_interrupted_jobs(db: &Db) -> Result<u64, ApiError> {
+    let now = Utc::now();
+    let result = sqlx::query(
+        "UPDATE scheduled_jobs SET status = 'failed', error = 'interrupted by server restart', updated_at = ?, completed_at = ?
+         WHERE status = 'running'",
+    )
+    .bind(now)
+    .bind(now)
+    .execute(&db.0)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Execute every pending job whose `run_at` has passed. Returns the number of jobs handled.
+pub async fn run_due_jobs(
+    cfg: &Config,
+    db: &Db,
+    chat: &actix::Addr<ChatServer>,
+    fetcher: &LinkFetcher,
+) -> Result<u64, ApiError> {
+    let rows = sqlx::query(
+        "SELECT id, user_id, kind, channel_id, message_id, content, file_id
+         FROM scheduled_jobs WHERE status = 'pending' AND run_at <= ? ORDER BY run_at ASC LIMIT 100",
+    )
+    .bind(Utc::now())
+    .fetch_all(&db.0)
+    .await?;
+
+    let mut handled = 0;
+    for r in rows {
+        let id: String = r.get("id");
+
+        // Claim the job so a concurrent edit/cancel can't race with delivery
+        let claimed = sqlx::query(
+            "UPDATE scheduled_jobs SET status = 'running', updated_at = ? WHERE id = ? AND status = 'pending'",
+        )
+        .bind(Utc::now())
+        .bind(&id)
+        .execute(&db.0)
+        .await?;
+        if claimed.rows_affected() == 0 {
+            continue;
+        }
+
+        let user_id: String = r.get("user_id");
+        let kind: String = r.get("kind");
+        let result = match kind.as_str() {
+            KIND_REMINDER => {
+                deliver_reminder(db, chat, &id, &user_id, r.get("message_id")).await
+            }
+            KIND_MESSAGE => match r.get::<Option<String>, _>("channel_id") {
+                Some(channel_id) => crate::routes::messages::create_message(
+                    cfg,
+                    db,
+                    chat,
+                    fetcher,
+                    &user_id,
+                    &channel_id,
+                    PostMessageReq {
+                        content: r.get("content"),
+                        file_id: r.get("file_id"),
+                        poll: None,
+                        voice_note: false,
+                    },
+                )
+                .await
+                .map(|_| ()),
+                None => Err(ApiError::BadRequest("missing channel".into())),
+            },
+            _ => Err(ApiError::BadRequest(format!("unknown job kind: {}", kind))),
+        };
+
+        let now = Utc::now();
+        match result {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE scheduled_jobs SET status = 'done', updated_at = ?, completed_at = ? WHERE id = ?",
+                )
+                .bind(now)
+                .bind(now)
+                .bind(&id)
+                .execute(&db.0)
+                .await?;
+            }
+            Err(e) => {
+                log::warn!("Scheduled job {} ({}) failed: {}", id, kind, e);
+                sqlx::query(
+                    "UPDATE scheduled_jobs SET status = 'failed', error = ?, updated_at = ?, completed_at = ? WHERE id = ?",
+                )
+                .bind(e.to_string())
+                .bind(now)
+                .bind(now)
+                .bind(&id)
+                .execute(&db.0)
+                .await?;
+            }
+        }
+        handled += 1;
+    }
+    Ok(handled)
+}
+
+async fn deliver_reminder(
+    db: &Db,
+    chat: &actix::Addr<ChatServer>,
+    job_id: &str,
+    user_id: &str,
+    message_id: Option<String>,
+) -> Result<(), ApiError> {
+    let message_id = message_id.ok_or(ApiError::BadRequest("missing message".into()))?;
+
+    // The user must still be able to read the message when the reminder fires
+    let row = sqlx::query(
+        "SELECT m.channel_id, m.user_id, m.content, s.note
+         FROM messages m
+         INNER JOIN channels c ON c.id = m.channel_id
+         INNER JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = ?
+         LEFT JOIN saved_messages s ON s.message_id = m.id AND s.user_id = cm.user_id
+         WHERE m.id = ? AND m.deleted_at IS NULL AND c.deleted_at IS NULL AND cm.can_read = 1",
+    )
+    .bind(user_id)
+    .bind(&message_id)
+    .fetch_optional(&db.0)
+    .await?;
+    let row = row.ok_or(ApiError::NotFound)?;
+
+    let payload = serde_json::json!({
+        "type": "reminder",
+        "job_id": job_id,
+        "message_id": message_id,
+        "channel_id": row.get::<String,_>("channel_id"),
+        "author_id": row.get::<String,_>("user_id"),
+        "content": row.get::<Option<String>,_>("content"),
+        "note": row.get::<Option<String>,_>("note"),
+    })
+    .to_string();
+
+    // Keep the event for when the owner is offline; it's shown until dismissed
+    sqlx::query("UPDATE scheduled_jobs SET notification = ? WHERE id = ?")
+        .bind(&payload)
+        .bind(job_id)
+        .execute(&db.0)
+        .await?;
+    chat.do_send(NotifyUsers {
+        user_ids: vec![user_id.to_string()],
+        payload,
+        skip_channel: None,
+    });
+    Ok(())
+}
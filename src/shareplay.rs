@@ -1,6 +1,15 @@
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::media_resolver::{MediaInfo, MediaRequest, MediaResolver, PlaylistEntry, Resolvers};
+use crate::shareplay_cache::{CachedTrack, SharePlayCache};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::AbortHandle;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -10,16 +19,58 @@ pub enum RepeatMode {
     All,
 }
 
+/// Who may control a channel's SharePlay, from `channels.shareplay_control`. Anyone
+/// who can read the channel may still vote to skip from the voice call.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SharePlayControl {
+    #[default]
+    Everyone,
+    /// Only users in the channel's voice call
+    Voice,
+    /// Only DJs, see `permissions::is_shareplay_dj`
+    Djs,
+}
+
+impl SharePlayControl {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "everyone" => Some(Self::Everyone),
+            "voice" => Some(Self::Voice),
+            "djs" => Some(Self::Djs),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Everyone => "everyone",
+            Self::Voice => "voice",
+            Self::Djs => "djs",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueueItem {
     pub id: String,
     pub url: String,
+    #[serde(default)]
+    pub added_by: Option<String>, // User id; resolvers check what they may access
     pub title: String,
     pub file_path: Option<String>,      // None while downloading
     pub thumbnail_path: Option<String>, // Resized thumbnail
     pub download_error: Option<String>, // Some(err) if failed
     pub duration_seconds: u64,
-    pub download_status: String, // "grabbing", "downloading", "ready", "error"
+    pub download_status: String, // "pending", "grabbing", "downloading", "ready", "error"
+    #[serde(default)]
+    pub download_progress: Option<f32>, // Percent downloaded while "downloading"
+    #[serde(default)]
+    pub download_eta_secs: Option<u64>,
+    #[serde(default)]
+    pub gain_db: Option<f64>, // Loudness normalization every client applies; None to play as is
+    #[serde(default)]
+    pub video: bool, // Watch-party item: downloaded and played as video
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,6 +81,10 @@ pub struct SharePlayState {
     pub start_time: Option<DateTime<Utc>>, // When playback started/resumed, for sync
     pub current_position_secs: f64,        // Saved position when paused
     pub repeat_mode: RepeatMode,
+    #[serde(default)]
+    pub skip_votes: Vec<String>, // User ids who voted to skip the current track
+    #[serde(default)]
+    pub skip_votes_needed: usize, // Votes that skip it, from the voice call's size
     #[serde(skip)]
     pub last_auto_next: Option<DateTime<Utc>>,
 }
@@ -43,21 +98,29 @@ impl SharePlayState {
             start_time: None,
             current_position_secs: 0.0,
             repeat_mode: RepeatMode::Off,
+            skip_votes: Vec::new(),
+            skip_votes_needed: 0,
             last_auto_next: None,
         }
     }
 
-    pub fn add_item(&mut self, url: String) -> String {
+    pub fn add_item(&mut self, url: String, added_by: &str, video: bool) -> String {
         let id = Uuid::new_v4().to_string();
         self.queue.push(QueueItem {
             id: id.clone(),
             url,
+            added_by: Some(added_by.to_string()),
             title: "Grabbing...".to_string(),
             file_path: None,
             thumbnail_path: None,
             download_error: None,
             duration_seconds: 0,
-            download_status: "grabbing".to_string(),
+            // Started by ChatServer once the download pool has a free slot
+            download_status: "pending".to_string(),
+            download_progress: None,
+            download_eta_secs: None,
+            gain_db: None,
+            video,
         });
 
         // Auto-play if queue was empty or nothing playing
@@ -75,13 +138,17 @@ impl SharePlayState {
         file_path: String,
         thumbnail_path: Option<String>,
         duration: u64,
+        gain_db: Option<f64>,
     ) {
         if let Some(item) = self.queue.iter_mut().find(|i| i.id == id) {
             item.title = title;
             item.file_path = Some(file_path);
             item.thumbnail_path = thumbnail_path;
             item.duration_seconds = duration;
+            item.gain_db = gain_db;
             item.download_status = "ready".to_string();
+            item.download_progress = None;
+            item.download_eta_secs = None;
         }
     }
 
@@ -90,6 +157,20 @@ impl SharePlayState {
             item.title = "Error loading song".to_string();
             item.download_error = Some(error);
             item.download_status = "error".to_string();
+            item.download_progress = None;
+            item.download_eta_secs = None;
+        }
+    }
+
+    /// Returns whether the item is still downloading, i.e. worth telling clients about.
+    pub fn update_item_progress(&mut self, id: &str, percent: f32, eta_secs: Option<u64>) -> bool {
+        match self.queue.iter_mut().find(|i| i.id == id) {
+            Some(item) if item.download_status == "downloading" => {
+                item.download_progress = Some(percent);
+                item.download_eta_secs = eta_secs;
+                true
+            }
+            _ => false,
         }
     }
 
@@ -109,6 +190,28 @@ impl SharePlayState {
         }
     }
 
+    /// The id of the current track, to tell whether an action changed it.
+    pub fn current_item_id(&self) -> Option<&str> {
+        self.current_index
+            .and_then(|i| self.queue.get(i))
+            .map(|item| item.id.as_str())
+    }
+
+    /// The current track, if it's downloaded, and how far into it playback is.
+    pub fn now_playing(&self) -> Option<(QueueItem, f64)> {
+        let item = self.current_index.and_then(|i| self.queue.get(i))?;
+        (item.download_status == "ready").then(|| (item.clone(), self.get_current_position()))
+    }
+
+    /// Count a vote to skip the current track. Returns whether it's a new vote.
+    pub fn vote_skip(&mut self, user_id: &str) -> bool {
+        if self.current_index.is_none() || self.skip_votes.iter().any(|u| u == user_id) {
+            return false;
+        }
+        self.skip_votes.push(user_id.to_string());
+        true
+    }
+
     pub fn play(&mut self) {
         if self.status == "playing" {
             return;
@@ -130,6 +233,14 @@ impl SharePlayState {
         self.start_time = None;
     }
 
+    /// How to save the state: paused where playback is now, so a queue restored after
+    /// a restart resumes from there rather than jumping ahead by the downtime.
+    pub fn for_saving(&self) -> Self {
+        let mut saved = self.clone();
+        saved.pause();
+        saved
+    }
+
     pub fn seek(&mut self, timestamp: f64) {
         self.current_position_secs = timestamp;
         if self.status == "playing" {
@@ -179,6 +290,19 @@ impl SharePlayState {
         }
     }
 
+    /// A client's player reached the end of track `id`. Anyone may report it, whatever
+    /// the channel's control mode, so it only counts near the end of the current track.
+    pub fn track_ended(&mut self, id: &str) {
+        let Some(item) = self.current_index.and_then(|i| self.queue.get(i)) else {
+            return;
+        };
+        let near_end = item.duration_seconds == 0
+            || self.get_current_position() + ENDED_SLACK_SECS >= item.duration_seconds as f64;
+        if item.id == id && near_end {
+            self.next();
+        }
+    }
+
     pub fn prev(&mut self) {
         if self.queue.is_empty() {
             return;
@@ -224,28 +348,6 @@ impl SharePlayState {
             return;
         }
 
-        // Delete the file if it exists
-        let item = &self.queue[index];
-        if let Some(file_path) = &item.file_path {
-            let _ = std::fs::remove_file(file_path);
-        }
-        if let Some(thumb) = &item.thumbnail_path {
-            let _ = std::fs::remove_file(thumb);
-        }
-
-        // Also try to delete by ID pattern
-        let temp_dir = std::path::PathBuf::from("temp");
-        if temp_dir.exists() {
-            if let Ok(entries) = std::fs::read_dir(&temp_dir) {
-                for entry in entries.flatten() {
-                    let name = entry.file_name().to_string_lossy().to_string();
-                    if name.starts_with(&item.id) {
-                        let _ = std::fs::remove_file(entry.path());
-                    }
-                }
-            }
-        }
-
         self.queue.remove(index);
 
         // Adjust current_index
@@ -271,7 +373,159 @@ impl SharePlayState {
         }
     }
 
-    fn get_current_position(&self) -> f64 {
+    /// Move the item at `from` to `to`; the current track keeps playing wherever it ends up.
+    pub fn move_item(&mut self, from: usize, to: usize) {
+        if from >= self.queue.len() || to >= self.queue.len() || from == to {
+            return;
+        }
+        let item = self.queue.remove(from);
+        self.queue.insert(to, item);
+
+        if let Some(curr) = self.current_index {
+            if curr == from {
+                self.current_index = Some(to);
+            } else if from < curr && to >= curr {
+                // Moved from before the current track to after it
+                self.current_index = Some(curr - 1);
+            } else if from > curr && to <= curr {
+                // Moved from after the current track to before it
+                self.current_index = Some(curr + 1);
+            }
+        }
+    }
+
+    /// Queue a playlist's songs where the item that turned out to be the playlist was,
+    /// or at the end without one; the current track keeps playing wherever it ends up.
+    pub fn insert_playlist(
+        &mut self,
+        placeholder_id: Option<&str>,
+        added_by: Option<String>,
+        entries: Vec<PlaylistEntry>,
+    ) {
+        let placeholder = placeholder_id.and_then(|id| self.queue.iter().position(|i| i.id == id));
+        // Its songs were queued by the same user, the same way
+        let (at, added_by, video) = match placeholder {
+            Some(idx) => {
+                let placeholder = self.queue.remove(idx);
+                (idx, placeholder.added_by, Some(placeholder.video))
+            }
+            None => (self.queue.len(), added_by, None),
+        };
+        let count = entries.len();
+        let items = entries.into_iter().map(|entry| QueueItem {
+            id: Uuid::new_v4().to_string(),
+            url: entry.url,
+            added_by: added_by.clone(),
+            title: entry.title,
+            file_path: None,
+            thumbnail_path: None,
+            download_error: None,
+            duration_seconds: entry.duration_seconds,
+            download_status: "pending".to_string(),
+            download_progress: None,
+            download_eta_secs: None,
+            gain_db: None,
+            video: video.unwrap_or(entry.video),
+        });
+        self.queue.splice(at..at, items);
+
+        match (self.current_index, placeholder) {
+            (None, _) if !self.queue.is_empty() => self.current_index = Some(0),
+            (Some(curr), Some(idx)) if curr > idx => {
+                self.current_index = Some(curr + count - 1);
+            }
+            (Some(curr), Some(idx)) if curr == idx => {
+                // The placeholder was the current track: its first song takes over, or
+                // with none, whatever slid into its place
+                if self.queue.is_empty() {
+                    self.current_index = None;
+                    self.status = "paused".to_string();
+                    self.start_time = None;
+                } else {
+                    if curr >= self.queue.len() {
+                        self.current_index = Some(self.queue.len() - 1);
+                    }
+                    self.current_position_secs = 0.0;
+                    if self.status == "playing" {
+                        self.start_time = Some(Utc::now());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Queue a song right after the current track.
+    pub fn play_next(&mut self, url: String, added_by: &str, video: bool) -> String {
+        let id = self.add_item(url, added_by, video);
+        let last = self.queue.len() - 1;
+        if let Some(curr) = self.current_index {
+            self.move_item(last, (curr + 1).min(last));
+        }
+        id
+    }
+
+    /// Shuffle the songs after the current track, which stays where it is.
+    pub fn shuffle_upcoming(&mut self) {
+        use rand::seq::SliceRandom;
+        let start = self.current_index.map_or(0, |curr| curr + 1);
+        if start < self.queue.len() {
+            self.queue[start..].shuffle(&mut rand::rng());
+        }
+    }
+
+    /// The songs after the current track, i.e. what `clear_upcoming` removes.
+    pub fn upcoming(&self) -> &[QueueItem] {
+        let start = self.current_index.map_or(0, |curr| curr + 1);
+        &self.queue[start.min(self.queue.len())..]
+    }
+
+    /// Remove every song after the current track.
+    pub fn clear_upcoming(&mut self) {
+        let start = self.current_index.map_or(0, |curr| curr + 1);
+        // From the back, so remove_item never has to shift the current index
+        for index in (start..self.queue.len()).rev() {
+            self.remove_item(index);
+        }
+    }
+
+    /// After a restart: keep downloaded songs whose files survived, and queue
+    /// everything else (including downloads the restart cut off) to be fetched again.
+    /// Returns whether anything needs downloading.
+    pub fn verify_files(&mut self) -> bool {
+        let mut missing = false;
+        for item in self.queue.iter_mut() {
+            if let Some(thumb) = &item.thumbnail_path
+                && !std::path::Path::new(thumb).exists()
+            {
+                item.thumbnail_path = None;
+            }
+            let ready = item.download_status == "ready"
+                && item
+                    .file_path
+                    .as_ref()
+                    .is_some_and(|p| std::path::Path::new(p).exists());
+            if !ready && item.download_status != "error" {
+                item.file_path = None;
+                item.gain_db = None;
+                item.download_status = "pending".to_string();
+                item.download_progress = None;
+                item.download_eta_secs = None;
+                missing = true;
+            }
+        }
+        // Don't leave listeners waiting on a song that isn't there yet
+        let current_missing = self
+            .current_index
+            .and_then(|i| self.queue.get(i))
+            .is_some_and(|i| i.file_path.is_none());
+        if current_missing {
+            self.pause();
+        }
+        missing
+    }
+
+    pub fn get_current_position(&self) -> f64 {
         if self.status == "paused" {
             self.current_position_secs
         } else if let Some(start) = self.start_time {
@@ -283,430 +537,740 @@ impl SharePlayState {
     }
 }
 
-/// Downloads audio from a URL using yt-dlp in two steps:
-/// 1. Simulate to get metadata (title, duration) - updates status to "downloading"
-/// 2. Actual download with audio extraction - updates status to "ready"
-/// Determines if the input looks like a URL or a search term.
-fn is_url(input: &str) -> bool {
-    let input_lower = input.to_lowercase();
-    // Check for common URL schemes
-    if input_lower.starts_with("http://") || input_lower.starts_with("https://") {
-        return true;
-    }
-    // Check for common video site patterns (without scheme)
-    let video_patterns = [
-        "youtube.com",
-        "youtu.be",
-        "vimeo.com",
-        "dailymotion.com",
-        "twitch.tv",
-        "soundcloud.com",
-        "bandcamp.com",
-    ];
-    for pattern in video_patterns {
-        if input_lower.contains(pattern) {
-            return true;
-        }
-    }
-    false
-}
+/// How far from the end of a track, by the server's clock, a client may report it ended.
+const ENDED_SLACK_SECS: f64 = 5.0;
+
+/// How long a resolver gets to look up a song, a playlist or a thumbnail.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(60);
+/// Minimum time between two progress updates for the same download.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
-fn is_youtube_playlist(url: &str) -> bool {
-    let url_lower = url.to_lowercase();
-    (url_lower.contains("youtube.com") || url_lower.contains("youtu.be"))
-        && (url_lower.contains("list=") || url_lower.contains("/playlist?"))
+/// Downloads in flight, across all channels. At most `max` run at once; other items
+/// wait as "pending" in their queue until `ChatServer` hands them a slot.
+pub struct DownloadJobs {
+    max: usize,
+    timeout: Duration,
+    /// Where items are downloaded to, before the cache takes them
+    temp_dir: PathBuf,
+    cache: Arc<SharePlayCache>,
+    resolvers: Resolvers,
+    target_lufs: Option<f64>,
+    running: HashMap<String, (String, AbortHandle)>, // item id -> (channel id, task)
 }
 
-pub fn start_download(
-    url: String,
-    id: String,
-    state_addr: actix::Addr<crate::ws::server::ChatServer>,
-    channel_id: String,
-) {
-    if is_youtube_playlist(&url) {
-        log::info!("Detected YouTube playlist: {}", url);
-        std::thread::spawn(move || {
-            let output = Command::new("yt-dlp")
-                .arg("--flat-playlist")
-                .arg("-J")
-                .arg(&url)
-                .output();
-
-            match output {
-                Ok(out) if out.status.success() => {
-                    let stdout = String::from_utf8_lossy(&out.stdout);
-                    if let Ok(info) = serde_json::from_str::<serde_json::Value>(&stdout) {
-                        let mut entries = Vec::new();
-                        if let Some(entries_arr) = info["entries"].as_array() {
-                            for entry in entries_arr {
-                                let entry_url = entry["url"].as_str().map(|s| {
-                                    if s.starts_with("http") {
-                                        s.to_string()
-                                    } else {
-                                        format!("https://www.youtube.com/watch?v={}", s)
-                                    }
-                                });
-                                let title = entry["title"]
-                                    .as_str()
-                                    .unwrap_or("Unknown Title")
-                                    .to_string();
-                                let duration = entry["duration"].as_f64().unwrap_or(0.0) as u64;
-
-                                if let Some(e_url) = entry_url {
-                                    entries.push((e_url, title, duration));
-                                }
-                            }
-                        }
-
-                        if !entries.is_empty() {
-                            state_addr.do_send(crate::ws::server::SharePlayPlaylistResult {
-                                channel_id,
-                                placeholder_id: id,
-                                entries,
-                            });
-                            return;
-                        }
-                    }
-                    log::error!("Failed to parse playlist JSON or zero entries found");
-                }
-                Ok(out) => {
-                    log::error!(
-                        "yt-dlp playlist resolve failed: {}",
-                        String::from_utf8_lossy(&out.stderr)
-                    );
-                }
-                Err(e) => {
-                    log::error!("Failed to execute yt-dlp for playlist: {}", e);
+impl DownloadJobs {
+    pub fn new(
+        max: usize,
+        timeout: Duration,
+        temp_dir: PathBuf,
+        cache: Arc<SharePlayCache>,
+        resolvers: Resolvers,
+        target_lufs: f64,
+    ) -> Self {
+        Self {
+            max: max.max(1),
+            timeout,
+            temp_dir,
+            cache,
+            resolvers,
+            target_lufs: (target_lufs != 0.0).then_some(target_lufs),
+            running: HashMap::new(),
+        }
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.running.len() < self.max
+    }
+
+    pub fn running_in(&self, channel_id: &str) -> usize {
+        self.running.values().filter(|(c, _)| c == channel_id).count()
+    }
+
+    /// Download a queue item (or expand the playlist it points to). The job reports
+    /// back with `SharePlayMetadataResult`/`SharePlayDownloadResult` or
+    /// `SharePlayPlaylistResult`, then `SharePlayJobFinished`.
+    pub fn start(
+        &mut self,
+        req: MediaRequest,
+        id: String,
+        channel_id: String,
+        addr: actix::Addr<crate::ws::server::ChatServer>,
+    ) {
+        let Some(resolver) = self.resolvers.pick(&req) else {
+            log::error!("No SharePlay resolver accepts {}", req.input);
+            addr.do_send(crate::ws::server::SharePlayMetadataResult {
+                channel_id,
+                id: id.clone(),
+                success: false,
+                title: "".to_string(),
+                duration: 0,
+                thumbnail_path: None,
+                error: Some("Unsupported link".to_string()),
+            });
+            addr.do_send(crate::ws::server::SharePlayJobFinished { id });
+            return;
+        };
+        log::info!("Resolving '{}' with {}", req.input, resolver.name());
+        let job = DownloadJob {
+            req,
+            resolver,
+            id: id.clone(),
+            channel_id: channel_id.clone(),
+            addr,
+            temp_dir: self.temp_dir.clone(),
+            cache: self.cache.clone(),
+            timeout: self.timeout,
+            target_lufs: self.target_lufs,
+        };
+        let handle = tokio::spawn(job.run());
+        self.running.insert(id, (channel_id, handle.abort_handle()));
+    }
+
+    pub fn finished(&mut self, id: &str) {
+        self.running.remove(id);
+    }
+
+    /// Stop an item's download, killing yt-dlp and deleting what it wrote so far.
+    pub fn cancel(&mut self, id: &str) {
+        if let Some((_, handle)) = self.running.remove(id) {
+            log::info!("Cancelling SharePlay download id={}", id);
+            handle.abort();
+        }
+    }
+
+    pub fn cancel_channel(&mut self, channel_id: &str) {
+        let ids: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, (c, _))| c == channel_id)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            self.cancel(&id);
+        }
+    }
+
+    /// Delete the files an item downloaded, unless the cache owns them, along with
+    /// anything else in the temp directory named after it (e.g. partial downloads).
+    pub fn delete_item_files(&self, item: &QueueItem) {
+        for path in [&item.file_path, &item.thumbnail_path].into_iter().flatten() {
+            if !self.cache.is_cached_path(path) {
+                if let Err(e) = std::fs::remove_file(path) {
+                    log::debug!("Failed to delete SharePlay file {}: {}", path, e);
+                } else {
+                    log::info!("Deleted SharePlay file: {}", path);
                 }
             }
-            // Fallback: If playlist resolve fails or empty, try as single video
-            start_single_download(url, id, state_addr, channel_id);
-        });
-    } else {
-        start_single_download(url, id, state_addr, channel_id);
+        }
+        remove_files_starting_with(&self.temp_dir, &item.id);
+    }
+}
+
+fn remove_files_starting_with(dir: &Path, prefix: &str) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
     }
 }
 
-pub fn start_single_download(
-    url: String,
+/// Deletes an item's files in the temp directory if its job is dropped halfway, i.e. cancelled.
+struct PartialFiles {
+    temp_dir: PathBuf,
+    id: String,
+    armed: bool,
+}
+
+impl Drop for PartialFiles {
+    fn drop(&mut self) {
+        if self.armed {
+            remove_files_starting_with(&self.temp_dir, &self.id);
+        }
+    }
+}
+
+struct DownloadJob {
+    req: MediaRequest,
+    resolver: Arc<dyn MediaResolver>,
     id: String,
-    state_addr: actix::Addr<crate::ws::server::ChatServer>,
     channel_id: String,
-) {
-    std::thread::spawn(move || {
-        // Determine if input is a URL or a search term
-        let effective_url = if is_url(&url) {
-            url.clone()
-        } else {
-            // Treat as a search term - use ytsearch1: to get first result
-            format!("ytsearch1:{}", url)
+    addr: actix::Addr<crate::ws::server::ChatServer>,
+    temp_dir: PathBuf,
+    cache: Arc<SharePlayCache>,
+    timeout: Duration,
+    target_lufs: Option<f64>,
+}
+
+impl DownloadJob {
+    async fn run(self) {
+        let mut partial = PartialFiles {
+            temp_dir: self.temp_dir.clone(),
+            id: self.id.clone(),
+            armed: true,
         };
-        log::info!(
-            "Input '{}' resolved to effective URL: {}",
-            url,
-            effective_url
-        );
+        if !self.resolve_playlist().await {
+            // Also the fallback if the playlist can't be resolved or is empty
+            self.download().await;
+        }
+        partial.armed = false;
+        self.addr.do_send(crate::ws::server::SharePlayJobFinished { id: self.id });
+    }
 
-        // Step 1: Simulate to get metadata
-        log::info!("Step 1: Getting metadata for url={}", effective_url);
-
-        let sim_output = Command::new("yt-dlp")
-            .arg("--simulate")
-            .arg("--print-json")
-            .arg(&effective_url)
-            .output();
-
-        let step1_res = match sim_output {
-            Ok(out) if out.status.success() => {
-                let stdout = String::from_utf8_lossy(&out.stdout);
-                let json_line = stdout.lines().last().unwrap_or("{}");
-
-                if let Ok(info) = serde_json::from_str::<serde_json::Value>(json_line) {
-                    let title = info["title"]
-                        .as_str()
-                        .unwrap_or("Unknown Title")
-                        .to_string();
-                    let duration = info["duration"].as_f64().unwrap_or(0.0) as u64;
-                    log::info!(
-                        "Metadata extracted: title={}, duration={}s",
-                        title,
-                        duration
-                    );
-
-                    // Send metadata update (grabbing -> downloading)
-                    state_addr.do_send(crate::ws::server::SharePlayMetadataResult {
-                        channel_id: channel_id.clone(),
-                        id: id.clone(),
-                        success: true,
-                        title: title.clone(),
-                        duration,
-                        thumbnail_path: None, // Will update when processed
-                        error: None,
-                    });
-
-                    Some((
-                        title,
-                        duration,
-                        info["thumbnail"].as_str().map(|s| s.to_string()),
-                    ))
-                } else {
-                    log::error!("Failed to parse metadata JSON: {}", json_line);
-                    state_addr.do_send(crate::ws::server::SharePlayMetadataResult {
-                        channel_id,
-                        id,
-                        success: false,
-                        title: "".to_string(),
-                        duration: 0,
-                        thumbnail_path: None,
-                        error: Some("Failed to parse metadata".to_string()),
-                    });
-                    return;
-                }
+    /// Whether the item was a playlist whose entries were sent to replace it.
+    async fn resolve_playlist(&self) -> bool {
+        let entries = match tokio::time::timeout(METADATA_TIMEOUT, self.resolver.playlist(&self.req)).await {
+            Ok(Ok(Some(entries))) => entries,
+            Ok(Ok(None)) => return false,
+            Ok(Err(e)) => {
+                log::error!("Failed to resolve playlist {}: {}", self.req.input, e);
+                return false;
             }
-            Ok(out) => {
-                let stderr = String::from_utf8_lossy(&out.stderr);
-                log::error!("yt-dlp simulate failed: {}", stderr);
-                state_addr.do_send(crate::ws::server::SharePlayMetadataResult {
-                    channel_id,
-                    id,
-                    success: false,
-                    title: "".to_string(),
-                    duration: 0,
-                    thumbnail_path: None,
-                    error: Some(format!("Failed to get metadata: {}", stderr)),
-                });
+            Err(_) => {
+                log::error!("Resolving playlist {} timed out", self.req.input);
+                return false;
+            }
+        };
+        if entries.is_empty() {
+            log::error!("Playlist {} has no entries", self.req.input);
+            return false;
+        }
+        self.addr.do_send(crate::ws::server::SharePlayPlaylistResult {
+            channel_id: self.channel_id.clone(),
+            placeholder_id: Some(self.id.clone()),
+            added_by: None,
+            entries,
+        });
+        true
+    }
+
+    /// Downloads audio in two steps:
+    /// 1. Get metadata (title, duration) - updates status to "downloading"
+    /// 2. Actual download, then measuring its loudness - updates status to "ready"
+    async fn download(&self) {
+        // Links only some users may open are never looked up or remembered by URL
+        let cache_url = self
+            .resolver
+            .url_cacheable(&self.req.input)
+            .then_some(self.req.input.as_str());
+        if let Some(url) = cache_url
+            && let Some(track) = self.cache.acquire_url(url, self.req.video, &self.id)
+        {
+            log::info!("SharePlay cache hit for url={}", self.req.input);
+            self.send_track(track);
+            return;
+        }
+
+        // Step 1: Get metadata
+        log::info!("Step 1: Getting metadata for {}", self.req.input);
+        let info = match tokio::time::timeout(METADATA_TIMEOUT, self.resolver.metadata(&self.req)).await {
+            Ok(Ok(info)) => info,
+            Ok(Err(e)) => {
+                log::error!("Failed to get metadata for {}: {}", self.req.input, e);
+                self.send_metadata_error(e);
                 return;
             }
-            Err(e) => {
-                log::error!("Failed to execute yt-dlp: {}", e);
-                state_addr.do_send(crate::ws::server::SharePlayMetadataResult {
-                    channel_id,
-                    id,
-                    success: false,
-                    title: "".to_string(),
-                    duration: 0,
-                    thumbnail_path: None,
-                    error: Some(format!("yt-dlp execution failed: {}", e)),
-                });
+            Err(_) => {
+                log::error!("Getting metadata for {} timed out", self.req.input);
+                self.send_metadata_error(format!(
+                    "Getting metadata timed out after {} seconds",
+                    METADATA_TIMEOUT.as_secs()
+                ));
                 return;
             }
         };
 
-        let (title, duration, thumbnail) = if let Some(vals) = step1_res {
-            vals
-        } else {
+        // Another link to (or search for) a song we already have
+        let cached = self
+            .cache
+            .acquire(&info.cache_key, cache_url, self.req.video, &self.id);
+        if let Some(track) = cached {
+            log::info!("SharePlay cache hit for key={}", info.cache_key);
+            self.send_track(track);
             return;
-        };
+        }
 
-        // Download and process thumbnail in background
-        let thumb_path = if let Some(_thumb_url) = thumbnail {
-            log::info!("Processing thumbnail for id={}", id);
-            let temp_dir = std::path::PathBuf::from("temp");
-            let raw_thumb = temp_dir.join(format!("{}_raw_thumb", id));
-            let final_thumb = temp_dir.join(format!("{}_thumb.jpg", id));
-
-            // Use yt-dlp to download it
-            let thumb_dl = Command::new("yt-dlp")
-                .arg("--skip-download")
-                .arg("--write-thumbnail")
-                .arg("--convert-thumbnails")
-                .arg("jpg")
-                .arg("-o")
-                .arg(raw_thumb.to_str().unwrap())
-                .arg(&effective_url)
-                .output();
-
-            match thumb_dl {
-                Ok(out) if out.status.success() => {
-                    // yt-dlp might have saved it as raw_thumb.jpg or similar
-                    // Find the thumbnail file directly (can't use find_downloaded_file
-                    // since it excludes files with "_thumb" in the name)
-                    let actual_raw = find_thumbnail_file(&temp_dir, &format!("{}_raw_thumb", id));
-                    if let Some(raw_path) = actual_raw {
-                        // Use image crate to resize/crop
-                        if let Ok(img) = image::open(&raw_path) {
-                            // Center crop and resize to 256x256
-                            let processed =
-                                img.resize_to_fill(256, 256, image::imageops::FilterType::Lanczos3);
-                            if processed.save(&final_thumb).is_ok() {
-                                let _ = std::fs::remove_file(raw_path);
-                                Some(final_thumb.to_string_lossy().to_string())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
-            }
+        log::info!(
+            "Metadata extracted: title={}, duration={}s",
+            info.title,
+            info.duration_seconds
+        );
+        // Send metadata update (grabbing -> downloading)
+        self.send_metadata(info.title.clone(), info.duration_seconds, None);
+
+        let thumb_path = if info.has_thumbnail {
+            self.thumbnail(&info).await
         } else {
             None
         };
-
         // Notify with thumbnail path if we got one
         if thumb_path.is_some() {
-            state_addr.do_send(crate::ws::server::SharePlayMetadataResult {
-                channel_id: channel_id.clone(),
-                id: id.clone(),
-                success: true,
-                title: title.clone(),
-                duration,
-                thumbnail_path: thumb_path.clone(),
-                error: None,
-            });
+            self.send_metadata(info.title.clone(), info.duration_seconds, thumb_path.clone());
         }
 
         // Step 2: Actual download
-        log::info!("Step 2: Downloading audio for url={}", effective_url);
-
-        let temp_dir = std::path::PathBuf::from("temp");
-        if let Err(e) = std::fs::create_dir_all(&temp_dir) {
+        log::info!(
+            "Step 2: Downloading {} for {}",
+            if self.req.video { "video" } else { "audio" },
+            info.source
+        );
+        if let Err(e) = std::fs::create_dir_all(&self.temp_dir) {
             log::error!("Failed to create temp directory: {}", e);
         }
-
-        let output_template = temp_dir.join(format!("{}.%(ext)s", id));
-
-        let download_output = Command::new("yt-dlp")
-            .arg("-x") // Extract audio
-            .arg("--audio-format")
-            .arg("opus")
-            .arg("--audio-quality")
-            .arg("0") // Best quality
-            //.arg("--extractor-args")
-            //.arg("youtube:player_client=default,-android_sdkless")
-            .arg("--cookies-from-browser")
-            .arg("firefox")
-            .arg("-o")
-            .arg(output_template.to_str().unwrap())
-            .arg(&effective_url)
-            .output();
-
-        match download_output {
-            Ok(out) if out.status.success() => {
-                let file_path = find_downloaded_file(&temp_dir, &id);
-                log::info!("Download success: file={:?}", file_path);
-
-                if let Some(path) = file_path {
-                    state_addr.do_send(crate::ws::server::SharePlayDownloadResult {
-                        channel_id,
-                        id,
-                        success: true,
-                        title,
-                        file_path: Some(path),
-                        thumbnail_path: thumb_path,
-                        duration,
-                        error: None,
-                    });
-                } else {
-                    log::error!("yt-dlp reported success but file not found in temp/");
-                    state_addr.do_send(crate::ws::server::SharePlayDownloadResult {
-                        channel_id,
-                        id,
-                        success: false,
-                        title: "".to_string(),
-                        file_path: None,
-                        thumbnail_path: None,
-                        duration: 0,
-                        error: Some("Downloaded file not found".to_string()),
-                    });
-                }
-            }
-            Ok(out) => {
-                let stderr = String::from_utf8_lossy(&out.stderr);
-                log::error!("yt-dlp download failed: {}", stderr);
-                state_addr.do_send(crate::ws::server::SharePlayDownloadResult {
-                    channel_id,
-                    id,
-                    success: false,
-                    title: "".to_string(),
-                    file_path: None,
-                    thumbnail_path: None,
-                    duration: 0,
-                    error: Some(format!("Download failed: {}", stderr)),
+        let last_progress = std::sync::Mutex::new(None::<Instant>);
+        let progress = |percent: f32, eta_secs: Option<u64>| {
+            let mut last = last_progress.lock().unwrap();
+            if last.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL) {
+                *last = Some(Instant::now());
+                self.addr.do_send(crate::ws::server::SharePlayDownloadProgress {
+                    channel_id: self.channel_id.clone(),
+                    id: self.id.clone(),
+                    percent,
+                    eta_secs,
                 });
             }
-            Err(e) => {
-                log::error!("Failed to execute yt-dlp: {}", e);
-                state_addr.do_send(crate::ws::server::SharePlayDownloadResult {
-                    channel_id,
-                    id,
-                    success: false,
-                    title: "".to_string(),
-                    file_path: None,
-                    thumbnail_path: None,
-                    duration: 0,
-                    error: Some(format!("yt-dlp execution failed: {}", e)),
-                });
+        };
+        let stem = self.temp_dir.join(&self.id);
+        let download = self.resolver.download(&self.req, &info, &stem, &progress);
+        let path = match tokio::time::timeout(self.timeout, download).await {
+            Ok(Ok(path)) => path,
+            Ok(Err(e)) => {
+                log::error!("Download of {} failed: {}", info.source, e);
+                self.send_download_error(e);
+                return;
             }
-        }
-    });
-}
+            Err(_) => {
+                log::error!("Download of {} timed out", info.source);
+                self.send_download_error(format!(
+                    "Download timed out after {} seconds",
+                    self.timeout.as_secs()
+                ));
+                return;
+            }
+        };
+        log::info!("Download success: file={}", path.display());
 
-/// Find a thumbnail file by prefix in the temp directory.
-fn find_thumbnail_file(temp_dir: &std::path::Path, prefix: &str) -> Option<String> {
-    if let Ok(entries) = std::fs::read_dir(temp_dir) {
-        for entry in entries.flatten() {
-            let file_name = entry.file_name();
-            let file_name_str = file_name.to_string_lossy();
-            if file_name_str.starts_with(prefix) {
-                if let Some(path_str) = entry.path().to_str() {
-                    return Some(path_str.to_string());
-                }
+        // Some sources only know how long the song is once it's here
+        let mut duration = info.duration_seconds;
+        if duration == 0 {
+            let mime = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| actix_files::file_extension_to_mime(e).to_string())
+                .filter(|m| m.starts_with("audio/") || m.starts_with("video/"))
+                .unwrap_or_else(|| "audio/*".to_string());
+            if let Some(audio) = crate::audio::analyze(&path, Some(&mime)).await {
+                duration = (audio.duration_ms / 1000) as u64;
             }
         }
+
+        // Measured even with normalization off, so the cache has it if it's turned on
+        let loudness = crate::audio::measure_loudness(&path).await;
+        if let Some(l) = loudness {
+            log::info!(
+                "Loudness of {}: {:.1} LUFS, true peak {:.1} dBTP",
+                info.source,
+                l.integrated_lufs,
+                l.true_peak_db
+            );
+        }
+
+        let track = self.cache.insert(
+            &info.cache_key,
+            cache_url,
+            self.req.video,
+            &self.id,
+            CachedTrack {
+                title: info.title,
+                duration_seconds: duration,
+                file_path: path.to_string_lossy().to_string(),
+                thumbnail_path: thumb_path,
+                loudness,
+            },
+        );
+        self.send_track(track);
+    }
+
+    /// Fetch the thumbnail and crop it to a 256x256 JPEG.
+    async fn thumbnail(&self, info: &MediaInfo) -> Option<String> {
+        log::info!("Processing thumbnail for id={}", self.id);
+        let raw_prefix = self.temp_dir.join(format!("{}_raw_thumb", self.id));
+        let final_thumb = self.temp_dir.join(format!("{}_thumb.jpg", self.id));
+        let raw_path = tokio::time::timeout(METADATA_TIMEOUT, self.resolver.thumbnail(info, &raw_prefix))
+            .await
+            .ok()
+            .flatten()?;
+        tokio::task::spawn_blocking(move || {
+            // Center crop and resize to 256x256
+            let img = image::open(&raw_path).ok()?;
+            let processed = img.resize_to_fill(256, 256, image::imageops::FilterType::Lanczos3);
+            processed.save(&final_thumb).ok()?;
+            let _ = std::fs::remove_file(&raw_path);
+            Some(final_thumb.to_string_lossy().to_string())
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    fn send_metadata(&self, title: String, duration: u64, thumbnail_path: Option<String>) {
+        self.addr.do_send(crate::ws::server::SharePlayMetadataResult {
+            channel_id: self.channel_id.clone(),
+            id: self.id.clone(),
+            success: true,
+            title,
+            duration,
+            thumbnail_path,
+            error: None,
+        });
+    }
+
+    fn send_metadata_error(&self, error: String) {
+        self.addr.do_send(crate::ws::server::SharePlayMetadataResult {
+            channel_id: self.channel_id.clone(),
+            id: self.id.clone(),
+            success: false,
+            title: "".to_string(),
+            duration: 0,
+            thumbnail_path: None,
+            error: Some(error),
+        });
+    }
+
+    fn send_track(&self, track: CachedTrack) {
+        self.addr.do_send(crate::ws::server::SharePlayDownloadResult {
+            channel_id: self.channel_id.clone(),
+            id: self.id.clone(),
+            success: true,
+            title: track.title,
+            file_path: Some(track.file_path),
+            thumbnail_path: track.thumbnail_path,
+            duration: track.duration_seconds,
+            gain_db: self
+                .target_lufs
+                .zip(track.loudness)
+                .map(|(target, loudness)| loudness.gain_db(target)),
+            error: None,
+        });
+    }
+
+    fn send_download_error(&self, error: String) {
+        self.addr.do_send(crate::ws::server::SharePlayDownloadResult {
+            channel_id: self.channel_id.clone(),
+            id: self.id.clone(),
+            success: false,
+            title: "".to_string(),
+            file_path: None,
+            thumbnail_path: None,
+            duration: 0,
+            gain_db: None,
+            error: Some(error),
+        });
     }
-    None
 }
 
-/// Find the downloaded file by ID in the temp directory.
-/// yt-dlp may change the extension, so we search for files starting with the ID.
-/// Excludes thumbnail files (containing "_thumb" or "_raw_thumb").
-fn find_downloaded_file(temp_dir: &std::path::Path, id: &str) -> Option<String> {
-    if let Ok(entries) = std::fs::read_dir(temp_dir) {
-        for entry in entries.flatten() {
-            let file_name = entry.file_name();
-            let file_name_str = file_name.to_string_lossy();
-            if file_name_str.starts_with(id)
-                && !file_name_str.contains("_thumb")
-                && !file_name_str.contains("_raw_thumb")
-            {
-                if let Some(path_str) = entry.path().to_str() {
-                    return Some(path_str.to_string());
-                }
+/// Delete all downloaded files for a SharePlay state, and hand its cached songs back
+/// to the cache
+pub fn cleanup_channel_files(state: &SharePlayState, downloads: &DownloadJobs) {
+    for item in &state.queue {
+        downloads.cache.release(&item.id);
+        downloads.delete_item_files(item);
+    }
+}
+
+/// A write to `shareplay_states`: the channel and its serialized state, or `None` to
+/// delete the row.
+pub type StateWrite = (String, Option<String>);
+
+/// Apply state writes one at a time, in the order they were made, so an older state
+/// never overwrites a newer one.
+pub fn spawn_state_writer(db: Db) -> tokio::sync::mpsc::UnboundedSender<StateWrite> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<StateWrite>();
+    tokio::spawn(async move {
+        while let Some((channel_id, state)) = rx.recv().await {
+            let res = match state {
+                Some(json) => sqlx::query(
+                    "INSERT INTO shareplay_states (channel_id, state, updated_at) VALUES (?, ?, ?)
+                     ON CONFLICT(channel_id) DO UPDATE SET state = excluded.state, updated_at = excluded.updated_at",
+                )
+                .bind(&channel_id)
+                .bind(json)
+                .bind(Utc::now())
+                .execute(&db.0)
+                .await,
+                None => sqlx::query("DELETE FROM shareplay_states WHERE channel_id = ?")
+                    .bind(&channel_id)
+                    .execute(&db.0)
+                    .await,
+            };
+            if let Err(e) = res {
+                log::warn!("Failed to save SharePlay state of channel {}: {}", channel_id, e);
             }
         }
+    });
+    tx
+}
+
+/// A track that was played, for `shareplay_history`.
+pub struct PlayedTrack {
+    pub channel_id: String,
+    pub item: QueueItem,
+}
+
+/// A track counts as played after this long, or half of it if it's shorter.
+const HISTORY_MIN_SECS: f64 = 30.0;
+
+impl PlayedTrack {
+    /// `now_playing` from before playback moved on, if it played long enough to count.
+    pub fn from_now_playing(channel_id: &str, now_playing: Option<(QueueItem, f64)>) -> Option<Self> {
+        let (item, position) = now_playing?;
+        let needed = HISTORY_MIN_SECS.min(item.duration_seconds as f64 / 2.0);
+        (position >= needed).then(|| Self {
+            channel_id: channel_id.to_string(),
+            item,
+        })
     }
-    None
 }
 
-/// Delete all downloaded files for a SharePlay state
-pub fn cleanup_channel_files(state: &SharePlayState) {
-    let temp_dir = std::path::PathBuf::from("temp");
-    for item in &state.queue {
-        if let Some(file_path) = &item.file_path {
-            if let Err(e) = std::fs::remove_file(file_path) {
-                log::warn!("Failed to delete SharePlay file {}: {}", file_path, e);
-            } else {
-                log::info!("Deleted SharePlay file: {}", file_path);
+/// Log played tracks in `shareplay_history`, in order.
+pub fn spawn_history_writer(db: Db) -> tokio::sync::mpsc::UnboundedSender<PlayedTrack> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PlayedTrack>();
+    tokio::spawn(async move {
+        while let Some(PlayedTrack { channel_id, item }) = rx.recv().await {
+            let res = sqlx::query(
+                "INSERT INTO shareplay_history (id, channel_id, url, title, duration_seconds, added_by, played_at, video)
+                 SELECT ?, ?, ?, ?, ?, (SELECT id FROM users WHERE id = ?), ?, ?",
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(&channel_id)
+            .bind(&item.url)
+            .bind(&item.title)
+            .bind(item.duration_seconds as i64)
+            .bind(&item.added_by)
+            .bind(Utc::now())
+            .bind(item.video)
+            .execute(&db.0)
+            .await;
+            if let Err(e) = res {
+                log::warn!("Failed to log SharePlay history of channel {}: {}", channel_id, e);
             }
         }
-        if let Some(thumb) = &item.thumbnail_path {
-            let _ = std::fs::remove_file(thumb);
-        }
-        // Also try to delete by ID pattern (for partially downloaded files or if file_path was wrong)
-        if temp_dir.exists() {
-            if let Ok(entries) = std::fs::read_dir(&temp_dir) {
-                for entry in entries.flatten() {
-                    let name = entry.file_name().to_string_lossy().to_string();
-                    if name.starts_with(&item.id) {
-                        let _ = std::fs::remove_file(entry.path());
-                    }
+    });
+    tx
+}
+
+/// Load the SharePlay queues saved before the last shutdown and check their files.
+pub async fn load_states(db: &Db) -> Result<HashMap<String, SharePlayState>, ApiError> {
+    let rows = sqlx::query("SELECT channel_id, state FROM shareplay_states")
+        .fetch_all(&db.0)
+        .await?;
+    let mut states = HashMap::new();
+    for r in rows {
+        let channel_id: String = r.get("channel_id");
+        match serde_json::from_str::<SharePlayState>(r.get("state")) {
+            Ok(mut state) => {
+                // States are saved paused; one saved playing by an older version can't
+                // tell playback from downtime, so it resumes where it was last saved
+                if state.status == "playing" {
+                    state.status = "paused".to_string();
+                    state.start_time = None;
                 }
+                state.verify_files();
+                states.insert(channel_id, state);
             }
+            Err(e) => log::warn!("Dropping unreadable SharePlay state of channel {}: {}", channel_id, e),
         }
     }
+    Ok(states)
+}
+
+/// Delete files in `temp_dir` that no restored queue item still uses: songs of queues
+/// that are gone, and partial downloads that will be fetched again. The cache
+/// directory looks after itself.
+pub fn remove_orphaned_files(
+    temp_dir: &Path,
+    states: &HashMap<String, SharePlayState>,
+) -> std::io::Result<usize> {
+    if !temp_dir.exists() {
+        return Ok(0);
+    }
+    let kept: std::collections::HashSet<std::path::PathBuf> = states
+        .values()
+        .flat_map(|s| s.queue.iter())
+        .flat_map(|i| [i.file_path.as_ref(), i.thumbnail_path.as_ref()])
+        .flatten()
+        .map(std::path::PathBuf::from)
+        .collect();
+    let mut removed = 0;
+    for entry in std::fs::read_dir(temp_dir)?.flatten() {
+        let path = entry.path();
+        if !kept.contains(&path) && std::fs::remove_file(&path).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A queue of songs named "0", "1", ... playing song `current`.
+    fn queue(len: usize, current: usize) -> SharePlayState {
+        let mut state = SharePlayState::new();
+        for i in 0..len {
+            state.add_item(i.to_string(), "user", false);
+        }
+        state.set_track(current);
+        state
+    }
+
+    fn urls(state: &SharePlayState) -> Vec<&str> {
+        state.queue.iter().map(|i| i.url.as_str()).collect()
+    }
+
+    fn current(state: &SharePlayState) -> Option<&str> {
+        state
+            .current_index
+            .and_then(|i| state.queue.get(i))
+            .map(|i| i.url.as_str())
+    }
+
+    #[test]
+    fn move_item_keeps_the_current_track() {
+        let mut state = queue(5, 2);
+        state.move_item(0, 4);
+        assert_eq!(urls(&state), ["1", "2", "3", "4", "0"]);
+        assert_eq!(current(&state), Some("2"));
+
+        state.move_item(4, 0);
+        assert_eq!(urls(&state), ["0", "1", "2", "3", "4"]);
+        assert_eq!(current(&state), Some("2"));
+
+        state.move_item(2, 4);
+        assert_eq!(urls(&state), ["0", "1", "3", "4", "2"]);
+        assert_eq!(state.current_index, Some(4));
+
+        // Out of range or in place: nothing happens
+        state.move_item(7, 0);
+        state.move_item(1, 1);
+        assert_eq!(urls(&state), ["0", "1", "3", "4", "2"]);
+    }
+
+    #[test]
+    fn play_next_goes_after_the_current_track() {
+        let mut state = queue(3, 1);
+        let id = state.play_next("x".into(), "user", true);
+        assert_eq!(urls(&state), ["0", "1", "x", "2"]);
+        assert_eq!(current(&state), Some("1"));
+        assert!(state.queue[2].video);
+        assert_eq!(state.queue[2].id, id);
+
+        let mut empty = SharePlayState::new();
+        empty.play_next("x".into(), "user", false);
+        assert_eq!(urls(&empty), ["x"]);
+        assert_eq!(empty.current_index, Some(0));
+    }
+
+    #[test]
+    fn clear_upcoming_keeps_played_songs() {
+        let mut state = queue(5, 1);
+        assert_eq!(state.upcoming().len(), 3);
+        state.clear_upcoming();
+        assert_eq!(urls(&state), ["0", "1"]);
+        assert_eq!(current(&state), Some("1"));
+        assert_eq!(state.status, "playing");
+    }
+
+    #[test]
+    fn remove_item_adjusts_the_current_track() {
+        let mut state = queue(4, 2);
+        state.remove_item(0);
+        assert_eq!(urls(&state), ["1", "2", "3"]);
+        assert_eq!(current(&state), Some("2"));
+
+        state.remove_item(2);
+        assert_eq!(current(&state), Some("2"));
+
+        // The current track goes: the next one slides into its place, or the one
+        // before it at the end of the queue
+        state.remove_item(1);
+        assert_eq!(urls(&state), ["1"]);
+        assert_eq!(current(&state), Some("1"));
+        assert_eq!(state.current_position_secs, 0.0);
+
+        state.remove_item(0);
+        assert!(state.queue.is_empty());
+        assert_eq!(state.current_index, None);
+        assert_eq!(state.status, "paused");
+
+        state.remove_item(3); // Out of range
+    }
+
+    fn entries(urls: &[&str]) -> Vec<PlaylistEntry> {
+        urls.iter()
+            .map(|u| PlaylistEntry {
+                url: u.to_string(),
+                title: u.to_string(),
+                duration_seconds: 60,
+                video: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn playlist_replaces_its_placeholder() {
+        let mut state = queue(2, 1);
+        let placeholder = state.add_item("playlist".into(), "dj", true);
+        state.add_item("after".into(), "user", false);
+        state.insert_playlist(Some(&placeholder), None, entries(&["a", "b"]));
+        assert_eq!(urls(&state), ["0", "1", "a", "b", "after"]);
+        assert_eq!(current(&state), Some("1"));
+        // Queued by the same user, the same way as the placeholder
+        assert!(state.queue[2..4].iter().all(|i| i.video && i.added_by.as_deref() == Some("dj")));
+        assert!(!state.queue[4].video);
+
+        // Before the current track, which keeps playing
+        let mut state = queue(3, 2);
+        let placeholder = state.queue[0].id.clone();
+        state.insert_playlist(Some(&placeholder), None, entries(&["a", "b", "c"]));
+        assert_eq!(urls(&state), ["a", "b", "c", "1", "2"]);
+        assert_eq!(current(&state), Some("2"));
+
+        // The current track itself: its first song takes over
+        let mut state = queue(3, 1);
+        let placeholder = state.queue[1].id.clone();
+        state.insert_playlist(Some(&placeholder), None, entries(&["a", "b"]));
+        assert_eq!(urls(&state), ["0", "a", "b", "2"]);
+        assert_eq!(current(&state), Some("a"));
+    }
+
+    #[test]
+    fn loaded_playlist_is_appended() {
+        let mut state = queue(1, 0);
+        let mut list = entries(&["a", "b"]);
+        list[1].video = true;
+        state.insert_playlist(None, Some("loader".into()), list);
+        assert_eq!(urls(&state), ["0", "a", "b"]);
+        assert_eq!(current(&state), Some("0"));
+        assert_eq!(state.queue[1].added_by.as_deref(), Some("loader"));
+        assert!(!state.queue[1].video && state.queue[2].video);
+
+        let mut empty = SharePlayState::new();
+        empty.insert_playlist(None, Some("loader".into()), entries(&["a"]));
+        assert_eq!(empty.current_index, Some(0));
+    }
+
+    #[test]
+    fn saved_state_is_paused_where_it_is() {
+        let mut state = queue(1, 0);
+        state.current_position_secs = 5.0;
+        state.start_time = Some(Utc::now() - chrono::Duration::seconds(10));
+        let saved = state.for_saving();
+        assert_eq!(saved.status, "paused");
+        assert_eq!(saved.start_time, None);
+        assert!((saved.current_position_secs - 15.0).abs() < 1.0);
+        assert_eq!(state.status, "playing");
+    }
 }
@@ -0,0 +1,612 @@
+use crate::audio::Loudness;
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::shareplay::SharePlayState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A downloaded song, ready to be put in a queue.
+#[derive(Debug, Clone)]
+pub struct CachedTrack {
+    pub title: String,
+    pub duration_seconds: u64,
+    pub file_path: String,
+    pub thumbnail_path: Option<String>,
+    pub loudness: Option<Loudness>,
+}
+
+struct Entry {
+    track: CachedTrack,
+    size_bytes: u64,
+    last_used: DateTime<Utc>,
+    /// Queue items currently using the entry; it can only be evicted when empty
+    refs: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// Normalized source URL -> cache key
+    urls: HashMap<String, String>,
+    /// Queue item id -> cache key
+    items: HashMap<String, String>,
+    hits: u64,
+    misses: u64,
+}
+
+enum CacheWrite {
+    Upsert {
+        key: String,
+        track: CachedTrack,
+        size_bytes: u64,
+        last_used: DateTime<Utc>,
+    },
+    Alias {
+        url: String,
+        key: String,
+    },
+    Touch {
+        key: String,
+        last_used: DateTime<Utc>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub in_use: usize,
+    pub size_bytes: u64,
+    pub budget_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// SharePlay downloads keyed by source, so a song queued twice, or in two channels,
+/// is only downloaded once. Unused songs are evicted least recently used first once
+/// the cache is over its disk budget. The index is kept in `shareplay_cache` so it
+/// survives restarts along with the queues.
+pub struct SharePlayCache {
+    /// Downloaded songs live here, named by a hash of their cache key, and are shared
+    /// by every queue item that plays them.
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+    budget_bytes: u64,
+    writes: UnboundedSender<CacheWrite>,
+}
+
+impl SharePlayCache {
+    /// Load the index, drop entries whose files are gone and files no entry owns, and
+    /// count the restored queue items that use each entry.
+    pub async fn load(
+        db: &Db,
+        dir: PathBuf,
+        budget_bytes: u64,
+        states: &HashMap<String, SharePlayState>,
+    ) -> Result<Self, ApiError> {
+        let writes = spawn_writer(db.clone());
+        let mut inner = Inner::default();
+
+        let rows = sqlx::query(
+            "SELECT key, title, duration_seconds, file_path, thumbnail_path, size_bytes, last_used_at,
+                    loudness_lufs, true_peak_db
+             FROM shareplay_cache",
+        )
+        .fetch_all(&db.0)
+        .await?;
+        for r in rows {
+            let key: String = r.get("key");
+            let file_path: String = r.get("file_path");
+            if !Path::new(&file_path).exists() {
+                let _ = writes.send(CacheWrite::Delete { key });
+                continue;
+            }
+            let thumbnail_path: Option<String> = r.get("thumbnail_path");
+            let loudness_lufs: Option<f64> = r.get("loudness_lufs");
+            let true_peak_db: Option<f64> = r.get("true_peak_db");
+            let entry = Entry {
+                track: CachedTrack {
+                    title: r.get("title"),
+                    duration_seconds: r.get::<i64, _>("duration_seconds") as u64,
+                    file_path,
+                    thumbnail_path: thumbnail_path.filter(|p| Path::new(p).exists()),
+                    loudness: loudness_lufs.zip(true_peak_db).map(|(i, tp)| Loudness {
+                        integrated_lufs: i,
+                        true_peak_db: tp,
+                    }),
+                },
+                size_bytes: r.get::<i64, _>("size_bytes") as u64,
+                last_used: r.get("last_used_at"),
+                refs: HashSet::new(),
+            };
+            inner.entries.insert(key, entry);
+        }
+        for r in sqlx::query("SELECT url, key FROM shareplay_cache_urls")
+            .fetch_all(&db.0)
+            .await?
+        {
+            let key: String = r.get("key");
+            if inner.entries.contains_key(&key) {
+                inner.urls.insert(r.get("url"), key);
+            }
+        }
+
+        let by_path: HashMap<String, String> = inner
+            .entries
+            .iter()
+            .map(|(k, e)| (e.track.file_path.clone(), k.clone()))
+            .collect();
+        for item in states.values().flat_map(|s| s.queue.iter()) {
+            if let Some(key) = item.file_path.as_ref().and_then(|p| by_path.get(p)) {
+                if let Some(entry) = inner.entries.get_mut(key) {
+                    entry.refs.insert(item.id.clone());
+                }
+                inner.items.insert(item.id.clone(), key.clone());
+            }
+        }
+
+        remove_unowned_files(&dir, &inner);
+        let cache = SharePlayCache {
+            dir,
+            inner: Mutex::new(inner),
+            budget_bytes,
+            writes,
+        };
+        cache.evict(&mut cache.inner.lock().unwrap());
+        Ok(cache)
+    }
+
+    /// Hand out the cached copy of `url` to `item_id`, if there is one.
+    pub fn acquire_url(&self, url: &str, video: bool, item_id: &str) -> Option<CachedTrack> {
+        let url = url_key(url, video)?;
+        let mut inner = self.inner.lock().unwrap();
+        let key = inner.urls.get(&url)?.clone();
+        self.acquire_locked(&mut inner, &key, item_id)
+    }
+
+    /// Hand out the cached copy of `key` to `item_id`, if there is one, and remember
+    /// that `url` leads to it.
+    pub fn acquire(
+        &self,
+        key: &str,
+        url: Option<&str>,
+        video: bool,
+        item_id: &str,
+    ) -> Option<CachedTrack> {
+        let key = entry_key(key, video);
+        let mut inner = self.inner.lock().unwrap();
+        let track = self.acquire_locked(&mut inner, &key, item_id)?;
+        self.alias(&mut inner, url, video, &key);
+        Some(track)
+    }
+
+    fn acquire_locked(&self, inner: &mut Inner, key: &str, item_id: &str) -> Option<CachedTrack> {
+        let now = Utc::now();
+        let entry = inner.entries.get_mut(key)?;
+        entry.refs.insert(item_id.to_string());
+        entry.last_used = now;
+        let track = entry.track.clone();
+        inner.items.insert(item_id.to_string(), key.to_string());
+        inner.hits += 1;
+        let _ = self.writes.send(CacheWrite::Touch {
+            key: key.to_string(),
+            last_used: now,
+        });
+        Some(track)
+    }
+
+    fn alias(&self, inner: &mut Inner, url: Option<&str>, video: bool, key: &str) {
+        if let Some(url) = url.and_then(|url| url_key(url, video))
+            && inner.urls.get(&url).map(String::as_str) != Some(key)
+        {
+            inner.urls.insert(url.clone(), key.to_string());
+            let _ = self.writes.send(CacheWrite::Alias {
+                url,
+                key: key.to_string(),
+            });
+        }
+    }
+
+    /// Move a fresh download of `key` into the cache on behalf of `item_id` and return
+    /// where it ended up. If another download of the same song won the race, this one
+    /// is thrown away in favour of it.
+    pub fn insert(
+        &self,
+        key: &str,
+        url: Option<&str>,
+        video: bool,
+        item_id: &str,
+        download: CachedTrack,
+    ) -> CachedTrack {
+        let key = &entry_key(key, video);
+        let mut inner = self.inner.lock().unwrap();
+        inner.misses += 1;
+        if inner.entries.contains_key(key) {
+            let _ = std::fs::remove_file(&download.file_path);
+            if let Some(thumb) = &download.thumbnail_path {
+                let _ = std::fs::remove_file(thumb);
+            }
+            inner.misses -= 1;
+            let track = self.acquire_locked(&mut inner, key, item_id);
+            self.alias(&mut inner, url, video, key);
+            return track.unwrap_or(download);
+        }
+
+        if let Err(e) = std::fs::create_dir_all(&self.dir) {
+            log::warn!("Failed to create SharePlay cache directory: {}", e);
+            return download;
+        }
+        let stem = file_stem(key);
+        let ext = Path::new(&download.file_path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("bin");
+        let file_path = self.path(&format!("{}.{}", stem, ext));
+        if let Err(e) = std::fs::rename(&download.file_path, &file_path) {
+            log::warn!("Failed to move {} into the SharePlay cache: {}", download.file_path, e);
+            return download;
+        }
+        let thumbnail_path = download.thumbnail_path.as_ref().and_then(|thumb| {
+            let dest = self.path(&format!("{}_thumb.jpg", stem));
+            std::fs::rename(thumb, &dest).ok().map(|_| dest)
+        });
+        let size_bytes = [Some(&file_path), thumbnail_path.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|p| std::fs::metadata(p).ok())
+            .map(|m| m.len())
+            .sum();
+
+        let track = CachedTrack {
+            title: download.title,
+            duration_seconds: download.duration_seconds,
+            file_path,
+            thumbnail_path,
+            loudness: download.loudness,
+        };
+        let now = Utc::now();
+        inner.entries.insert(
+            key.to_string(),
+            Entry {
+                track: track.clone(),
+                size_bytes,
+                last_used: now,
+                refs: HashSet::from([item_id.to_string()]),
+            },
+        );
+        inner.items.insert(item_id.to_string(), key.to_string());
+        let _ = self.writes.send(CacheWrite::Upsert {
+            key: key.to_string(),
+            track: track.clone(),
+            size_bytes,
+            last_used: now,
+        });
+        self.alias(&mut inner, url, video, key);
+        self.evict(&mut inner);
+        track
+    }
+
+    /// Count a file made from the cached song at `file_path`, such as a low-bitrate
+    /// copy, against the budget: the entry's size becomes that of all its files.
+    pub fn count_derived_file(&self, file_path: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some((key, entry)) = inner
+            .entries
+            .iter_mut()
+            .find(|(_, e)| e.track.file_path == file_path)
+        else {
+            return;
+        };
+        let stem = file_stem(key);
+        let Ok(files) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        entry.size_bytes = files
+            .flatten()
+            .filter(|f| f.file_name().to_string_lossy().starts_with(&stem))
+            .filter_map(|f| f.metadata().ok())
+            .map(|m| m.len())
+            .sum();
+        let _ = self.writes.send(CacheWrite::Upsert {
+            key: key.clone(),
+            track: entry.track.clone(),
+            size_bytes: entry.size_bytes,
+            last_used: entry.last_used,
+        });
+        self.evict(&mut inner);
+    }
+
+    /// A queue item stopped using its song, e.g. it was removed or its queue was cleared.
+    pub fn release(&self, item_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(key) = inner.items.remove(item_id) else {
+            return;
+        };
+        if let Some(entry) = inner.entries.get_mut(&key) {
+            entry.refs.remove(item_id);
+        }
+        self.evict(&mut inner);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            entries: inner.entries.len(),
+            in_use: inner.entries.values().filter(|e| !e.refs.is_empty()).count(),
+            size_bytes: inner.entries.values().map(|e| e.size_bytes).sum(),
+            budget_bytes: self.budget_bytes,
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+
+    /// Drop unused songs, least recently used first, until the cache fits its budget.
+    /// Songs in a queue are kept even if that leaves it over budget.
+    /// Whether `path` is a song or thumbnail owned by the cache, which queue items must
+    /// not delete themselves.
+    pub fn is_cached_path(&self, path: &str) -> bool {
+        Path::new(path).starts_with(&self.dir)
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().to_string()
+    }
+
+    fn evict(&self, inner: &mut Inner) {
+        let mut total: u64 = inner.entries.values().map(|e| e.size_bytes).sum();
+        while total > self.budget_bytes {
+            let Some(key) = inner
+                .entries
+                .iter()
+                .filter(|(_, e)| e.refs.is_empty())
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            let entry = inner.entries.remove(&key).expect("key was just found");
+            total -= entry.size_bytes;
+            inner.urls.retain(|_, k| *k != key);
+            remove_entry_files(&self.dir, &key);
+            log::info!("Evicted SharePlay cache entry {} ({})", key, entry.track.title);
+            let _ = self.writes.send(CacheWrite::Delete { key });
+        }
+    }
+}
+
+/// Watch-party videos are cached apart from the audio-only downloads of the same source.
+fn entry_key(key: &str, video: bool) -> String {
+    if video {
+        format!("video:{}", key)
+    } else {
+        key.to_string()
+    }
+}
+
+/// `normalize_url`, in the namespace of `entry_key`.
+fn url_key(url: &str, video: bool) -> Option<String> {
+    let url = normalize_url(url)?;
+    Some(if video { format!("video:{}", url) } else { url })
+}
+
+/// Cache files are named after a hash of the key, which may hold any characters.
+fn file_stem(key: &str) -> String {
+    hex::encode(&Sha256::digest(key.as_bytes())[..16])
+}
+
+/// Delete the song, its thumbnail and any transcoded copies.
+fn remove_entry_files(dir: &Path, key: &str) {
+    let stem = file_stem(key);
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(&stem) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn remove_unowned_files(dir: &Path, inner: &Inner) {
+    let stems: HashSet<String> = inner.entries.keys().map(|k| file_stem(k)).collect();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let owned = name.get(..32).is_some_and(|stem| stems.contains(stem));
+        if !owned {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Cache key for a download, from the extractor and video id yt-dlp reports (e.g.
+/// `Youtube:dQw4w9WgXcQ`), falling back to the URL.
+pub fn cache_key(info: &serde_json::Value, url: &str) -> String {
+    match (info["extractor_key"].as_str(), info["id"].as_str()) {
+        (Some(extractor), Some(id)) => format!("{}:{}", extractor, id),
+        _ => format!("url:{}", normalize_url(url).unwrap_or_else(|| url.to_string())),
+    }
+}
+
+/// Tracking parameters that don't change what a URL points to.
+const IGNORED_PARAMS: [&str; 6] = ["si", "feature", "pp", "t", "start_radio", "index"];
+
+/// Canonical form of a song URL, so different links to the same video share a cache
+/// entry. YouTube links of any shape become `https://www.youtube.com/watch?v=ID`.
+/// `None` for search terms.
+pub fn normalize_url(input: &str) -> Option<String> {
+    let input = input.trim();
+    let with_scheme = if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("https://{}", input)
+    };
+    let mut url = url::Url::parse(&with_scheme).ok()?;
+    let host = url.host_str()?.to_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("m."))
+        .or_else(|| host.strip_prefix("music."))
+        .unwrap_or(&host)
+        .to_string();
+    if !host.contains('.') {
+        return None;
+    }
+
+    let youtube_id = match host.as_str() {
+        "youtu.be" => url.path_segments().and_then(|mut s| s.next()).map(str::to_string),
+        "youtube.com" => match url.path() {
+            "/watch" => url
+                .query_pairs()
+                .find(|(k, _)| k == "v")
+                .map(|(_, v)| v.into_owned()),
+            path => ["/shorts/", "/embed/", "/live/"]
+                .iter()
+                .find_map(|p| path.strip_prefix(p))
+                .map(|id| id.trim_end_matches('/').to_string()),
+        },
+        _ => None,
+    };
+    if let Some(id) = youtube_id.filter(|id| !id.is_empty()) {
+        return Some(format!("https://www.youtube.com/watch?v={}", id));
+    }
+
+    let mut params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !k.starts_with("utm_") && !IGNORED_PARAMS.contains(&k.as_ref()))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    params.sort();
+    url.set_fragment(None);
+    url.set_query(None);
+    if !params.is_empty() {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    let _ = url.set_scheme("https");
+    let _ = url.set_host(Some(&host));
+    Some(url.to_string().trim_end_matches('/').to_string())
+}
+
+fn spawn_writer(db: Db) -> UnboundedSender<CacheWrite> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<CacheWrite>();
+    tokio::spawn(async move {
+        while let Some(write) = rx.recv().await {
+            let res = match write {
+                CacheWrite::Upsert {
+                    key,
+                    track,
+                    size_bytes,
+                    last_used,
+                } => sqlx::query(
+                    "INSERT INTO shareplay_cache (key, title, duration_seconds, file_path, thumbnail_path, size_bytes,
+                       loudness_lufs, true_peak_db, last_used_at, created_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                     ON CONFLICT(key) DO UPDATE SET
+                       title = excluded.title, duration_seconds = excluded.duration_seconds,
+                       file_path = excluded.file_path, thumbnail_path = excluded.thumbnail_path,
+                       size_bytes = excluded.size_bytes, loudness_lufs = excluded.loudness_lufs,
+                       true_peak_db = excluded.true_peak_db, last_used_at = excluded.last_used_at",
+                )
+                .bind(&key)
+                .bind(&track.title)
+                .bind(track.duration_seconds as i64)
+                .bind(&track.file_path)
+                .bind(&track.thumbnail_path)
+                .bind(size_bytes as i64)
+                .bind(track.loudness.map(|l| l.integrated_lufs))
+                .bind(track.loudness.map(|l| l.true_peak_db))
+                .bind(last_used)
+                .bind(Utc::now())
+                .execute(&db.0)
+                .await,
+                CacheWrite::Alias { url, key } => sqlx::query(
+                    "INSERT INTO shareplay_cache_urls (url, key) VALUES (?, ?)
+                     ON CONFLICT(url) DO UPDATE SET key = excluded.key",
+                )
+                .bind(&url)
+                .bind(&key)
+                .execute(&db.0)
+                .await,
+                CacheWrite::Touch { key, last_used } => {
+                    sqlx::query("UPDATE shareplay_cache SET last_used_at = ? WHERE key = ?")
+                        .bind(last_used)
+                        .bind(&key)
+                        .execute(&db.0)
+                        .await
+                }
+                CacheWrite::Delete { key } => {
+                    sqlx::query("DELETE FROM shareplay_cache WHERE key = ?")
+                        .bind(&key)
+                        .execute(&db.0)
+                        .await
+                }
+            };
+            if let Err(e) = res {
+                log::warn!("Failed to update SharePlay cache index: {}", e);
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn youtube_links_share_a_key() {
+        let canonical = Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string());
+        for link in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "youtube.com/watch?v=dQw4w9WgXcQ&t=42&si=abc",
+            "https://m.youtube.com/watch?feature=share&v=dQw4w9WgXcQ",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?si=xyz",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ/",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ",
+            "  https://www.youtube.com/live/dQw4w9WgXcQ  ",
+        ] {
+            assert_eq!(normalize_url(link), canonical, "{}", link);
+        }
+    }
+
+    #[test]
+    fn other_links_drop_tracking() {
+        assert_eq!(
+            normalize_url("HTTP://WWW.Example.com/song.mp3?utm_source=x&b=2&a=1#t=10").as_deref(),
+            Some("https://example.com/song.mp3?a=1&b=2")
+        );
+        assert_eq!(
+            normalize_url("https://soundcloud.com/artist/track/").as_deref(),
+            Some("https://soundcloud.com/artist/track")
+        );
+    }
+
+    #[test]
+    fn search_terms_are_not_urls() {
+        assert_eq!(normalize_url("never gonna give you up"), None);
+        assert_eq!(normalize_url("rickroll"), None);
+        assert_eq!(normalize_url("http://localhost/song.mp3"), None);
+    }
+
+    #[test]
+    fn videos_are_cached_apart() {
+        assert_eq!(entry_key("Youtube:x", false), "Youtube:x");
+        assert_eq!(entry_key("Youtube:x", true), "video:Youtube:x");
+        assert_eq!(
+            url_key("youtu.be/x", true).as_deref(),
+            Some("video:https://www.youtube.com/watch?v=x")
+        );
+        assert_ne!(file_stem("Youtube:x"), file_stem("video:Youtube:x"));
+    }
+}
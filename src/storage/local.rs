@@ -0,0 +1,91 @@
+use super::{ServeMeta, Storage};
+use crate::errors::ApiError;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use std::path::{Path, PathBuf};
+
+/// Objects stored as plain files under `uploads_dir`.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    async fn ensure_parent(path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for LocalStorage {
+    async fn put_file(&self, key: &str, path: &Path) -> std::io::Result<()> {
+        let dest = self.path(key);
+        Self::ensure_parent(&dest).await?;
+        // Temp files live in uploads_dir, so this is normally a rename on the same filesystem
+        if tokio::fs::rename(path, &dest).await.is_err() {
+            tokio::fs::copy(path, &dest).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn put_bytes(&self, key: &str, data: Vec<u8>) -> std::io::Result<()> {
+        let dest = self.path(key);
+        Self::ensure_parent(&dest).await?;
+        tokio::fs::write(dest, data).await
+    }
+
+    async fn get_file(&self, key: &str, dest: &Path) -> std::io::Result<()> {
+        tokio::fs::copy(self.path(key), dest).await.map(|_| ())
+    }
+
+    async fn exists(&self, key: &str) -> std::io::Result<bool> {
+        tokio::fs::try_exists(self.path(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn serve<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        key: &'a str,
+        meta: &'a ServeMeta<'a>,
+    ) -> LocalBoxFuture<'a, Result<HttpResponse, ApiError>> {
+        Box::pin(async move {
+            let content_type: actix_web::mime::Mime = meta
+                .content_type
+                .parse()
+                .unwrap_or(actix_web::mime::APPLICATION_OCTET_STREAM);
+            // NamedFile handles Range, If-None-Match and If-Modified-Since for us
+            let named = actix_files::NamedFile::open_async(self.path(key))
+                .await
+                .map_err(|_| ApiError::NotFound)?
+                .set_content_type(content_type)
+                .use_last_modified(true)
+                .prefer_utf8(true)
+                .set_content_disposition(ContentDisposition {
+                    disposition: DispositionType::Inline,
+                    parameters: vec![DispositionParam::Filename(meta.filename.to_string())],
+                });
+            Ok(named.into_response(req))
+        })
+    }
+}
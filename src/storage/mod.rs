@@ -0,0 +1,56 @@
+mod local;
+mod s3;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+use crate::config::Config;
+use crate::errors::ApiError;
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use std::path::Path;
+use std::sync::Arc;
+
+/// How an object should be presented when served to a client.
+pub struct ServeMeta<'a> {
+    pub content_type: &'a str,
+    pub filename: &'a str,
+}
+
+/// Where uploaded files, emojis and avatars live. Keys are `/`-separated paths such
+/// as `blobs/ab/abcdef...` or `emojis/{id}.png`.
+///
+/// Uploads are always received into a local temp file under `uploads_dir` first (so
+/// they can be hashed, sniffed and resumed), then handed to `put_file`.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the local file at `path` under `key`. The local file is consumed.
+    async fn put_file(&self, key: &str, path: &Path) -> std::io::Result<()>;
+    async fn put_bytes(&self, key: &str, data: Vec<u8>) -> std::io::Result<()>;
+    /// Copy the object under `key` to the local file `dest`.
+    async fn get_file(&self, key: &str, dest: &Path) -> std::io::Result<()>;
+    async fn exists(&self, key: &str) -> std::io::Result<bool>;
+    /// Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> std::io::Result<()>;
+    /// Answer a GET/HEAD for the object, honouring Range and conditional headers.
+    /// Backends may redirect the client to the object instead of streaming it.
+    /// (Not `Send`, since it borrows the request, so it can't go through `async_trait`.)
+    fn serve<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        key: &'a str,
+        meta: &'a ServeMeta<'a>,
+    ) -> LocalBoxFuture<'a, Result<HttpResponse, ApiError>>;
+}
+
+/// Pick the backend from the config: S3 when an `[s3]` section is present, otherwise
+/// the local `uploads_dir`.
+pub fn from_config(cfg: &Config) -> Arc<dyn Storage> {
+    match &cfg.s3 {
+        Some(s3) => {
+            log::info!("Storage: S3 bucket {} at {}", s3.bucket, s3.endpoint);
+            Arc::new(S3Storage::new(s3.clone()))
+        }
+        None => Arc::new(LocalStorage::new(&cfg.uploads_dir)),
+    }
+}
@@ -0,0 +1,270 @@
+use crate::config::Config;
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::storage::Storage;
+use image::{DynamicImage, GenericImageView, ImageDecoder};
+use sqlx::Row;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Bounding boxes thumbnails are generated for. Only sizes smaller than the
+/// original are generated; clients fall back to the file itself.
+const THUMBNAIL_SIZES: [u32; 3] = [160, 480, 1080];
+const MAX_IMAGE_DIMENSION: u32 = 16384;
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Thumbnail {
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub jpeg: Vec<u8>,
+}
+
+/// What we know about an image or video attachment.
+pub struct MediaInfo {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// Thumbnails are keyed by content hash, next to the blob they were made from.
+pub fn thumbnail_key(sha256: &str, size: u32) -> String {
+    format!("thumbnails/{}/{}/{}.jpg", &sha256[..2], sha256, size)
+}
+
+/// Decode an image, or grab a poster frame from a video with ffmpeg, and build its
+/// thumbnails. Returns `None` for other files and for anything that fails to decode;
+/// the upload itself goes ahead either way.
+pub async fn analyze(path: &Path, mime: Option<&str>) -> Option<MediaInfo> {
+    let mime = mime?;
+    let result = if mime.starts_with("image/") {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let reader = image::ImageReader::open(&path)?.with_guessed_format()?;
+            build(decode(reader)?, false)
+        })
+        .await
+    } else if mime.starts_with("video/") {
+        let frame = poster_frame(path).await?;
+        tokio::task::spawn_blocking(move || {
+            let reader =
+                image::ImageReader::new(std::io::Cursor::new(frame)).with_guessed_format()?;
+            build(decode(reader)?, true)
+        })
+        .await
+    } else {
+        return None;
+    };
+    match result {
+        Ok(Ok(info)) => Some(info),
+        Ok(Err(e)) => {
+            log::debug!("No thumbnails for {}: {}", path.display(), e);
+            None
+        }
+        Err(e) => {
+            log::warn!("Thumbnail task for {} failed: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Decoded and turned upright, in case the EXIF orientation wasn't applied on upload.
+fn decode<R: std::io::BufRead + std::io::Seek>(
+    mut reader: image::ImageReader<R>,
+) -> image::ImageResult<DynamicImage> {
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// A poster frame is always worth having, so videos smaller than every size still
+/// get one thumbnail at their own size.
+fn build(img: DynamicImage, is_poster: bool) -> image::ImageResult<MediaInfo> {
+    let (width, height) = img.dimensions();
+    let mut thumbnails = Vec::new();
+    for size in THUMBNAIL_SIZES {
+        if width.max(height) <= size && !(is_poster && thumbnails.is_empty()) {
+            break;
+        }
+        let thumb = img.thumbnail(size, size);
+        let mut out = std::io::Cursor::new(Vec::new());
+        // JPEG has no alpha channel
+        DynamicImage::ImageRgb8(thumb.to_rgb8()).write_to(&mut out, image::ImageFormat::Jpeg)?;
+        thumbnails.push(Thumbnail {
+            size,
+            width: thumb.width(),
+            height: thumb.height(),
+            jpeg: out.into_inner(),
+        });
+    }
+
+    let tiny = img.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(
+        BLURHASH_COMPONENTS.0,
+        BLURHASH_COMPONENTS.1,
+        tiny.width(),
+        tiny.height(),
+        tiny.as_raw(),
+    )
+    .ok();
+
+    Ok(MediaInfo {
+        width,
+        height,
+        blurhash,
+        thumbnails,
+    })
+}
+
+/// Grab a frame one second in (or the first frame of shorter clips) as PNG.
+/// `None` if ffmpeg isn't installed or can't read the file.
+async fn poster_frame(path: &Path) -> Option<Vec<u8>> {
+    for seek in ["1", "0"] {
+        let output = tokio::time::timeout(
+            FFMPEG_TIMEOUT,
+            tokio::process::Command::new("ffmpeg")
+                .args(["-hide_banner", "-loglevel", "error", "-ss", seek, "-i"])
+                .arg(path)
+                .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "pipe:1"])
+                .stdin(std::process::Stdio::null())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await;
+        match output {
+            Ok(Ok(out)) if out.status.success() && !out.stdout.is_empty() => {
+                return Some(out.stdout);
+            }
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => {
+                log::debug!("ffmpeg unavailable, skipping video poster: {}", e);
+                return None;
+            }
+            Err(_) => {
+                log::warn!("ffmpeg timed out on {}", path.display());
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Dimensions and blurhash of another file with the same content, so re-uploads
+/// don't have to decode it again (its thumbnails already exist).
+pub async fn known_media_info(db: &Db, sha256: &str) -> Result<Option<MediaInfo>, ApiError> {
+    let row = sqlx::query(
+        "SELECT width, height, blurhash FROM files
+         WHERE sha256 = ? AND width IS NOT NULL LIMIT 1",
+    )
+    .bind(sha256)
+    .fetch_optional(&db.0)
+    .await?;
+    Ok(row.map(|r| MediaInfo {
+        width: r.get::<i64, _>("width") as u32,
+        height: r.get::<i64, _>("height") as u32,
+        blurhash: r.get("blurhash"),
+        thumbnails: Vec::new(),
+    }))
+}
+
+/// Upload the thumbnails of a blob. Call while holding its `PendingBlob`, then
+/// record them with `insert_thumbnails` in the transaction that references it.
+/// Returns the ones that were stored.
+pub async fn put_thumbnails<'a>(
+    storage: &dyn Storage,
+    sha256: &str,
+    thumbnails: &'a [Thumbnail],
+) -> Vec<&'a Thumbnail> {
+    let mut stored = Vec::new();
+    for t in thumbnails {
+        let key = thumbnail_key(sha256, t.size);
+        match storage.put_bytes(&key, t.jpeg.clone()).await {
+            Ok(()) => stored.push(t),
+            Err(e) => log::warn!("Storing thumbnail {} failed: {}", key, e),
+        }
+    }
+    stored
+}
+
+pub async fn insert_thumbnails(
+    conn: &mut sqlx::SqliteConnection,
+    sha256: &str,
+    thumbnails: &[&Thumbnail],
+) -> Result<(), ApiError> {
+    for t in thumbnails {
+        sqlx::query(
+            "INSERT OR IGNORE INTO thumbnails (sha256, size, width, height, stored_name)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(sha256)
+        .bind(t.size)
+        .bind(t.width)
+        .bind(t.height)
+        .bind(thumbnail_key(sha256, t.size))
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Delete the thumbnails of a blob that is being garbage collected.
+pub async fn delete_thumbnails(storage: &dyn Storage, db: &Db, sha256: &str) -> Result<(), ApiError> {
+    let rows = sqlx::query("DELETE FROM thumbnails WHERE sha256 = ? RETURNING stored_name")
+        .bind(sha256)
+        .fetch_all(&db.0)
+        .await?;
+    for r in rows {
+        let stored: String = r.get("stored_name");
+        if let Err(e) = storage.delete(&stored).await {
+            log::warn!("Failed to delete thumbnail {}: {}", stored, e);
+        }
+    }
+    Ok(())
+}
+
+/// Batch-load thumbnails for the given files as JSON, smallest first, with signed URLs.
+pub async fn load_thumbnails(
+    cfg: &Config,
+    db: &Db,
+    file_ids: &[String],
+) -> Result<HashMap<String, Vec<serde_json::Value>>, ApiError> {
+    let mut map: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    if file_ids.is_empty() {
+        return Ok(map);
+    }
+    let placeholders = file_ids.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+    let query_str = format!(
+        "SELECT f.id, t.size, t.width, t.height FROM files f
+         INNER JOIN thumbnails t ON t.sha256 = f.sha256
+         WHERE f.id IN ({}) ORDER BY t.size ASC",
+        placeholders
+    );
+    let mut q = sqlx::query(&query_str);
+    for id in file_ids {
+        q = q.bind(id);
+    }
+    for r in q.fetch_all(&db.0).await? {
+        let file_id: String = r.get("id");
+        let size: i64 = r.get("size");
+        let url = crate::routes::files::signed_thumbnail_url(cfg, &file_id, size);
+        map.entry(file_id).or_default().push(serde_json::json!({
+            "size": size,
+            "width": r.get::<i64, _>("width"),
+            "height": r.get::<i64, _>("height"),
+            "url": url,
+        }));
+    }
+    Ok(map)
+}
+
@@ -0,0 +1,867 @@
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::ws::server::{Broadcast, ChatServer};
+use chrono::Utc;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use sqlx::Row;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use url::Url;
+
+const MAX_URLS_PER_MESSAGE: usize = 3;
+const MAX_HTML_BYTES: usize = 512 * 1024;
+const MAX_OEMBED_BYTES: usize = 64 * 1024;
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(8);
+const PREVIEW_IMAGE_SIZE: u32 = 400;
+const CACHE_TTL_OK_HOURS: i64 = 24 * 7;
+const CACHE_TTL_FAILED_HOURS: i64 = 1;
+
+#[derive(Error, Debug)]
+pub enum UnfurlError {
+    #[error("url not allowed")]
+    Blocked,
+    #[error("http error: {0}")]
+    Http(String),
+    #[error("response too large")]
+    TooLarge,
+    #[error("unsupported content type")]
+    UnsupportedContent,
+    #[error("no preview metadata")]
+    NoMetadata,
+}
+
+impl From<reqwest::Error> for UnfurlError {
+    fn from(e: reqwest::Error) -> Self {
+        UnfurlError::Http(e.to_string())
+    }
+}
+
+/// Metadata extracted from a page's OpenGraph/Twitter/oEmbed tags.
+#[derive(Debug, Default, Clone)]
+pub struct PagePreview {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub image_url: Option<Url>,
+}
+
+/// Only accept http(s) URLs whose host is not a literal private/loopback address.
+/// Hostnames are checked at connect time by `PublicOnlyResolver`.
+pub(crate) fn check_url(url: &Url, allow_private: bool) -> Result<(), UnfurlError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(UnfurlError::Blocked);
+    }
+    if allow_private {
+        return Ok(());
+    }
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) if !is_public_ip(IpAddr::V4(ip)) => Err(UnfurlError::Blocked),
+        Some(url::Host::Ipv6(ip)) if !is_public_ip(IpAddr::V6(ip)) => Err(UnfurlError::Blocked),
+        Some(url::Host::Domain(d)) if d.eq_ignore_ascii_case("localhost") => {
+            Err(UnfurlError::Blocked)
+        }
+        None => Err(UnfurlError::Blocked),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_unspecified()
+                || v4.is_multicast()
+                || o[0] == 0
+                || o[0] >= 240
+                || (o[0] == 100 && (64..128).contains(&o[1])) // CGNAT / Tailscale
+                || (o[0] == 192 && o[1] == 0 && o[2] == 0)
+                || (o[0] == 198 && (o[1] == 18 || o[1] == 19)))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let seg = v6.segments();
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (seg[0] & 0xfe00) == 0xfc00 // unique local
+                || (seg[0] & 0xffc0) == 0xfe80 // link local
+                || (seg[0] == 0x2001 && seg[1] == 0x0db8))
+        }
+    }
+}
+
+/// DNS resolver that drops private addresses, so a public hostname pointing at
+/// an internal IP (or rebinding to one) can't be used to reach internal services.
+struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|a| is_public_ip(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err("host resolves only to private addresses".into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// HTTP client for link previews with strict timeouts, size caps and SSRF protection.
+/// `allow_private_networks` disables the private-IP checks so previews can be
+/// fetched from a local HTTP server.
+#[derive(Clone)]
+pub struct LinkFetcher {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+/// An HTTP client builder with the link preview SSRF protection: few redirects, none
+/// to a blocked URL, and (unless `allow_private`) no connections to private addresses.
+/// Callers still need to `check_url` the first URL themselves.
+pub(crate) fn guarded_client_builder(allow_private: bool) -> reqwest::ClientBuilder {
+    let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if check_url(attempt.url(), allow_private).is_err() {
+            attempt.error("redirect target not allowed")
+        } else {
+            attempt.follow()
+        }
+    });
+    let builder = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .redirect(redirect)
+        .no_proxy();
+    if allow_private {
+        builder
+    } else {
+        builder.dns_resolver(Arc::new(PublicOnlyResolver))
+    }
+}
+
+impl LinkFetcher {
+    pub fn new(allow_private_networks: bool) -> Self {
+        let allow_private = allow_private_networks;
+        let builder = guarded_client_builder(allow_private)
+            .user_agent("stuffchat-link-preview/1.0")
+            .timeout(REQUEST_TIMEOUT);
+        Self {
+            client: builder.build().expect("failed to build link preview client"),
+            allow_private,
+        }
+    }
+
+    /// GET `url` and return (content type, body). Once `max_bytes` is exceeded the
+    /// body is either cut off there (`truncate`) or the request fails.
+    async fn get_limited(
+        &self,
+        url: &Url,
+        max_bytes: usize,
+        truncate: bool,
+    ) -> Result<(String, Vec<u8>), UnfurlError> {
+        check_url(url, self.allow_private)?;
+        let mut resp = self.client.get(url.clone()).send().await?;
+        if !resp.status().is_success() {
+            return Err(UnfurlError::Http(format!("status {}", resp.status())));
+        }
+        if !truncate
+            && resp
+                .content_length()
+                .map(|l| l as usize > max_bytes)
+                .unwrap_or(false)
+        {
+            return Err(UnfurlError::TooLarge);
+        }
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if body.len() + chunk.len() > max_bytes {
+                if truncate {
+                    body.extend_from_slice(&chunk[..max_bytes - body.len()]);
+                    break;
+                }
+                return Err(UnfurlError::TooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok((content_type, body))
+    }
+
+    /// Fetch a page and extract its preview metadata, following oEmbed discovery
+    /// for anything the page's meta tags didn't provide.
+    pub async fn fetch_page(&self, url: &Url) -> Result<PagePreview, UnfurlError> {
+        // Meta tags live in <head>, so a truncated page is still useful
+        let (content_type, body) = self.get_limited(url, MAX_HTML_BYTES, true).await?;
+        if !content_type.starts_with("text/html") && !content_type.contains("xhtml") {
+            return Err(UnfurlError::UnsupportedContent);
+        }
+        let html = String::from_utf8_lossy(&body);
+        let meta = parse_html_meta(&html);
+
+        let mut preview = PagePreview {
+            title: meta.get("og:title").or(meta.get("twitter:title")).or(meta.get("title")).cloned(),
+            description: meta
+                .get("og:description")
+                .or(meta.get("twitter:description"))
+                .or(meta.get("description"))
+                .cloned(),
+            site_name: meta.get("og:site_name").cloned(),
+            image_url: meta
+                .get("og:image")
+                .or(meta.get("og:image:url"))
+                .or(meta.get("twitter:image"))
+                .and_then(|u| url.join(u).ok()),
+        };
+
+        if let Some(oembed) = meta.get("oembed").and_then(|u| url.join(u).ok())
+            && let Ok((_, body)) = self.get_limited(&oembed, MAX_OEMBED_BYTES, false).await
+            && let Ok(info) = serde_json::from_slice::<serde_json::Value>(&body)
+        {
+            let field = |k: &str| info[k].as_str().map(|s| s.to_string());
+            preview.title = preview.title.or(field("title"));
+            preview.site_name = preview.site_name.or(field("provider_name"));
+            preview.description = preview.description.or(field("author_name"));
+            preview.image_url = preview
+                .image_url
+                .or(field("thumbnail_url").and_then(|u| url.join(&u).ok()));
+        }
+
+        if preview.title.is_none() && preview.description.is_none() && preview.image_url.is_none()
+        {
+            return Err(UnfurlError::NoMetadata);
+        }
+        preview.title = preview.title.map(|s| truncate(&s, 300));
+        preview.description = preview.description.map(|s| truncate(&s, 1000));
+        preview.site_name = preview.site_name.map(|s| truncate(&s, 100));
+        Ok(preview)
+    }
+
+    /// Download and downscale a preview image. Returns (jpeg bytes, width, height).
+    pub async fn fetch_image(&self, url: &Url) -> Result<(Vec<u8>, u32, u32), UnfurlError> {
+        let (content_type, body) = self.get_limited(url, MAX_IMAGE_BYTES, false).await?;
+        if !content_type.starts_with("image/") {
+            return Err(UnfurlError::UnsupportedContent);
+        }
+        tokio::task::spawn_blocking(move || {
+            let mut reader = image::ImageReader::new(std::io::Cursor::new(body))
+                .with_guessed_format()
+                .map_err(|_| UnfurlError::UnsupportedContent)?;
+            let mut limits = image::Limits::default();
+            limits.max_image_width = Some(8192);
+            limits.max_image_height = Some(8192);
+            limits.max_alloc = Some(128 * 1024 * 1024);
+            reader.limits(limits);
+            let img = reader
+                .decode()
+                .map_err(|_| UnfurlError::UnsupportedContent)?;
+            let thumb = img.thumbnail(PREVIEW_IMAGE_SIZE, PREVIEW_IMAGE_SIZE).to_rgb8();
+            let (w, h) = thumb.dimensions();
+            let mut out = std::io::Cursor::new(Vec::new());
+            thumb
+                .write_to(&mut out, image::ImageFormat::Jpeg)
+                .map_err(|_| UnfurlError::UnsupportedContent)?;
+            Ok((out.into_inner(), w, h))
+        })
+        .await
+        .map_err(|_| UnfurlError::UnsupportedContent)?
+    }
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    let s = s.trim();
+    match s.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &s[..idx]),
+        None => s.to_string(),
+    }
+}
+
+/// Extract up to `MAX_URLS_PER_MESSAGE` distinct http(s) URLs from message content.
+pub fn extract_urls(content: &str) -> Vec<Url> {
+    let mut out: Vec<Url> = Vec::new();
+    for word in content.split_whitespace() {
+        let lower = word.to_ascii_lowercase();
+        let Some(start) = lower.find("http://").or(lower.find("https://")) else {
+            continue;
+        };
+        // Drop wrapping punctuation such as "(https://x.y)." or "<https://x.y>"
+        let candidate = word[start..].trim_end_matches(|c: char| {
+            matches!(c, '.' | ',' | ')' | '>' | ']' | '!' | '?' | ';' | ':' | '"' | '\'')
+        });
+        if let Ok(mut url) = Url::parse(candidate) {
+            url.set_fragment(None);
+            if url.host().is_some() && !out.contains(&url) {
+                out.push(url);
+                if out.len() >= MAX_URLS_PER_MESSAGE {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Scan the HTML for <title>, <meta> and the oEmbed discovery <link>.
+/// Keys are lowercased `property`/`name` values; "title" and "oembed" are synthetic.
+fn parse_html_meta(html: &str) -> HashMap<String, String> {
+    let lower = html.to_ascii_lowercase();
+    let mut out = HashMap::new();
+    let mut pos = 0;
+    while let Some(rel) = lower[pos..].find('<') {
+        let start = pos + rel + 1;
+        let Some(end_rel) = find_tag_end(&lower[start..]) else {
+            break;
+        };
+        let end = start + end_rel;
+        let tag = &html[start..end];
+        let tag_lower = &lower[start..end];
+        pos = end + 1;
+
+        if tag_lower.starts_with("/head") || tag_lower.starts_with("body") {
+            break;
+        } else if tag_lower.starts_with("title") && !out.contains_key("title") {
+            if let Some(close) = lower[pos..].find("</title") {
+                out.insert(
+                    "title".to_string(),
+                    decode_entities(html[pos..pos + close].trim()),
+                );
+            }
+        } else if tag_lower.starts_with("meta ") {
+            let attrs = parse_attrs(&tag[5..]);
+            let key = attrs.get("property").or(attrs.get("name"));
+            if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+                let key = key.to_ascii_lowercase();
+                if !content.trim().is_empty() && !out.contains_key(&key) {
+                    out.insert(key, decode_entities(content.trim()));
+                }
+            }
+        } else if tag_lower.starts_with("link ") {
+            let attrs = parse_attrs(&tag[5..]);
+            let is_oembed = attrs
+                .get("type")
+                .map(|t| t.eq_ignore_ascii_case("application/json+oembed"))
+                .unwrap_or(false);
+            if is_oembed && let Some(href) = attrs.get("href") {
+                out.entry("oembed".to_string())
+                    .or_insert_with(|| decode_entities(href));
+            }
+        }
+    }
+    out
+}
+
+/// Find the closing '>' of a tag, ignoring any inside quoted attribute values.
+fn find_tag_end(s: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_attrs(s: &str) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            let (value, remaining) = match after_eq.chars().next() {
+                Some(q @ ('"' | '\'')) => match after_eq[1..].find(q) {
+                    Some(close) => (&after_eq[1..1 + close], &after_eq[close + 2..]),
+                    None => (&after_eq[1..], ""),
+                },
+                _ => {
+                    let end = after_eq
+                        .find(char::is_whitespace)
+                        .unwrap_or(after_eq.len());
+                    (&after_eq[..end], &after_eq[end..])
+                }
+            };
+            out.insert(name, value.to_string());
+            rest = remaining.trim_start();
+        } else if name.is_empty() {
+            // Skip stray characters such as a trailing '/'
+            rest = rest.get(1..).unwrap_or("").trim_start();
+        }
+    }
+    out
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Return the cached preview id for `url`, fetching (or refreshing) it if needed.
+/// Returns `None` when the page has no usable preview.
+async fn get_or_fetch_preview(
+    fetcher: &LinkFetcher,
+    db: &Db,
+    uploads_dir: &str,
+    url: &Url,
+) -> Result<Option<String>, ApiError> {
+    let now = Utc::now();
+    let cached = sqlx::query(
+        "SELECT id, status, image_stored_name, fetched_at FROM link_previews WHERE url = ?",
+    )
+    .bind(url.as_str())
+    .fetch_optional(&db.0)
+    .await?;
+    if let Some(row) = &cached {
+        let status: String = row.get("status");
+        let fetched_at: chrono::DateTime<Utc> = row.get("fetched_at");
+        let ttl = if status == "ok" {
+            CACHE_TTL_OK_HOURS
+        } else {
+            CACHE_TTL_FAILED_HOURS
+        };
+        if now - fetched_at < chrono::Duration::hours(ttl) {
+            return Ok((status == "ok").then(|| row.get("id")));
+        }
+    }
+
+    let id = cached
+        .as_ref()
+        .map(|r| r.get::<String, _>("id"))
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let old_image: Option<String> = cached.as_ref().and_then(|r| r.get("image_stored_name"));
+
+    let preview = match fetcher.fetch_page(url).await {
+        Ok(p) => Some(p),
+        Err(e) => {
+            log::info!("Link preview failed for {}: {}", url, e);
+            None
+        }
+    };
+
+    // Store the preview image under uploads_dir/previews
+    let mut image: Option<(String, u32, u32)> = None;
+    if let Some(image_url) = preview.as_ref().and_then(|p| p.image_url.as_ref()) {
+        match fetcher.fetch_image(image_url).await {
+            Ok((bytes, w, h)) => {
+                let dir = std::path::Path::new(uploads_dir).join("previews");
+                let stored_name = format!("{}.jpg", uuid::Uuid::new_v4());
+                let written = tokio::fs::create_dir_all(&dir).await.is_ok()
+                    && tokio::fs::write(dir.join(&stored_name), &bytes).await.is_ok();
+                if written {
+                    image = Some((stored_name, w, h));
+                } else {
+                    log::warn!("Failed to store link preview image for {}", url);
+                }
+            }
+            Err(e) => log::info!("Link preview image failed for {}: {}", image_url, e),
+        }
+    }
+
+    let status = if preview.is_some() { "ok" } else { "failed" };
+    let preview = preview.unwrap_or_default();
+    sqlx::query(
+        "INSERT INTO link_previews (id, url, status, title, description, site_name, image_stored_name, image_width, image_height, fetched_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(url) DO UPDATE SET status = excluded.status, title = excluded.title, description = excluded.description,
+           site_name = excluded.site_name, image_stored_name = excluded.image_stored_name,
+           image_width = excluded.image_width, image_height = excluded.image_height, fetched_at = excluded.fetched_at",
+    )
+    .bind(&id)
+    .bind(url.as_str())
+    .bind(status)
+    .bind(&preview.title)
+    .bind(&preview.description)
+    .bind(&preview.site_name)
+    .bind(image.as_ref().map(|i| &i.0))
+    .bind(image.as_ref().map(|i| i.1 as i64))
+    .bind(image.as_ref().map(|i| i.2 as i64))
+    .bind(now)
+    .execute(&db.0)
+    .await?;
+
+    if let Some(old) = old_image {
+        let _ = tokio::fs::remove_file(std::path::Path::new(uploads_dir).join("previews").join(old)).await;
+    }
+
+    // A concurrent fetch may have inserted the row first; use whichever id won
+    let row = sqlx::query("SELECT id FROM link_previews WHERE url = ?")
+        .bind(url.as_str())
+        .fetch_one(&db.0)
+        .await?;
+    Ok((status == "ok").then(|| row.get("id")))
+}
+
+/// Fetch previews for `urls` in the background, attach them to the message and
+/// push a `message_embeds_updated` event once done.
+pub fn spawn_unfurl(
+    fetcher: LinkFetcher,
+    db: Db,
+    chat: actix::Addr<ChatServer>,
+    uploads_dir: String,
+    message_id: String,
+    channel_id: String,
+    urls: Vec<Url>,
+) {
+    tokio::spawn(async move {
+        let mut attached = 0;
+        for (position, url) in urls.iter().enumerate() {
+            match get_or_fetch_preview(&fetcher, &db, &uploads_dir, url).await {
+                Ok(Some(preview_id)) => {
+                    let res = sqlx::query(
+                        "INSERT OR IGNORE INTO message_embeds (message_id, preview_id, position) VALUES (?, ?, ?)",
+                    )
+                    .bind(&message_id)
+                    .bind(&preview_id)
+                    .bind(position as i64)
+                    .execute(&db.0)
+                    .await;
+                    if res.is_ok() {
+                        attached += 1;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Link preview storage failed for {}: {}", url, e),
+            }
+        }
+        if attached == 0 {
+            return;
+        }
+
+        let embeds = match load_embeds(&db, std::slice::from_ref(&message_id)).await {
+            Ok(mut m) => m.remove(&message_id).unwrap_or_default(),
+            Err(_) => return,
+        };
+        let payload = serde_json::json!({
+            "type": "message_embeds_updated",
+            "message_id": message_id,
+            "channel_id": channel_id,
+            "embeds": embeds,
+        })
+        .to_string();
+        chat.do_send(Broadcast {
+            channel_id,
+            payload,
+        });
+    });
+}
+
+/// Batch-load link previews for the given messages, keyed by message_id.
+pub async fn load_embeds(
+    db: &Db,
+    message_ids: &[String],
+) -> Result<HashMap<String, Vec<serde_json::Value>>, ApiError> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let placeholders: String = message_ids
+        .iter()
+        .map(|_| "?")
+        .collect::<Vec<_>>()
+        .join(",");
+    let query_str = format!(
+        "SELECT e.message_id, p.id, p.url, p.title, p.description, p.site_name, p.image_stored_name, p.image_width, p.image_height
+         FROM message_embeds e
+         INNER JOIN link_previews p ON p.id = e.preview_id
+         WHERE e.message_id IN ({}) AND p.status = 'ok'
+         ORDER BY e.position ASC",
+        placeholders
+    );
+    let mut q = sqlx::query(&query_str);
+    for mid in message_ids {
+        q = q.bind(mid);
+    }
+    let rows = q.fetch_all(&db.0).await?;
+
+    let mut out: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    for r in rows {
+        let id: String = r.get("id");
+        let image_url = r
+            .get::<Option<String>, _>("image_stored_name")
+            .map(|_| format!("/link_previews/{}/image", id));
+        out.entry(r.get("message_id"))
+            .or_default()
+            .push(serde_json::json!({
+                "url": r.get::<String,_>("url"),
+                "title": r.get::<Option<String>,_>("title"),
+                "description": r.get::<Option<String>,_>("description"),
+                "site_name": r.get::<Option<String>,_>("site_name"),
+                "image_url": image_url,
+                "image_width": r.get::<Option<i64>,_>("image_width"),
+                "image_height": r.get::<Option<i64>,_>("image_height"),
+            }));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// What the test server sends back for a path.
+    enum Reply {
+        Page(&'static str, Vec<u8>),
+        /// Claims a body of this many bytes, then sends only a little of it
+        Huge(&'static str, usize),
+        Redirect(&'static str),
+        /// Sends the headers, then never finishes the body
+        Stall,
+        NotFound,
+    }
+
+    /// A local HTTP server answering each request with `route(path)`. Returns its base URL.
+    async fn serve(route: fn(&str) -> Reply) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 8192];
+                    let mut len = 0;
+                    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf[len..]).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => len += n,
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let (head, body) = match route(&path) {
+                        Reply::Page(content_type, body) => (
+                            format!(
+                                "200 OK\r\nContent-Type: {}\r\nContent-Length: {}",
+                                content_type,
+                                body.len()
+                            ),
+                            body,
+                        ),
+                        Reply::Huge(content_type, len) => (
+                            format!("200 OK\r\nContent-Type: {}\r\nContent-Length: {}", content_type, len),
+                            vec![b'x'; 1024],
+                        ),
+                        Reply::Redirect(location) => (
+                            format!("302 Found\r\nLocation: {}\r\nContent-Length: 0", location),
+                            Vec::new(),
+                        ),
+                        Reply::Stall => {
+                            let head = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 100\r\n\r\n<html>";
+                            let _ = stream.write_all(head.as_bytes()).await;
+                            tokio::time::sleep(Duration::from_secs(60)).await;
+                            return;
+                        }
+                        Reply::NotFound => ("404 Not Found\r\nContent-Length: 0".to_string(), Vec::new()),
+                    };
+                    let head = format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", head);
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        base
+    }
+
+    fn html(s: &str) -> Reply {
+        Reply::Page("text/html; charset=utf-8", s.as_bytes().to_vec())
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut out = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(width, height)
+            .write_to(&mut out, image::ImageFormat::Png)
+            .unwrap();
+        out.into_inner()
+    }
+
+    fn route(path: &str) -> Reply {
+        match path {
+            "/og" => html(
+                r#"<!doctype html><html><head>
+                <title>Fallback title</title>
+                <meta property="og:title" content="Tom &amp; Jerry">
+                <meta name="description" content='A "classic" cartoon'>
+                <meta property="og:site_name" content="Example">
+                <meta property="og:image" content="/images/cover.png">
+                </head><body><meta property="og:title" content="Not in head"></body></html>"#,
+            ),
+            "/oembed-page" => html(
+                r#"<html><head><link rel="alternate" type="application/json+oembed" href="/oembed.json"></head></html>"#,
+            ),
+            "/oembed.json" => Reply::Page(
+                "application/json",
+                br#"{"title":"From oEmbed","provider_name":"Provider","author_name":"Someone","thumbnail_url":"/thumb.png"}"#.to_vec(),
+            ),
+            "/bare" => html("<html><head></head><body>Nothing here</body></html>"),
+            "/text" => Reply::Page("text/plain", b"just text".to_vec()),
+            "/long" => {
+                // The metadata is at the top; everything past the limit is ignored
+                let mut page = r#"<html><head><meta property="og:title" content="Long page">"#.to_string();
+                page.push_str(&"<!-- padding -->".repeat(MAX_HTML_BYTES / 16 + 1000));
+                page.push_str("</head></html>");
+                html(&page)
+            }
+            "/images/cover.png" => Reply::Page("image/png", png(800, 600)),
+            "/huge.png" => Reply::Huge("image/png", MAX_IMAGE_BYTES + 1),
+            "/stall" => Reply::Stall,
+            "/to-og" => Reply::Redirect("/og"),
+            "/to-file" => Reply::Redirect("file:///etc/passwd"),
+            _ => Reply::NotFound,
+        }
+    }
+
+    #[tokio::test]
+    async fn opengraph_and_oembed() {
+        let base = serve(route).await;
+        let fetcher = LinkFetcher::new(true);
+
+        let preview = fetcher.fetch_page(&base.join("og").unwrap()).await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(preview.description.as_deref(), Some("A \"classic\" cartoon"));
+        assert_eq!(preview.site_name.as_deref(), Some("Example"));
+        let image_url = preview.image_url.unwrap();
+        assert_eq!(image_url, base.join("images/cover.png").unwrap());
+
+        let (jpeg, w, h) = fetcher.fetch_image(&image_url).await.unwrap();
+        assert_eq!((w, h), (PREVIEW_IMAGE_SIZE, 300));
+        assert!(jpeg.starts_with(&[0xff, 0xd8]));
+
+        let preview = fetcher.fetch_page(&base.join("oembed-page").unwrap()).await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("From oEmbed"));
+        assert_eq!(preview.site_name.as_deref(), Some("Provider"));
+        assert_eq!(preview.description.as_deref(), Some("Someone"));
+        assert_eq!(preview.image_url, Some(base.join("thumb.png").unwrap()));
+
+        let redirected = fetcher.fetch_page(&base.join("to-og").unwrap()).await.unwrap();
+        assert_eq!(redirected.title.as_deref(), Some("Tom & Jerry"));
+
+        assert!(matches!(
+            fetcher.fetch_page(&base.join("bare").unwrap()).await,
+            Err(UnfurlError::NoMetadata)
+        ));
+        assert!(matches!(
+            fetcher.fetch_page(&base.join("text").unwrap()).await,
+            Err(UnfurlError::UnsupportedContent)
+        ));
+        assert!(matches!(
+            fetcher.fetch_page(&base.join("missing").unwrap()).await,
+            Err(UnfurlError::Http(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn size_limits() {
+        let base = serve(route).await;
+        let fetcher = LinkFetcher::new(true);
+
+        // Pages are cut off rather than refused
+        let preview = fetcher.fetch_page(&base.join("long").unwrap()).await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Long page"));
+
+        // Images over the limit are refused before their body is read
+        assert!(matches!(
+            fetcher.fetch_image(&base.join("huge.png").unwrap()).await,
+            Err(UnfurlError::TooLarge)
+        ));
+        // Not an image
+        assert!(matches!(
+            fetcher.fetch_image(&base.join("og").unwrap()).await,
+            Err(UnfurlError::UnsupportedContent)
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_servers_time_out() {
+        let base = serve(route).await;
+        let fetcher = LinkFetcher::new(true);
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            fetcher.fetch_page(&base.join("stall").unwrap()).await,
+            Err(UnfurlError::Http(_))
+        ));
+        assert!(started.elapsed() < REQUEST_TIMEOUT + Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn private_networks_are_refused() {
+        let base = serve(route).await;
+        let port = base.port().unwrap();
+        let fetcher = LinkFetcher::new(false);
+
+        // Literal private addresses and localhost, before connecting
+        for url in [
+            format!("http://127.0.0.1:{}/og", port),
+            format!("http://localhost:{}/og", port),
+            format!("http://[::1]:{}/og", port),
+            "http://10.0.0.1/".to_string(),
+            "http://169.254.169.254/latest/meta-data/".to_string(),
+            "ftp://example.com/".to_string(),
+        ] {
+            let url = Url::parse(&url).unwrap();
+            assert!(
+                matches!(fetcher.fetch_page(&url).await, Err(UnfurlError::Blocked)),
+                "{}",
+                url
+            );
+        }
+        // A hostname that only resolves to a private address fails at connect time
+        let url = Url::parse(&format!("http://localhost.:{}/og", port)).unwrap();
+        assert!(matches!(fetcher.fetch_page(&url).await, Err(UnfurlError::Http(_))));
+
+        // Redirects can't leave http(s), even with private networks allowed
+        let open = LinkFetcher::new(true);
+        assert!(matches!(
+            open.fetch_page(&base.join("to-file").unwrap()).await,
+            Err(UnfurlError::Http(_))
+        ));
+
+        assert!(is_public_ip("93.184.216.34".parse().unwrap()));
+        for ip in ["192.168.1.1", "100.64.0.1", "::ffff:127.0.0.1", "fd00::1", "fe80::1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn urls_in_messages() {
+        let urls = extract_urls(
+            "see (https://a.example/x). and <http://b.example/y#frag>, https://a.example/x again \
+             https://c.example https://d.example",
+        );
+        let urls: Vec<&str> = urls.iter().map(Url::as_str).collect();
+        assert_eq!(urls, ["https://a.example/x", "http://b.example/y", "https://c.example/"]);
+    }
+}
@@ -1,65 +1,229 @@
-use crate::shareplay::SharePlayState;
+use crate::media_resolver::{MediaRequest, PlaylistEntry};
+use crate::shareplay::{
+    DownloadJobs, PlayedTrack, QueueItem, SharePlayControl, SharePlayState, StateWrite,
+};
+use crate::shareplay_cache::SharePlayCache;
 use actix::{Actor, AsyncContext, Context, Handler, Message};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 pub struct ChatServer {
     rooms: HashMap<String, HashSet<actix::Addr<super::session::WsSession>>>,
     voice_participants: HashMap<String, HashSet<(String, String)>>, // channel_id -> set of (user_id, session_id)
     user_sessions: HashMap<String, HashMap<String, actix::Addr<super::session::WsSession>>>, // user_id -> { session_id -> addr }
     pub shareplay_states: HashMap<String, SharePlayState>,
+    shareplay_writes: tokio::sync::mpsc::UnboundedSender<StateWrite>,
+    shareplay_history: tokio::sync::mpsc::UnboundedSender<PlayedTrack>,
+    shareplay_cache: Arc<SharePlayCache>,
+    downloads: DownloadJobs,
+    skip_vote_fraction: f64, // Of the voice call, to skip a track by vote
 }
 
 impl ChatServer {
-    pub fn new() -> Self {
+    /// `shareplay_states` are the queues restored from the database; their missing
+    /// songs are downloaded again once the server starts.
+    pub fn new(
+        shareplay_states: HashMap<String, SharePlayState>,
+        shareplay_writes: tokio::sync::mpsc::UnboundedSender<StateWrite>,
+        shareplay_history: tokio::sync::mpsc::UnboundedSender<PlayedTrack>,
+        shareplay_cache: Arc<SharePlayCache>,
+        downloads: DownloadJobs,
+        skip_vote_fraction: f64,
+    ) -> Self {
         Self {
             rooms: HashMap::new(),
             voice_participants: HashMap::new(),
             user_sessions: HashMap::new(),
-            shareplay_states: HashMap::new(),
+            shareplay_states,
+            shareplay_writes,
+            shareplay_history,
+            shareplay_cache,
+            downloads,
+            skip_vote_fraction,
         }
     }
 }
 
 impl Actor for ChatServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.trigger_pending_downloads(ctx);
+    }
 }
 
 impl ChatServer {
-    fn trigger_pending_downloads(&mut self, channel_id: String, ctx: &mut Context<Self>) {
-        if let Some(state) = self.shareplay_states.get_mut(&channel_id) {
-            let active_count = state
-                .queue
-                .iter()
-                .filter(|i| i.download_status == "grabbing" || i.download_status == "downloading")
-                .count();
+    /// Save a channel's SharePlay state after it changed, or forget it if it's gone.
+    fn save_shareplay(&self, channel_id: &str) {
+        let state = self
+            .shareplay_states
+            .get(channel_id)
+            .and_then(|s| serde_json::to_string(&s.for_saving()).ok());
+        let _ = self.shareplay_writes.send((channel_id.to_string(), state));
+    }
 
-            let slots = if active_count < 2 {
-                2 - active_count
-            } else {
-                0
+    /// Start pending downloads while the pool has free slots, taking them from the
+    /// channel with the fewest downloads running so one long playlist can't hold up
+    /// every other channel.
+    fn trigger_pending_downloads(&mut self, ctx: &mut Context<Self>) {
+        while self.downloads.has_capacity() {
+            let next = self
+                .shareplay_states
+                .iter()
+                .filter_map(|(channel_id, state)| {
+                    let item = state.queue.iter().find(|i| i.download_status == "pending")?;
+                    Some((channel_id, item))
+                })
+                .min_by_key(|(channel_id, _)| self.downloads.running_in(channel_id))
+                .map(|(channel_id, item)| {
+                    let req = MediaRequest {
+                        input: item.url.clone(),
+                        user_id: item.added_by.clone(),
+                        video: item.video,
+                    };
+                    (channel_id.clone(), item.id.clone(), req)
+                });
+            let Some((channel_id, id, req)) = next else {
+                break;
             };
 
-            // Collect items to download (mark as grabbing first to prevent re-triggering)
-            let mut to_download = Vec::new();
-            for item in state.queue.iter_mut() {
-                if to_download.len() >= slots {
-                    break;
-                }
-                if item.download_status == "pending" {
-                    item.download_status = "grabbing".to_string();
-                    to_download.push((item.url.clone(), item.id.clone()));
-                }
+            if let Some(item) = self
+                .shareplay_states
+                .get_mut(&channel_id)
+                .and_then(|s| s.queue.iter_mut().find(|i| i.id == id))
+            {
+                item.download_status = "grabbing".to_string();
             }
+            log::info!(
+                "Triggering pending download: channel_id={}, id={}",
+                channel_id,
+                id
+            );
+            self.downloads.start(req, id, channel_id.clone(), ctx.address());
+            self.broadcast_shareplay(&channel_id, ctx);
+            self.save_shareplay(&channel_id);
+        }
+    }
 
-            // Now spawn the downloads
-            for (url, id) in to_download {
-                log::info!(
-                    "Triggering pending download: channel_id={}, id={}",
-                    channel_id,
-                    id
-                );
-                crate::shareplay::start_single_download(url, id, ctx.address(), channel_id.clone());
+    /// Log what was playing before a change, now that playback has moved on from it.
+    fn record_played(&self, channel_id: &str, before: Option<(QueueItem, f64)>) {
+        if let Some(track) = PlayedTrack::from_now_playing(channel_id, before) {
+            let _ = self.shareplay_history.send(track);
+        }
+    }
+
+    /// Drop skip votes of users who left the voice call, recount the votes needed from
+    /// its size, and skip the track once there are enough. Returns whether anything changed.
+    fn update_skip_votes(&mut self, channel_id: &str) -> bool {
+        let in_voice: HashSet<&str> = self
+            .voice_participants
+            .get(channel_id)
+            .map(|v| v.iter().map(|(uid, _)| uid.as_str()).collect())
+            .unwrap_or_default();
+        let Some(state) = self.shareplay_states.get_mut(channel_id) else {
+            return false;
+        };
+        let votes = state.skip_votes.len();
+        state.skip_votes.retain(|uid| in_voice.contains(uid.as_str()));
+        let needed = ((in_voice.len() as f64 * self.skip_vote_fraction).ceil() as usize).max(1);
+        let mut changed = votes != state.skip_votes.len() || needed != state.skip_votes_needed;
+        state.skip_votes_needed = needed;
+
+        if !state.skip_votes.is_empty() && state.skip_votes.len() >= needed {
+            log::info!(
+                "SharePlay: skipping track in {} by vote ({}/{})",
+                channel_id,
+                state.skip_votes.len(),
+                needed
+            );
+            state.skip_votes.clear();
+            let before = state.now_playing();
+            // A vote isn't a client's `ended` event racing another's
+            state.last_auto_next = None;
+            state.next();
+            if let Some(track) = PlayedTrack::from_now_playing(channel_id, before) {
+                let _ = self.shareplay_history.send(track);
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// Check a SharePlay action against the channel's control mode, and tell the
+    /// session that sent it if it's refused. Returns whether it was.
+    fn shareplay_denied(
+        &self,
+        channel_id: &str,
+        user_id: &str,
+        session_id: &str,
+        action_type: &str,
+        control: SharePlayControl,
+        is_dj: bool,
+    ) -> bool {
+        let in_voice = self
+            .voice_participants
+            .get(channel_id)
+            .is_some_and(|v| v.iter().any(|(uid, _)| uid == user_id));
+        let denied = if action_type == "vote_skip" {
+            (!in_voice).then_some("Join the voice call to vote")
+        } else if action_type == "ended" {
+            None
+        } else {
+            match control {
+                SharePlayControl::Everyone => None,
+                SharePlayControl::Voice => {
+                    (!in_voice).then_some("Only the voice call can control SharePlay")
+                }
+                SharePlayControl::Djs => (!is_dj).then_some("Only DJs can control SharePlay"),
             }
+        };
+        let Some(reason) = denied else {
+            return false;
+        };
+        log::info!(
+            "SharePlay action {} denied for {} in {}: {}",
+            action_type,
+            user_id,
+            channel_id,
+            reason
+        );
+        let payload = serde_json::json!({
+            "type": "shareplay_denied",
+            "channel_id": channel_id,
+            "action_type": action_type,
+            "reason": reason
+        })
+        .to_string();
+        if let Some(addr) = self
+            .user_sessions
+            .get(user_id)
+            .and_then(|sessions| sessions.get(session_id))
+        {
+            addr.do_send(super::session::ServerMsg { payload });
+        }
+        true
+    }
+
+    /// After the voice call changed size.
+    fn voice_changed(&mut self, channel_id: &str, ctx: &mut Context<Self>) {
+        if self.update_skip_votes(channel_id) {
+            self.broadcast_shareplay(channel_id, ctx);
+            self.save_shareplay(channel_id);
+        }
+    }
+
+    fn broadcast_shareplay(&self, channel_id: &str, ctx: &mut Context<Self>) {
+        if let Some(state) = self.shareplay_states.get(channel_id) {
+            let payload = serde_json::json!({
+                "type": "shareplay_update",
+                "channel_id": channel_id,
+                "state": state
+            })
+            .to_string();
+            ctx.notify(Broadcast {
+                channel_id: channel_id.to_string(),
+                payload,
+            });
         }
     }
 }
@@ -144,8 +308,11 @@ pub struct NotifyUsers {
 pub struct SharePlayAction {
     pub channel_id: String,
     pub user_id: String,
-    pub action_type: String, // "play", "pause", "next", "prev", "seek", "add", "track", "toggle_repeat", "remove"
-    pub data: Option<String>, // url for add, timestamp for seek, index for track
+    pub session_id: String,
+    pub action_type: String, // "play", "pause", "next", "prev", "seek", "add", "track", "toggle_repeat", "remove", "move", "play_next", "shuffle", "clear_upcoming", "vote_skip", "ended", "add_video", "play_next_video"
+    pub data: Option<String>, // url for add/play_next and their _video forms, timestamp for seek, index for track, "from,to" for move, item id for ended
+    pub control: SharePlayControl, // The channel's, checked here since it may need the voice call
+    pub is_dj: bool,
 }
 
 #[derive(Message)]
@@ -158,6 +325,7 @@ pub struct SharePlayDownloadResult {
     pub file_path: Option<String>,
     pub thumbnail_path: Option<String>,
     pub duration: u64,
+    pub gain_db: Option<f64>,
     pub error: Option<String>,
 }
 
@@ -173,32 +341,78 @@ pub struct SharePlayMetadataResult {
     pub error: Option<String>,
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SharePlayDownloadProgress {
+    pub channel_id: String,
+    pub id: String,
+    pub percent: f32,
+    pub eta_secs: Option<u64>,
+}
+
+/// A download job is done, whatever the outcome, and its slot is free.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SharePlayJobFinished {
+    pub id: String,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct SharePlayPlaylistResult {
     pub channel_id: String,
-    pub placeholder_id: String,
-    pub entries: Vec<(String, String, u64)>, // url, title, duration
+    pub placeholder_id: Option<String>, // The item that turned out to be a playlist, if any
+    pub added_by: Option<String>,       // Without a placeholder
+    pub entries: Vec<PlaylistEntry>,
 }
 
+/// Queue a saved playlist, as a `SharePlayAction` would be: the sender must be allowed
+/// to control the channel's SharePlay.
 #[derive(Message)]
-#[rtype(result = "Result<Option<String>, ()>")]
-pub struct GetSharePlaySongId {
+#[rtype(result = "()")]
+pub struct SharePlayLoadPlaylist {
     pub channel_id: String,
+    pub user_id: String,
+    pub session_id: String,
+    pub control: SharePlayControl,
+    pub is_dj: bool,
+    pub entries: Vec<PlaylistEntry>,
 }
 
+/// The songs in a channel's queue, to save it as a playlist.
 #[derive(Message)]
-#[rtype(result = "Result<Option<String>, ()>")]
-pub struct GetSharePlaySongPath {
-    pub song_id: String,
+#[rtype(result = "Result<Vec<PlaylistEntry>, ()>")]
+pub struct GetSharePlayEntries {
+    pub channel_id: String,
 }
 
 #[derive(Message)]
 #[rtype(result = "Result<Option<String>, ()>")]
-pub struct GetSharePlayThumbnailPath {
+pub struct GetSharePlaySongId {
+    pub channel_id: String,
+}
+
+/// A queue item's downloaded files, and the channel whose queue it is in.
+pub struct SharePlayItemFiles {
+    pub channel_id: String,
+    pub item_id: String,
+    pub file_path: Option<String>,
+    pub thumbnail_path: Option<String>,
+    pub video: bool,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Option<SharePlayItemFiles>, ()>")]
+pub struct GetSharePlayItem {
     pub item_id: String,
 }
 
+#[derive(Message)]
+#[rtype(result = "Result<Vec<SharePlayItemFiles>, ()>")]
+pub struct GetSharePlayQueueFiles {
+    pub channel_id: String,
+}
+
 impl Handler<Join> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
@@ -270,7 +484,10 @@ impl Handler<Leave> for ChatServer {
                     // If NO ONE is left in voice, clean up SharePlay
                     if voice_users.is_empty() {
                         if let Some(state) = self.shareplay_states.remove(&msg.channel_id) {
-                            crate::shareplay::cleanup_channel_files(&state);
+                            self.downloads.cancel_channel(&msg.channel_id);
+                            self.record_played(&msg.channel_id, state.now_playing());
+                            crate::shareplay::cleanup_channel_files(&state, &self.downloads);
+                            let _ = self.shareplay_writes.send((msg.channel_id.clone(), None));
                             log::info!("Cleaned up SharePlay for empty channel {}", msg.channel_id);
 
                             // Notify clients
@@ -288,6 +505,9 @@ impl Handler<Leave> for ChatServer {
                 }
             }
         }
+        self.voice_changed(&msg.channel_id, ctx);
+        // Cancelled downloads free up slots for other channels
+        self.trigger_pending_downloads(ctx);
     }
 }
 impl Handler<Broadcast> for ChatServer {
@@ -324,9 +544,10 @@ impl Handler<JoinVoice> for ChatServer {
             })
             .to_string();
             ctx.notify(Broadcast {
-                channel_id: msg.channel_id,
+                channel_id: msg.channel_id.clone(),
                 payload,
             });
+            self.voice_changed(&msg.channel_id, ctx);
         }
     }
 }
@@ -360,7 +581,10 @@ impl Handler<LeaveVoice> for ChatServer {
                 // If NO ONE is left in voice, clean up SharePlay
                 if voice_users.is_empty() {
                     if let Some(state) = self.shareplay_states.remove(&msg.channel_id) {
-                        crate::shareplay::cleanup_channel_files(&state);
+                        self.downloads.cancel_channel(&msg.channel_id);
+                        self.record_played(&msg.channel_id, state.now_playing());
+                        crate::shareplay::cleanup_channel_files(&state, &self.downloads);
+                        let _ = self.shareplay_writes.send((msg.channel_id.clone(), None));
                         log::info!("Cleaned up SharePlay for empty channel {}", msg.channel_id);
 
                         // Notify clients
@@ -377,6 +601,9 @@ impl Handler<LeaveVoice> for ChatServer {
                 }
             }
         }
+        self.voice_changed(&msg.channel_id, ctx);
+        // Cancelled downloads free up slots for other channels
+        self.trigger_pending_downloads(ctx);
     }
 }
 
@@ -464,11 +691,6 @@ impl Handler<BroadcastAll> for ChatServer {
 impl Handler<SharePlayAction> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: SharePlayAction, ctx: &mut Context<Self>) {
-        let state = self
-            .shareplay_states
-            .entry(msg.channel_id.clone())
-            .or_insert_with(SharePlayState::new);
-
         log::info!(
             "ChatServer handling SharePlayAction: channel_id={}, user_id={}, action={}",
             msg.channel_id,
@@ -476,17 +698,29 @@ impl Handler<SharePlayAction> for ChatServer {
             msg.action_type
         );
 
+        if self.shareplay_denied(
+            &msg.channel_id,
+            &msg.user_id,
+            &msg.session_id,
+            &msg.action_type,
+            msg.control,
+            msg.is_dj,
+        ) {
+            return;
+        }
+
+        let state = self
+            .shareplay_states
+            .entry(msg.channel_id.clone())
+            .or_insert_with(SharePlayState::new);
+        let track_before = state.current_item_id().map(str::to_string);
+        let playing_before = state.now_playing();
+
         match msg.action_type.as_str() {
-            "add" => {
+            "add" | "add_video" => {
                 if let Some(url) = msg.data {
-                    let id = state.add_item(url.clone());
-                    // Trigger download
-                    crate::shareplay::start_download(
-                        url,
-                        id,
-                        ctx.address(),
-                        msg.channel_id.clone(),
-                    );
+                    // Downloaded by trigger_pending_downloads below
+                    state.add_item(url, &msg.user_id, msg.action_type == "add_video");
                 }
             }
             "play" => state.play(),
@@ -511,25 +745,65 @@ impl Handler<SharePlayAction> for ChatServer {
             "remove" => {
                 if let Some(idx_str) = msg.data {
                     if let Ok(idx) = idx_str.parse::<usize>() {
+                        if let Some(item) = state.queue.get(idx) {
+                            self.downloads.cancel(&item.id);
+                            self.shareplay_cache.release(&item.id);
+                            self.downloads.delete_item_files(item);
+                        }
                         state.remove_item(idx);
                     }
                 }
             }
+            "move" => {
+                if let Some((from, to)) = msg.data.as_deref().and_then(|d| d.split_once(','))
+                    && let (Ok(from), Ok(to)) = (from.trim().parse(), to.trim().parse())
+                {
+                    state.move_item(from, to);
+                }
+            }
+            "play_next" | "play_next_video" => {
+                if let Some(url) = msg.data {
+                    state.play_next(url, &msg.user_id, msg.action_type == "play_next_video");
+                }
+            }
+            "shuffle" => state.shuffle_upcoming(),
+            "clear_upcoming" => {
+                for item in state.upcoming() {
+                    self.downloads.cancel(&item.id);
+                    self.shareplay_cache.release(&item.id);
+                    self.downloads.delete_item_files(item);
+                }
+                state.clear_upcoming();
+            }
+            "vote_skip" => {
+                state.vote_skip(&msg.user_id);
+            }
+            "ended" => {
+                if let Some(id) = msg.data {
+                    state.track_ended(&id);
+                }
+            }
             _ => {}
         }
+        let track_changed = state.current_item_id() != track_before.as_deref();
+        // Repeating the track starts it over
+        let replayed = matches!(msg.action_type.as_str(), "next" | "ended")
+            && playing_before
+                .as_ref()
+                .is_some_and(|(_, pos)| state.get_current_position() < *pos);
+        // Votes are for the track they were cast on
+        if track_changed {
+            state.skip_votes.clear();
+        }
+        if track_changed || replayed {
+            self.record_played(&msg.channel_id, playing_before);
+        }
+        self.update_skip_votes(&msg.channel_id);
 
-        // Broadcast update
-        let payload = serde_json::json!({
-            "type": "shareplay_update",
-            "channel_id": msg.channel_id,
-            "state": state
-        })
-        .to_string();
-
-        ctx.notify(Broadcast {
-            channel_id: msg.channel_id,
-            payload,
-        });
+        self.broadcast_shareplay(&msg.channel_id, ctx);
+        self.save_shareplay(&msg.channel_id);
+        // Start added songs, or others if a removal cancelled a download
+        self.trigger_pending_downloads(ctx);
     }
 }
 
@@ -542,6 +816,14 @@ impl Handler<SharePlayDownloadResult> for ChatServer {
             msg.id,
             msg.success
         );
+        // The item was removed while it downloaded; nothing holds on to the song
+        let queued = self
+            .shareplay_states
+            .get(&msg.channel_id)
+            .is_some_and(|s| s.queue.iter().any(|i| i.id == msg.id));
+        if !queued {
+            self.shareplay_cache.release(&msg.id);
+        }
         if let Some(state) = self.shareplay_states.get_mut(&msg.channel_id) {
             if msg.success {
                 state.update_item_success(
@@ -550,6 +832,7 @@ impl Handler<SharePlayDownloadResult> for ChatServer {
                     msg.file_path.unwrap_or_default(),
                     msg.thumbnail_path,
                     msg.duration,
+                    msg.gain_db,
                 );
             } else {
                 state.update_item_error(&msg.id, msg.error.unwrap_or("Unknown error".to_string()));
@@ -569,8 +852,29 @@ impl Handler<SharePlayDownloadResult> for ChatServer {
             });
         }
 
-        // Always trigger next pending downloads even on success/failure
-        self.trigger_pending_downloads(msg.channel_id, ctx);
+        self.save_shareplay(&msg.channel_id);
+    }
+}
+
+impl Handler<SharePlayDownloadProgress> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: SharePlayDownloadProgress, ctx: &mut Context<Self>) {
+        // Not saved: progress means nothing after a restart
+        let updated = self
+            .shareplay_states
+            .get_mut(&msg.channel_id)
+            .is_some_and(|s| s.update_item_progress(&msg.id, msg.percent, msg.eta_secs));
+        if updated {
+            self.broadcast_shareplay(&msg.channel_id, ctx);
+        }
+    }
+}
+
+impl Handler<SharePlayJobFinished> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: SharePlayJobFinished, ctx: &mut Context<Self>) {
+        self.downloads.finished(&msg.id);
+        self.trigger_pending_downloads(ctx);
     }
 }
 
@@ -583,31 +887,7 @@ impl Handler<SharePlayPlaylistResult> for ChatServer {
             msg.entries.len()
         );
         if let Some(state) = self.shareplay_states.get_mut(&msg.channel_id) {
-            // Remove placeholder
-            let placeholder_idx = state.queue.iter().position(|i| i.id == msg.placeholder_id);
-            if let Some(idx) = placeholder_idx {
-                state.queue.remove(idx);
-            }
-
-            // Batch add items as pending
-            for (url, title, duration) in msg.entries {
-                let id = uuid::Uuid::new_v4().to_string();
-                state.queue.push(crate::shareplay::QueueItem {
-                    id,
-                    url,
-                    title,
-                    file_path: None,
-                    thumbnail_path: None,
-                    download_error: None,
-                    duration_seconds: duration,
-                    download_status: "pending".to_string(),
-                });
-            }
-
-            // Sync current index if it was pointing to the placeholder or empty
-            if state.current_index.is_none() && !state.queue.is_empty() {
-                state.current_index = Some(0);
-            }
+            state.insert_playlist(msg.placeholder_id.as_deref(), msg.added_by, msg.entries);
 
             // Broadcast update
             let payload = serde_json::json!({
@@ -622,9 +902,66 @@ impl Handler<SharePlayPlaylistResult> for ChatServer {
                 payload,
             });
 
-            // Trigger downloads
-            self.trigger_pending_downloads(msg.channel_id, ctx);
+            self.save_shareplay(&msg.channel_id);
         }
+        self.trigger_pending_downloads(ctx);
+    }
+}
+
+impl Handler<SharePlayLoadPlaylist> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: SharePlayLoadPlaylist, ctx: &mut Context<Self>) {
+        log::info!(
+            "ChatServer handling SharePlayLoadPlaylist: channel_id={}, user_id={}, entries={}",
+            msg.channel_id,
+            msg.user_id,
+            msg.entries.len()
+        );
+        if self.shareplay_denied(
+            &msg.channel_id,
+            &msg.user_id,
+            &msg.session_id,
+            "load_playlist",
+            msg.control,
+            msg.is_dj,
+        ) {
+            return;
+        }
+        self.shareplay_states
+            .entry(msg.channel_id.clone())
+            .or_insert_with(SharePlayState::new);
+        ctx.notify(SharePlayPlaylistResult {
+            channel_id: msg.channel_id,
+            placeholder_id: None,
+            added_by: Some(msg.user_id),
+            entries: msg.entries,
+        });
+    }
+}
+
+impl Handler<GetSharePlayEntries> for ChatServer {
+    type Result = Result<Vec<PlaylistEntry>, ()>;
+
+    fn handle(&mut self, msg: GetSharePlayEntries, _: &mut Context<Self>) -> Self::Result {
+        let Some(state) = self.shareplay_states.get(&msg.channel_id) else {
+            return Ok(Vec::new());
+        };
+        Ok(state
+            .queue
+            .iter()
+            .filter(|item| item.download_status != "error")
+            .map(|item| PlaylistEntry {
+                url: item.url.clone(),
+                // Songs not looked up yet only have their link
+                title: if item.title == "Grabbing..." {
+                    item.url.clone()
+                } else {
+                    item.title.clone()
+                },
+                duration_seconds: item.duration_seconds,
+                video: item.video,
+            })
+            .collect())
     }
 }
 
@@ -658,8 +995,7 @@ impl Handler<SharePlayMetadataResult> for ChatServer {
             });
         }
 
-        // Always trigger next pending downloads
-        self.trigger_pending_downloads(msg.channel_id, ctx);
+        self.save_shareplay(&msg.channel_id);
     }
 }
 
@@ -678,30 +1014,179 @@ impl Handler<GetSharePlaySongId> for ChatServer {
     }
 }
 
-impl Handler<GetSharePlaySongPath> for ChatServer {
-    type Result = Result<Option<String>, ()>;
+impl Handler<GetSharePlayItem> for ChatServer {
+    type Result = Result<Option<SharePlayItemFiles>, ()>;
 
-    fn handle(&mut self, msg: GetSharePlaySongPath, _: &mut Context<Self>) -> Self::Result {
-        // Search across all channels for this song ID
-        for state in self.shareplay_states.values() {
-            if let Some(item) = state.queue.iter().find(|i| i.id == msg.song_id) {
-                return Ok(item.file_path.clone());
+    fn handle(&mut self, msg: GetSharePlayItem, _: &mut Context<Self>) -> Self::Result {
+        // Search across all channels for this item ID
+        for (channel_id, state) in &self.shareplay_states {
+            if let Some(item) = state.queue.iter().find(|i| i.id == msg.item_id) {
+                return Ok(Some(SharePlayItemFiles {
+                    channel_id: channel_id.clone(),
+                    item_id: item.id.clone(),
+                    file_path: item.file_path.clone(),
+                    thumbnail_path: item.thumbnail_path.clone(),
+                    video: item.video,
+                }));
             }
         }
         Ok(None)
     }
 }
 
-impl Handler<GetSharePlayThumbnailPath> for ChatServer {
-    type Result = Result<Option<String>, ()>;
+impl Handler<GetSharePlayQueueFiles> for ChatServer {
+    type Result = Result<Vec<SharePlayItemFiles>, ()>;
 
-    fn handle(&mut self, msg: GetSharePlayThumbnailPath, _: &mut Context<Self>) -> Self::Result {
-        // Search across all channels for this item ID
-        for state in self.shareplay_states.values() {
-            if let Some(item) = state.queue.iter().find(|i| i.id == msg.item_id) {
-                return Ok(item.thumbnail_path.clone());
+    fn handle(&mut self, msg: GetSharePlayQueueFiles, _: &mut Context<Self>) -> Self::Result {
+        let Some(state) = self.shareplay_states.get(&msg.channel_id) else {
+            return Ok(Vec::new());
+        };
+        Ok(state
+            .queue
+            .iter()
+            .map(|item| SharePlayItemFiles {
+                channel_id: msg.channel_id.clone(),
+                item_id: item.id.clone(),
+                file_path: item.file_path.clone(),
+                thumbnail_path: item.thumbnail_path.clone(),
+                video: item.video,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media_resolver::{FakeResolver, Resolvers};
+    use std::time::Duration;
+
+    #[derive(Message)]
+    #[rtype(result = "Option<SharePlayState>")]
+    struct GetState(String);
+
+    impl Handler<GetState> for ChatServer {
+        type Result = Option<SharePlayState>;
+        fn handle(&mut self, msg: GetState, _: &mut Context<Self>) -> Self::Result {
+            self.shareplay_states.get(&msg.0).cloned()
+        }
+    }
+
+    fn action(channel_id: &str, action_type: &str, data: &str) -> SharePlayAction {
+        SharePlayAction {
+            channel_id: channel_id.to_string(),
+            user_id: "user".to_string(),
+            session_id: "session".to_string(),
+            action_type: action_type.to_string(),
+            data: Some(data.to_string()),
+            control: SharePlayControl::Everyone,
+            is_dj: false,
+        }
+    }
+
+    /// Poll the channel's state until `done` holds for it.
+    async fn wait_for(
+        addr: &actix::Addr<ChatServer>,
+        channel_id: &str,
+        done: impl Fn(&SharePlayState) -> bool,
+    ) -> SharePlayState {
+        for _ in 0..250 {
+            if let Some(state) = addr.send(GetState(channel_id.to_string())).await.unwrap()
+                && done(&state)
+            {
+                return state;
             }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
         }
-        Ok(None)
+        panic!("SharePlay state of {} never got there", channel_id);
+    }
+
+    fn titles(state: &SharePlayState) -> Vec<&str> {
+        state.queue.iter().map(|i| i.title.as_str()).collect()
+    }
+
+    fn all_done(state: &SharePlayState) -> bool {
+        !state.queue.is_empty()
+            && state
+                .queue
+                .iter()
+                .all(|i| i.download_status == "ready" || i.download_status == "error")
+    }
+
+    /// Songs go through the same download jobs, cache and queue handling as in
+    /// production, with `FakeResolver` standing in for yt-dlp.
+    #[actix_web::test]
+    async fn shareplay_with_fake_media() {
+        let dir = std::env::temp_dir().join(format!("shareplay-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = crate::db::Db::connect_and_migrate(&dir.join("test.sqlite3").to_string_lossy())
+            .await
+            .unwrap();
+        let cache = Arc::new(
+            SharePlayCache::load(&db, dir.join("cache"), 1 << 30, &HashMap::new())
+                .await
+                .unwrap(),
+        );
+        let addr = ChatServer::new(
+            HashMap::new(),
+            crate::shareplay::spawn_state_writer(db.clone()),
+            crate::shareplay::spawn_history_writer(db.clone()),
+            cache.clone(),
+            DownloadJobs::new(
+                2,
+                Duration::from_secs(30),
+                dir.clone(),
+                cache.clone(),
+                Resolvers::new(vec![Arc::new(FakeResolver)]),
+                0.0,
+            ),
+            0.5,
+        )
+        .start();
+        let ch = "channel";
+
+        // A song, then a playlist that expands where it was queued
+        addr.do_send(action(ch, "add", "first"));
+        addr.do_send(action(ch, "add", "playlist:a,b,c"));
+        addr.do_send(action(ch, "add_video", "last"));
+        let state = wait_for(&addr, ch, |s| s.queue.len() == 5 && all_done(s)).await;
+        assert_eq!(titles(&state), ["first", "a", "b", "c", "last"]);
+        assert_eq!(state.current_index, Some(0));
+        assert!(state.queue[4].video && !state.queue[1].video);
+        for item in &state.queue {
+            assert_eq!(item.duration_seconds, 10);
+            let path = std::path::Path::new(item.file_path.as_ref().unwrap());
+            assert!(path.exists() && path.starts_with(&dir));
+        }
+
+        // A failed lookup marks the item, and doesn't hold up the queue
+        addr.do_send(action(ch, "add", "error:no such song"));
+        let state = wait_for(&addr, ch, |s| s.queue.len() == 6 && all_done(s)).await;
+        let failed = &state.queue[5];
+        assert_eq!(failed.download_status, "error");
+        assert!(failed.download_error.as_deref().unwrap().contains("no such song"));
+
+        // The same song again comes from the cache
+        let hits = cache.stats().hits;
+        addr.do_send(action(ch, "add", "first"));
+        let state = wait_for(&addr, ch, |s| s.queue.len() == 7 && all_done(s)).await;
+        assert_eq!(state.queue[6].file_path, state.queue[0].file_path);
+        assert_eq!(cache.stats().hits, hits + 1);
+
+        // Removing an item keeps a cached song another item still plays
+        addr.do_send(action(ch, "remove", "6"));
+        let state = wait_for(&addr, ch, |s| s.queue.len() == 6).await;
+        assert_eq!(state.queue.iter().filter(|i| i.url == "first").count(), 1);
+        assert!(std::path::Path::new(state.queue[0].file_path.as_ref().unwrap()).exists());
+
+        // Clearing what's upcoming leaves the current song, and frees the others'
+        addr.do_send(action(ch, "move", "3,0"));
+        addr.do_send(action(ch, "clear_upcoming", ""));
+        let state = wait_for(&addr, ch, |s| s.queue.len() == 2).await;
+        assert_eq!(titles(&state), ["c", "first"]);
+        assert_eq!(state.current_index, Some(1));
+        assert_eq!(cache.stats().in_use, 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
@@ -1,6 +1,10 @@
 use super::server::{
     Broadcast, ChatServer, Connect, DirectSignal, Disconnect, Join, Leave, SharePlayAction,
+    SharePlayLoadPlaylist,
 };
+use crate::permissions::is_shareplay_dj;
+use crate::routes::playlists::loadable_entries;
+use crate::shareplay::SharePlayControl;
 use crate::{auth, config::Config, db::Db};
 use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, StreamHandler, WrapFuture};
 use actix_web::{Error, HttpRequest, HttpResponse, web};
@@ -181,6 +185,36 @@ async fn can_write(db: &crate::db::Db, user_id: &str, channel_id: &str) -> bool
     .unwrap_or(false)
 }
 
+/// Who may control SharePlay in this channel
+async fn shareplay_control(db: &crate::db::Db, channel_id: &str) -> SharePlayControl {
+    sqlx::query_scalar::<_, String>("SELECT shareplay_control FROM channels WHERE id = ?")
+        .bind(channel_id)
+        .fetch_optional(&db.0)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|c| SharePlayControl::parse(&c))
+        .unwrap_or_default()
+}
+
+/// The URL of a track in the channel's SharePlay history and whether it was a video,
+/// to queue it again
+async fn history_url(
+    db: &crate::db::Db,
+    channel_id: &str,
+    entry_id: &str,
+) -> Option<(String, bool)> {
+    sqlx::query_as::<_, (String, bool)>(
+        "SELECT url, video FROM shareplay_history WHERE id = ? AND channel_id = ?",
+    )
+    .bind(entry_id)
+    .bind(channel_id)
+    .fetch_optional(&db.0)
+    .await
+    .ok()
+    .flatten()
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ClientEvent {
@@ -392,24 +426,66 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
                         }
                         ClientEvent::SharePlayAction {
                             channel_id,
-                            action_type,
-                            data,
+                            mut action_type,
+                            mut data,
                         } => {
                             let db = self.db.clone();
                             let user_id = self.user_id.clone();
+                            let session_id = self.session_id.clone();
                             let server = self.server.clone();
                             let cid = channel_id.clone();
                             ctx.spawn(
                                 async move {
-                                    // Must be in voice/read permission to control?
-                                    // For now check read permission
+                                    // Readers may send actions; the server checks the
+                                    // channel's control mode, which may need the voice call
                                     if can_read(&db, &user_id, &cid).await {
+                                        // Queue a track from the history again, as an add
+                                        if action_type == "requeue" {
+                                            let entry_id = data.take().unwrap_or_default();
+                                            let Some((url, video)) =
+                                                history_url(&db, &cid, &entry_id).await
+                                            else {
+                                                return;
+                                            };
+                                            action_type =
+                                                if video { "add_video" } else { "add" }.to_string();
+                                            data = Some(url);
+                                        }
+                                        let control = shareplay_control(&db, &cid).await;
+                                        let is_dj = control == SharePlayControl::Djs
+                                            && is_shareplay_dj(&db, &user_id, &cid)
+                                                .await
+                                                .unwrap_or(false);
+                                        // Queue a saved playlist the user may see
+                                        if action_type == "load_playlist" {
+                                            let playlist_id = data.unwrap_or_default();
+                                            match loadable_entries(&db, &user_id, &playlist_id).await {
+                                                Ok(entries) => server.do_send(SharePlayLoadPlaylist {
+                                                    channel_id: cid,
+                                                    user_id,
+                                                    session_id,
+                                                    control,
+                                                    is_dj,
+                                                    entries,
+                                                }),
+                                                Err(e) => log::warn!(
+                                                    "User {} can't load playlist {}: {}",
+                                                    user_id,
+                                                    playlist_id,
+                                                    e
+                                                ),
+                                            }
+                                            return;
+                                        }
                                         log::info!("WsSession sending SharePlayAction to server: user_id={}, channel_id={}, action={}", user_id, cid, action_type);
                                         server.do_send(SharePlayAction {
                                             channel_id: cid,
                                             user_id,
+                                            session_id,
                                             action_type,
                                             data,
+                                            control,
+                                            is_dj,
                                         });
                                     }
                                 }